@@ -0,0 +1,196 @@
+//! Conversion of a realistic block of the blockchain updates, along with the number of allocations it makes.
+//!
+//! `cargo bench --bench convert`
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use waves_protobuf_schemas::waves::{
+    block::Header,
+    data_transaction_data::{data_entry::Value as DataEntryValue, DataEntry},
+    events::{
+        blockchain_updated::{
+            append::{BlockAppend, Body},
+            Append, Update,
+        },
+        transaction_metadata::{InvokeScriptMetadata, LeaseMetadata, Metadata},
+        BlockchainUpdated, TransactionMetadata,
+    },
+    invoke_script_result::call::{
+        argument::{List, Value},
+        Argument,
+    },
+    signed_transaction::Transaction as TransactionEnum,
+    transaction::Data,
+    Amount, Block, DataTransactionData, InvokeScriptTransactionData, LeaseTransactionData, SignedTransaction,
+    Transaction,
+};
+
+use lib::consumer::convert_for_bench;
+
+/// Counts the allocations, to see how many of them the conversion makes
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn signed_tx(data: Data) -> SignedTransaction {
+    SignedTransaction {
+        transaction: Some(TransactionEnum::WavesTransaction(Transaction {
+            chain_id: 'W' as i32,
+            sender_public_key: vec![1; 32],
+            fee: Some(Amount {
+                asset_id: vec![],
+                amount: 500_000,
+            }),
+            timestamp: 1_700_000_000_000,
+            version: 2,
+            data: Some(data),
+        })),
+        proofs: vec![vec![2; 64]],
+    }
+}
+
+fn metadata(metadata: Metadata) -> TransactionMetadata {
+    TransactionMetadata {
+        sender_address: vec![3; 26],
+        metadata: Some(metadata),
+    }
+}
+
+fn arg(value: Value) -> Argument {
+    Argument { value: Some(value) }
+}
+
+/// Invoke script with a few arguments of every kind and two payments
+fn invoke() -> (SignedTransaction, TransactionMetadata) {
+    let payments = vec![
+        Amount {
+            asset_id: vec![4; 32],
+            amount: 1_000,
+        },
+        Amount {
+            asset_id: vec![],
+            amount: 2_000,
+        },
+    ];
+    let data = Data::InvokeScript(InvokeScriptTransactionData {
+        d_app: None,
+        function_call: vec![],
+        payments: payments.clone(),
+    });
+    let list = List {
+        items: vec![arg(Value::IntegerValue(1)), arg(Value::StringValue("item".to_owned()))],
+    };
+    let meta = Metadata::InvokeScript(InvokeScriptMetadata {
+        d_app_address: vec![5; 26],
+        function_name: "swap".to_owned(),
+        arguments: vec![
+            arg(Value::IntegerValue(42)),
+            arg(Value::StringValue("3PAddress".to_owned())),
+            arg(Value::BinaryValue(vec![6; 32])),
+            arg(Value::BooleanValue(true)),
+            arg(Value::List(list)),
+        ],
+        payments,
+        result: None,
+    });
+    (signed_tx(data), metadata(meta))
+}
+
+fn lease() -> (SignedTransaction, TransactionMetadata) {
+    let data = Data::Lease(LeaseTransactionData {
+        recipient: None,
+        amount: 1_000_000,
+    });
+    let meta = Metadata::Lease(LeaseMetadata {
+        recipient_address: vec![7; 26],
+    });
+    (signed_tx(data), metadata(meta))
+}
+
+fn data_transaction() -> (SignedTransaction, TransactionMetadata) {
+    let entry = |key: &str, value| DataEntry {
+        key: key.to_owned(),
+        value: Some(value),
+    };
+    let data = Data::DataTransaction(DataTransactionData {
+        data: vec![
+            entry("int", DataEntryValue::IntValue(1)),
+            entry("string", DataEntryValue::StringValue("value".to_owned())),
+            entry("binary", DataEntryValue::BinaryValue(vec![8; 32])),
+        ],
+    });
+    let meta = TransactionMetadata {
+        sender_address: vec![3; 26],
+        metadata: None,
+    };
+    (signed_tx(data), meta)
+}
+
+/// Key block of 100 transactions, mostly invoke scripts as on the mainnet
+fn block() -> BlockchainUpdated {
+    let txs = (0..100).map(|i| match i % 10 {
+        0 => lease(),
+        1 => data_transaction(),
+        _ => invoke(),
+    });
+    let (transactions, transactions_metadata): (Vec<_>, Vec<_>) = txs.unzip();
+    let transaction_ids = (0..transactions.len()).map(|i| vec![i as u8; 32]).collect();
+    BlockchainUpdated {
+        id: vec![9; 64],
+        height: 4_000_000,
+        update: Some(Update::Append(Append {
+            body: Some(Body::Block(BlockAppend {
+                block: Some(Block {
+                    header: Some(Header {
+                        timestamp: 1_700_000_000_000,
+                        ..Default::default()
+                    }),
+                    transactions,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+            transaction_ids,
+            transactions_metadata,
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+fn convert_block(c: &mut Criterion) {
+    let update = block();
+
+    let input = update.clone();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let operations = convert_for_bench(input).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{} operations, {} allocations per block", operations, allocations);
+
+    c.bench_function("convert block", |b| {
+        b.iter_batched(
+            || update.clone(),
+            |update| convert_for_bench(update).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, convert_block);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+-- Revert indexes for the keyset-paginated operations queries
+
+DROP INDEX IF EXISTS transactions__op_type__idx;
+
+DROP INDEX IF EXISTS transactions__sender__uid__idx;
@@ -0,0 +1,7 @@
+-- Indexes for the keyset-paginated operations queries
+
+
+-- Filtering by sender and paging by uid, the plain sender index is kept for the other sender lookups
+CREATE INDEX IF NOT EXISTS transactions__sender__uid__idx ON transactions (sender, uid);
+
+CREATE INDEX IF NOT EXISTS transactions__op_type__idx ON transactions (op_type);
@@ -0,0 +1,3 @@
+-- Revert storing whether a block is a key block or a microblock
+
+ALTER TABLE blocks_microblocks DROP COLUMN IF EXISTS is_microblock;
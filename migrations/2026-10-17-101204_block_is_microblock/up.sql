@@ -0,0 +1,3 @@
+-- Store whether a block is a key block or a microblock
+
+ALTER TABLE blocks_microblocks ADD COLUMN IF NOT EXISTS is_microblock BOOLEAN NOT NULL DEFAULT FALSE;
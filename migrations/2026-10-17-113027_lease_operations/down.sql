@@ -0,0 +1,8 @@
+-- Revert Lease and LeaseCancel operation types (enum values can't be dropped, so recreate the type)
+
+DELETE FROM transactions WHERE op_type IN ('lease', 'lease_cancel');
+
+ALTER TYPE operation_type RENAME TO operation_type_old;
+CREATE TYPE operation_type AS ENUM ('invoke_script');
+ALTER TABLE transactions ALTER COLUMN op_type TYPE operation_type USING op_type::text::operation_type;
+DROP TYPE operation_type_old;
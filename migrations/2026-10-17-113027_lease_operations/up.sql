@@ -0,0 +1,4 @@
+-- Lease and LeaseCancel operation types
+
+ALTER TYPE operation_type ADD VALUE IF NOT EXISTS 'lease';
+ALTER TYPE operation_type ADD VALUE IF NOT EXISTS 'lease_cancel';
@@ -0,0 +1,8 @@
+-- Revert DataTransaction operation type (enum values can't be dropped, so recreate the type)
+
+DELETE FROM transactions WHERE op_type = 'data_transaction';
+
+ALTER TYPE operation_type RENAME TO operation_type_old;
+CREATE TYPE operation_type AS ENUM ('invoke_script', 'lease', 'lease_cancel');
+ALTER TABLE transactions ALTER COLUMN op_type TYPE operation_type USING op_type::text::operation_type;
+DROP TYPE operation_type_old;
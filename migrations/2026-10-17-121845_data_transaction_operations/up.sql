@@ -0,0 +1,3 @@
+-- DataTransaction operation type
+
+ALTER TYPE operation_type ADD VALUE IF NOT EXISTS 'data_transaction';
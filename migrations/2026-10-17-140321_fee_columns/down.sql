@@ -0,0 +1,4 @@
+-- Revert storing the transaction fee in dedicated columns
+
+ALTER TABLE transactions DROP COLUMN IF EXISTS fee_asset;
+ALTER TABLE transactions DROP COLUMN IF EXISTS fee_amount;
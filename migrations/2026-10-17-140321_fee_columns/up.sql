@@ -0,0 +1,11 @@
+-- Store the transaction fee in dedicated columns (duplicating the `fee` of the operation body)
+
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS fee_amount BIGINT;
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS fee_asset VARCHAR;
+
+UPDATE transactions
+SET fee_amount = (operation -> 'fee' ->> 'amount')::BIGINT,
+    fee_asset  = operation -> 'fee' ->> 'id';
+
+ALTER TABLE transactions ALTER COLUMN fee_amount SET NOT NULL;
+ALTER TABLE transactions ALTER COLUMN fee_asset SET NOT NULL;
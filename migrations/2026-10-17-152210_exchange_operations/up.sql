@@ -0,0 +1,3 @@
+-- Exchange operation type
+
+ALTER TYPE operation_type ADD VALUE IF NOT EXISTS 'exchange';
@@ -0,0 +1,15 @@
+-- Transactions that failed to convert (with QUARANTINE_BAD_TX enabled), kept for investigation
+
+CREATE TABLE IF NOT EXISTS quarantine
+(
+    uid       BIGINT  NOT NULL GENERATED BY DEFAULT AS IDENTITY
+        CONSTRAINT quarantine__uid__key UNIQUE,
+    id        VARCHAR NOT NULL
+        CONSTRAINT quarantine__pkey PRIMARY KEY,
+    block_uid BIGINT  NOT NULL
+        CONSTRAINT quarantine__block_uid__fkey REFERENCES blocks_microblocks (uid) ON DELETE CASCADE,
+    raw       BYTEA   NOT NULL,
+    reason    TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS quarantine__block_uid__idx ON quarantine (block_uid);
@@ -0,0 +1,6 @@
+-- Revert storing the block height in the transactions
+
+DROP FUNCTION IF EXISTS create_height_partitions(INTEGER);
+DROP FUNCTION IF EXISTS drop_height_partitions_above(INTEGER);
+
+ALTER TABLE transactions DROP COLUMN IF EXISTS height;
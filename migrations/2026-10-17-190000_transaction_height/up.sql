@@ -0,0 +1,28 @@
+-- Store the block height in the transactions as well (duplicating the one of the block),
+-- so that the transactions can be range-partitioned by height, see `migration -- partition`
+
+
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS height INTEGER;
+
+UPDATE transactions
+SET height = blocks_microblocks.height
+FROM blocks_microblocks
+WHERE blocks_microblocks.uid = transactions.block_uid;
+
+ALTER TABLE transactions ALTER COLUMN height SET NOT NULL;
+
+
+-- Hooks of the consumer for the tables range-partitioned by height, see `scripts/partition_by_height.sql`,
+-- which replaces them. Until then there are no partitions, so they do nothing.
+
+-- Creates the partitions for the given height, if there are none yet, called before inserting a block
+CREATE OR REPLACE FUNCTION create_height_partitions(_height INTEGER) RETURNS VOID AS $$
+BEGIN
+END;
+$$ LANGUAGE plpgsql;
+
+-- Drops the partitions with all the heights above the given one, called on rollbacks
+CREATE OR REPLACE FUNCTION drop_height_partitions_above(_height INTEGER) RETURNS VOID AS $$
+BEGIN
+END;
+$$ LANGUAGE plpgsql;
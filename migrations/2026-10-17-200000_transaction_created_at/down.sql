@@ -0,0 +1,3 @@
+-- Revert storing the ingestion time of the transactions
+
+ALTER TABLE transactions DROP COLUMN IF EXISTS created_at;
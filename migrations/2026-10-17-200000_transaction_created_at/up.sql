@@ -0,0 +1,4 @@
+-- Wall clock time when the transaction was written by the consumer, for debugging the ingestion lag.
+-- Rows stored before get the time of this migration.
+
+ALTER TABLE transactions ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS quarantine;
+DROP TABLE IF EXISTS transactions;
+DROP TABLE IF EXISTS blocks_microblocks;
@@ -0,0 +1,42 @@
+-- SQLite database structure, for local development only (mirrors the Postgres one)
+
+CREATE TABLE IF NOT EXISTS blocks_microblocks
+(
+    uid           INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    id            TEXT    NOT NULL UNIQUE,
+    height        INTEGER NOT NULL,
+    time_stamp    INTEGER NOT NULL,
+    is_microblock BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS blocks_microblocks__height__idx ON blocks_microblocks (height);
+
+
+CREATE TABLE IF NOT EXISTS transactions
+(
+    uid        INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    id         TEXT    NOT NULL UNIQUE,
+    block_uid  INTEGER NOT NULL REFERENCES blocks_microblocks (uid) ON DELETE CASCADE,
+    sender     TEXT    NOT NULL,
+    tx_type    SMALLINT NOT NULL,
+    op_type    TEXT    NOT NULL,
+    operation  TEXT    NOT NULL,
+    fee_amount BIGINT  NOT NULL,
+    fee_asset  TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS transactions__block_uid__idx ON transactions (block_uid);
+CREATE INDEX IF NOT EXISTS transactions__sender__uid__idx ON transactions (sender, uid);
+CREATE INDEX IF NOT EXISTS transactions__op_type__idx ON transactions (op_type);
+
+
+CREATE TABLE IF NOT EXISTS quarantine
+(
+    uid       INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    id        TEXT    NOT NULL UNIQUE,
+    block_uid INTEGER NOT NULL REFERENCES blocks_microblocks (uid) ON DELETE CASCADE,
+    raw       BLOB    NOT NULL,
+    reason    TEXT    NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS quarantine__block_uid__idx ON quarantine (block_uid);
@@ -0,0 +1,3 @@
+-- Revert storing the block height in the transactions
+
+ALTER TABLE transactions DROP COLUMN height;
@@ -0,0 +1,6 @@
+-- Store the block height in the transactions as well (mirrors the Postgres one, there is no partitioning)
+
+ALTER TABLE transactions ADD COLUMN height INTEGER NOT NULL DEFAULT 0;
+
+UPDATE transactions
+SET height = (SELECT height FROM blocks_microblocks WHERE blocks_microblocks.uid = transactions.block_uid);
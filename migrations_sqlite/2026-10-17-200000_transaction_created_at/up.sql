@@ -0,0 +1,5 @@
+-- Wall clock time when the transaction was written by the consumer (mirrors the Postgres one).
+-- SQLite can't add a column defaulting to the current time, so the consumer sets it on insert,
+-- rows stored before have none.
+
+ALTER TABLE transactions ADD COLUMN created_at TEXT;
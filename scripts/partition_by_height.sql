@@ -0,0 +1,219 @@
+-- Converts `blocks_microblocks` and `transactions` into tables range-partitioned by height,
+-- {partition_size} heights per partition, run by `migration -- partition <partition size>`.
+--
+-- The partitions are named `blocks_microblocks_h<first height>` and `transactions_h<first height>`.
+-- The consumer creates the ones for a new height before inserting its block (`create_height_partitions`)
+-- and, on rollbacks, drops the ones entirely above the rollback height (`drop_height_partitions_above`)
+-- instead of deleting their rows one by one. A partition that is only partly above the height is kept,
+-- its rows above the height are deleted as usual.
+--
+-- Unique constraints of a partitioned table must include the partition key, so the uids and the ids
+-- are kept globally unique by the `block_keys` and `transaction_keys` tables, filled by the triggers.
+--
+-- There is no way back other than re-importing. The tables are rewritten in one transaction,
+-- so on a big database expect it to take a while with the consumer stopped.
+
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_partitioned_table WHERE partrelid = 'transactions'::REGCLASS) THEN
+        RAISE EXCEPTION 'transactions table is already partitioned';
+    END IF;
+END;
+$$;
+
+
+ALTER TABLE blocks_microblocks RENAME TO blocks_microblocks_unpartitioned;
+ALTER TABLE transactions RENAME TO transactions_unpartitioned;
+
+
+CREATE TABLE block_keys
+(
+    uid    BIGINT  NOT NULL
+        CONSTRAINT block_keys__pkey PRIMARY KEY,
+    id     VARCHAR NOT NULL
+        CONSTRAINT block_keys__id__key UNIQUE,
+    height INTEGER NOT NULL
+);
+
+CREATE INDEX block_keys__height__idx ON block_keys (height);
+
+CREATE TABLE transaction_keys
+(
+    uid       BIGINT  NOT NULL
+        CONSTRAINT transaction_keys__pkey PRIMARY KEY,
+    id        VARCHAR NOT NULL
+        CONSTRAINT transaction_keys__id__key UNIQUE,
+    block_uid BIGINT  NOT NULL
+        CONSTRAINT transaction_keys__block_uid__fkey REFERENCES block_keys (uid) ON DELETE CASCADE
+);
+
+CREATE INDEX transaction_keys__block_uid__idx ON transaction_keys (block_uid);
+
+
+CREATE TABLE blocks_microblocks
+(
+    uid           BIGINT  NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    id            VARCHAR NOT NULL,
+    height        INTEGER NOT NULL,
+    time_stamp    BIGINT  NOT NULL,
+    is_microblock BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT blocks_microblocks__uid__height__pkey PRIMARY KEY (uid, height)
+) PARTITION BY RANGE (height);
+
+CREATE TABLE transactions
+(
+    uid        BIGINT         NOT NULL GENERATED BY DEFAULT AS IDENTITY,
+    id         VARCHAR        NOT NULL,
+    block_uid  BIGINT         NOT NULL,
+    sender     VARCHAR        NOT NULL,
+    tx_type    SMALLINT       NOT NULL,
+    op_type    OPERATION_TYPE NOT NULL,
+    operation  JSONB          NOT NULL,
+    fee_amount BIGINT         NOT NULL,
+    fee_asset  VARCHAR        NOT NULL,
+    height     INTEGER        NOT NULL,
+    created_at TIMESTAMPTZ    NOT NULL DEFAULT now(),
+    CONSTRAINT transactions__uid__height__pkey PRIMARY KEY (uid, height),
+    CONSTRAINT transactions__block_uid__height__fkey FOREIGN KEY (block_uid, height)
+        REFERENCES blocks_microblocks (uid, height) ON DELETE CASCADE
+) PARTITION BY RANGE (height);
+
+
+CREATE OR REPLACE FUNCTION create_height_partitions(_height INTEGER) RETURNS VOID AS $$
+DECLARE
+    _from  INTEGER := _height - _height % {partition_size};
+    _table TEXT;
+BEGIN
+    FOREACH _table IN ARRAY ARRAY ['blocks_microblocks', 'transactions'] LOOP
+        IF to_regclass(format('%s_h%s', _table, _from)) IS NULL THEN
+            EXECUTE format('CREATE TABLE %I PARTITION OF %I FOR VALUES FROM (%s) TO (%s)',
+                           format('%s_h%s', _table, _from), _table, _from, _from + {partition_size});
+        END IF;
+    END LOOP;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Decided by the lower bound of the partition, so a partition with any height up to the given one is kept
+CREATE OR REPLACE FUNCTION drop_height_partitions_above(_height INTEGER) RETURNS VOID AS $$
+DECLARE
+    _partition TEXT;
+BEGIN
+    -- Before the blocks they reference
+    FOR _partition IN
+        SELECT child.oid::REGCLASS::TEXT
+        FROM pg_inherits
+                 JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+        WHERE pg_inherits.inhparent = 'transactions'::REGCLASS
+          AND substring(pg_get_expr(child.relpartbound, child.oid) FROM 'FROM \(([0-9]+)\)')::INTEGER > _height
+    LOOP
+        EXECUTE format('DROP TABLE %s', _partition);
+    END LOOP;
+    FOR _partition IN
+        SELECT child.oid::REGCLASS::TEXT
+        FROM pg_inherits
+                 JOIN pg_class child ON child.oid = pg_inherits.inhrelid
+        WHERE pg_inherits.inhparent = 'blocks_microblocks'::REGCLASS
+          AND substring(pg_get_expr(child.relpartbound, child.oid) FROM 'FROM \(([0-9]+)\)')::INTEGER > _height
+    LOOP
+        EXECUTE format('ALTER TABLE blocks_microblocks DETACH PARTITION %s', _partition);
+        EXECUTE format('DROP TABLE %s', _partition);
+    END LOOP;
+    -- Dropping the partitions doesn't fire the delete triggers, cascades to the transaction keys and the quarantine
+    DELETE FROM block_keys WHERE height > _height;
+END;
+$$ LANGUAGE plpgsql;
+
+
+SELECT create_height_partitions(partition_height)
+FROM (SELECT DISTINCT height - height % {partition_size} AS partition_height
+      FROM blocks_microblocks_unpartitioned) AS p;
+
+INSERT INTO blocks_microblocks (uid, id, height, time_stamp, is_microblock)
+SELECT uid, id, height, time_stamp, is_microblock
+FROM blocks_microblocks_unpartitioned;
+
+INSERT INTO block_keys (uid, id, height)
+SELECT uid, id, height
+FROM blocks_microblocks;
+
+INSERT INTO transactions (uid, id, block_uid, sender, tx_type, op_type, operation, fee_amount, fee_asset, height,
+                          created_at)
+SELECT uid, id, block_uid, sender, tx_type, op_type, operation, fee_amount, fee_asset, height, created_at
+FROM transactions_unpartitioned;
+
+INSERT INTO transaction_keys (uid, id, block_uid)
+SELECT uid, id, block_uid
+FROM transactions;
+
+SELECT setval(pg_get_serial_sequence('blocks_microblocks', 'uid'), (SELECT max(uid) FROM blocks_microblocks));
+SELECT setval(pg_get_serial_sequence('transactions', 'uid'), (SELECT max(uid) FROM transactions));
+
+ALTER TABLE quarantine DROP CONSTRAINT quarantine__block_uid__fkey;
+ALTER TABLE quarantine
+    ADD CONSTRAINT quarantine__block_uid__fkey FOREIGN KEY (block_uid) REFERENCES block_keys (uid) ON DELETE CASCADE;
+
+DROP TABLE transactions_unpartitioned;
+DROP TABLE blocks_microblocks_unpartitioned;
+
+CREATE INDEX blocks_microblocks__id__idx ON blocks_microblocks (id);
+CREATE INDEX blocks_microblocks__height__idx ON blocks_microblocks (height);
+CREATE INDEX transactions__block_uid__idx ON transactions (block_uid);
+CREATE INDEX transactions__sender__idx ON transactions (sender);
+CREATE INDEX transactions__sender__uid__idx ON transactions (sender, uid);
+CREATE INDEX transactions__op_type__idx ON transactions (op_type);
+
+
+CREATE FUNCTION block_keys__insert() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO block_keys (uid, id, height) VALUES (NEW.uid, NEW.id, NEW.height);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION block_keys__delete() RETURNS TRIGGER AS $$
+BEGIN
+    DELETE FROM block_keys WHERE uid = OLD.uid;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION block_keys__truncate() RETURNS TRIGGER AS $$
+BEGIN
+    -- The quarantine referencing the block keys is truncated along with the blocks, so it can't be truncated here
+    TRUNCATE transaction_keys;
+    DELETE FROM block_keys;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION transaction_keys__insert() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO transaction_keys (uid, id, block_uid) VALUES (NEW.uid, NEW.id, NEW.block_uid);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER blocks_microblocks__insert_key
+    AFTER INSERT
+    ON blocks_microblocks
+    FOR EACH ROW
+EXECUTE FUNCTION block_keys__insert();
+
+-- Cascades to the transaction keys and the quarantine, the transactions are deleted by their own foreign key
+CREATE TRIGGER blocks_microblocks__delete_key
+    AFTER DELETE
+    ON blocks_microblocks
+    FOR EACH ROW
+EXECUTE FUNCTION block_keys__delete();
+
+CREATE TRIGGER blocks_microblocks__truncate_keys
+    AFTER TRUNCATE
+    ON blocks_microblocks
+EXECUTE FUNCTION block_keys__truncate();
+
+CREATE TRIGGER transactions__insert_key
+    AFTER INSERT
+    ON transactions
+    FOR EACH ROW
+EXECUTE FUNCTION transaction_keys__insert();
@@ -1,5 +1,7 @@
 //! Operations service's database migration tool.
 
+use diesel::backend::Backend;
+use diesel::connection::SimpleConnection;
 use diesel::migration::Migration;
 use diesel::{migration, pg::PgConnection, Connection};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
@@ -8,18 +10,57 @@ use lib::common::database;
 
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+/// Conversion of the blocks and the transactions into the partitioned tables,
+/// not a regular migration because it is optional
+const PARTITION_BY_HEIGHT_SQL: &str = include_str!("../../scripts/partition_by_height.sql");
+
+#[cfg(feature = "sqlite")]
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
 fn main() -> anyhow::Result<()> {
     let action = action::parse_command_line()?;
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = database::config::sqlite_database() {
+        if let action::Action::Partition(_) = action {
+            anyhow::bail!("partitioning is only supported on Postgres");
+        }
+        let mut conn = diesel::sqlite::SqliteConnection::establish(&path)?;
+        return run(action, &mut conn, SQLITE_MIGRATIONS).map_err(|e| anyhow::anyhow!(e));
+    }
+
     let dbconfig = database::config::load()?;
-    let conn = PgConnection::establish(&dbconfig.database_url())?;
-    run(action, conn).map_err(|e| anyhow::anyhow!(e))
+    let mut conn = PgConnection::establish(&dbconfig.database_url())?;
+    if let action::Action::Partition(partition_size) = action {
+        return partition_by_height(&mut conn, partition_size);
+    }
+    run(action, &mut conn, MIGRATIONS).map_err(|e| anyhow::anyhow!(e))
+}
+
+/// Convert the blocks and the transactions into the tables range-partitioned by height,
+/// all the migrations must be applied first
+fn partition_by_height(conn: &mut PgConnection, partition_size: u32) -> anyhow::Result<()> {
+    if conn.has_pending_migration(MIGRATIONS).map_err(|e| anyhow::anyhow!(e))? {
+        anyhow::bail!("there are pending migrations, apply them first");
+    }
+    let sql = PARTITION_BY_HEIGHT_SQL.replace("{partition_size}", &partition_size.to_string());
+    conn.transaction(|conn| conn.batch_execute(&sql))?;
+    println!(
+        "Partitioned blocks and transactions by height, {} heights per partition",
+        partition_size
+    );
+    Ok(())
 }
 
-fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()> {
+fn run<DB: Backend>(
+    action: action::Action,
+    conn: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> migration::Result<()> {
     use action::Action::*;
     match action {
         ListPending => {
-            let list = conn.pending_migrations(MIGRATIONS)?;
+            let list = conn.pending_migrations(migrations)?;
             if list.is_empty() {
                 println!("No pending migrations.");
             }
@@ -28,7 +69,7 @@ fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()>
             }
         }
         MigrateUp => {
-            let list = conn.run_pending_migrations(MIGRATIONS)?;
+            let list = conn.run_pending_migrations(migrations)?;
             if list.is_empty() {
                 println!("No pending migrations.");
             }
@@ -37,9 +78,10 @@ fn run(action: action::Action, mut conn: PgConnection) -> migration::Result<()>
             }
         }
         MigrateDown => {
-            let mig = conn.revert_last_migration(MIGRATIONS)?;
+            let mig = conn.revert_last_migration(migrations)?;
             println!("Reverted migration: {}", mig);
         }
+        Partition(_) => unreachable!("handled before running the migrations"),
     }
     Ok(())
 }
@@ -49,6 +91,8 @@ mod action {
         ListPending,
         MigrateUp,
         MigrateDown,
+        /// Number of heights per partition
+        Partition(u32),
     }
 
     impl TryFrom<&str> for Action {
@@ -66,9 +110,17 @@ mod action {
 
     pub fn parse_command_line() -> Result<Action, anyhow::Error> {
         let action_str = std::env::args().nth(1).unwrap_or_default();
+        if action_str == "partition" {
+            let partition_size = std::env::args()
+                .nth(2)
+                .and_then(|size| size.parse::<u32>().ok())
+                .filter(|&size| size > 0)
+                .ok_or_else(|| anyhow::anyhow!("'partition' expects the positive number of heights per partition"))?;
+            return Ok(Action::Partition(partition_size));
+        }
         let action = action_str.as_str().try_into().map_err(|()| {
             anyhow::anyhow!(
-                "unrecognized command line argument: {} (either 'up' or 'down' expected)",
+                "unrecognized command line argument: {} (either 'up', 'down' or 'partition' expected)",
                 action_str
             )
         })?;
@@ -6,35 +6,75 @@
 //!
 //! Always introduces a delay of 1 microblock
 //! to handle the most common rollback type in-memory.
+//!
+//! In key-blocks-only mode (`skip_microblocks`) microblocks are never passed on,
+//! instead they are collected together with their key block and merged into it
+//! once the next key block arrives, so that only finalized blocks are written.
+//! The price is latency: transactions are only seen once the next key block lands.
+//!
+//! Batching can be disabled altogether (`start_unbatched`), then each update is passed on
+//! as soon as it is received, losing the in-memory handling of the short rollbacks.
 
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 use tokio::{sync::mpsc, task};
 
-use crate::consumer::metrics::{UPDATES_BATCH_SIZE, UPDATES_BATCH_TIME};
-use crate::consumer::updates::BlockchainUpdate;
+use crate::consumer::metrics::{BATCH_FLUSHES, BATCH_QUEUE_DEPTH, UPDATES_BATCH_SIZE, UPDATES_BATCH_TIME};
+use crate::consumer::updates::{AppendBlock, BlockchainUpdate};
 
 #[derive(Clone, Default)]
 pub struct BatchingParams {
     pub max_updates: Option<usize>,
     pub max_delay: Option<Duration>,
+    pub skip_microblocks: bool,
+    /// Hard cap on the buffered updates, the buffer is flushed once it is reached no matter what,
+    /// even with a rollback on top (it is then left to the database)
+    pub max_buffer_len: Option<usize>,
+    /// How many batches can wait for the writer, each of them can be up to `max_updates` blocks big
+    pub queue_capacity: usize,
+}
+
+/// Runtime state of the batcher, shared with the debug endpoint.
+#[derive(Default)]
+pub struct BatcherState {
+    buffer_len: AtomicUsize,
+    /// Unix time (in ms) of the last flush, 0 if there was none yet
+    last_flush_at: AtomicI64,
+}
+
+impl BatcherState {
+    /// Number of the updates waiting in the buffer
+    pub fn buffer_len(&self) -> usize {
+        self.buffer_len.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last batch was passed on, `None` if there was none yet
+    pub fn last_flush_age(&self) -> Option<Duration> {
+        let last_flush_at = self.last_flush_at.load(Ordering::Relaxed);
+        let age = chrono::Utc::now().timestamp_millis() - last_flush_at;
+        (last_flush_at != 0).then(|| Duration::from_millis(age.max(0) as u64))
+    }
 }
 
 pub fn start(
     input: mpsc::Receiver<BlockchainUpdate>,
     batching_params: BatchingParams,
+    state: Arc<BatcherState>,
 ) -> mpsc::Receiver<Vec<BlockchainUpdate>> {
-    let (tx, rx) = mpsc::channel::<Vec<BlockchainUpdate>>(1);
+    let (tx, rx) = mpsc::channel::<Vec<BlockchainUpdate>>(batching_params.queue_capacity.max(1));
     let buffer_capacity = batching_params.max_updates.unwrap_or(1);
     let mut batcher = Batcher {
         input,
         output: tx,
         batching_params,
         buffer: Vec::with_capacity(buffer_capacity),
-        last_block_timestamp: None,
-        last_block_height: None,
+        timestamps: TimestampPropagation::default(),
+        pending_block: Vec::new(),
         last_flush: Instant::now(),
+        state,
     };
     task::spawn(async move {
         batcher.run().await.expect("receiver dropped");
@@ -42,57 +82,107 @@ pub fn start(
     rx
 }
 
+/// Pass the updates on one by one, exactly as received.
+pub fn start_unbatched(mut input: mpsc::Receiver<BlockchainUpdate>) -> mpsc::Receiver<Vec<BlockchainUpdate>> {
+    let (tx, rx) = mpsc::channel::<Vec<BlockchainUpdate>>(1);
+    task::spawn(async move {
+        let mut timestamps = TimestampPropagation::default();
+        while let Some(mut update) = input.recv().await {
+            match update {
+                BlockchainUpdate::Append(ref mut append) => timestamps.propagate(append),
+                BlockchainUpdate::Rollback(_) => timestamps.reset(),
+            }
+            UPDATES_BATCH_SIZE.set(1);
+            BATCH_QUEUE_DEPTH.inc();
+            tx.send(vec![update]).await.expect("receiver dropped");
+        }
+    });
+    rx
+}
+
+/// Why the buffered updates are passed on
+#[derive(Clone, Copy, Debug)]
+enum FlushReason {
+    /// Rollback in the buffer (already followed by the replacement block)
+    Rollback,
+    /// Microblock on top of the other updates
+    Microblock,
+    /// `max_updates` reached
+    Size,
+    /// `max_delay` exceeded
+    Delay,
+    /// Neither `max_updates` nor `max_delay` is set
+    Unbatched,
+    /// `max_buffer_len` reached
+    Overflow,
+}
+
+impl FlushReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            FlushReason::Rollback => "rollback",
+            FlushReason::Microblock => "microblock",
+            FlushReason::Size => "size",
+            FlushReason::Delay => "delay",
+            FlushReason::Unbatched => "unbatched",
+            FlushReason::Overflow => "overflow",
+        }
+    }
+}
+
 struct Batcher {
     input: mpsc::Receiver<BlockchainUpdate>,
     output: mpsc::Sender<Vec<BlockchainUpdate>>,
     batching_params: BatchingParams,
     buffer: Vec<BlockchainUpdate>,
-    last_block_timestamp: Option<u64>,
-    last_block_height: Option<u32>,
+    timestamps: TimestampPropagation,
+    /// Key block with its microblocks, not finalized yet (only used when skipping microblocks)
+    pending_block: Vec<AppendBlock>,
     last_flush: Instant,
+    state: Arc<BatcherState>,
 }
 
 impl Batcher {
     async fn run(&mut self) -> Result<(), mpsc::error::SendError<Vec<BlockchainUpdate>>> {
         while let Some(update) = self.input.recv().await {
             self.push_update(update);
-            if self.need_flush() {
+            self.state.buffer_len.store(self.buffer.len(), Ordering::Relaxed);
+            if let Some(reason) = self.need_flush() {
                 let count = self.buffer.len();
                 let time = self.last_flush.elapsed();
                 log::debug!("Collected {} updates in {:?}", count, time,);
+                if let FlushReason::Overflow = reason {
+                    log::warn!("Batcher buffer reached its limit of {} updates, flushing", count);
+                }
+                log::trace!("Flushing batch, reason: {}", reason.as_str());
+                BATCH_FLUSHES.with_label_values(&[reason.as_str()]).inc();
                 UPDATES_BATCH_SIZE.set(count as i64);
                 UPDATES_BATCH_TIME.set(time.as_millis() as i64);
                 self.flush().await?;
             }
         }
+        // The input ends with the height range of a replay, what is buffered is passed on as it is,
+        // a block not finalized yet (when skipping microblocks) is still held back
+        if !self.buffer.is_empty() {
+            log::trace!("Flushing batch, reason: input closed");
+            let updates = self.buffer.drain(..).collect_vec();
+            BATCH_QUEUE_DEPTH.inc();
+            self.output.send(updates).await?;
+            self.state.buffer_len.store(0, Ordering::Relaxed);
+        }
         Ok(())
     }
 
     fn push_update(&mut self, mut update: BlockchainUpdate) {
+        if self.batching_params.skip_microblocks {
+            match self.hold_pending_block(update) {
+                Some(next) => update = next,
+                None => return,
+            }
+        }
         match update {
             BlockchainUpdate::Append(ref mut append) => {
-                // Propagate timestamp from the last known block at the same height to the microblock
-                if append.is_microblock && append.timestamp.is_none() {
-                    if let Some(last_height) = self.last_block_height {
-                        if last_height == append.height {
-                            assert!(
-                                self.last_block_timestamp.is_some(),
-                                "Internal error: propagate timestamp failed (no saved timestamp)"
-                            );
-                            append.timestamp = self.last_block_timestamp;
-                        } else {
-                            panic!(
-                                "Internal error: propagate timestamp failed (last_height={}, append.height={})",
-                                last_height, append.height
-                            );
-                        }
-                    } else {
-                        panic!("Internal error: propagate timestamp failed (no known block)");
-                    }
-                } else {
-                    self.last_block_height = Some(append.height);
-                    self.last_block_timestamp = append.timestamp;
-                }
+                self.timestamps.propagate(append);
                 self.buffer.push(update);
             }
             BlockchainUpdate::Rollback(ref rollback) => {
@@ -109,26 +199,65 @@ impl Batcher {
                         }
                     }
                 }
-                self.last_block_height = None;
-                self.last_block_timestamp = None;
+                self.timestamps.reset();
                 self.buffer.push(update); // Let database handle the rollback
             }
         }
     }
 
-    fn need_flush(&self) -> bool {
+    /// Collects the current key block and its microblocks in `pending_block`.
+    /// Returns the update to be buffered as usual, if any:
+    /// either the finalized (merged) previous block or a rollback beyond the pending block.
+    fn hold_pending_block(&mut self, update: BlockchainUpdate) -> Option<BlockchainUpdate> {
+        match update {
+            BlockchainUpdate::Append(append) if append.is_microblock => {
+                match self.pending_block.first() {
+                    Some(key_block) if key_block.height == append.height => self.pending_block.push(append),
+                    _ => log::warn!(
+                        "Dropping microblock {} at height {} without a key block",
+                        append.block_id,
+                        append.height
+                    ),
+                }
+                None
+            }
+            BlockchainUpdate::Append(append) => {
+                let finalized = std::mem::replace(&mut self.pending_block, vec![append]);
+                merge_microblocks(finalized).map(BlockchainUpdate::Append)
+            }
+            BlockchainUpdate::Rollback(rollback) => {
+                let pos = self.pending_block.iter().position(|b| b.block_id == rollback.block_id);
+                if let Some(i) = pos {
+                    self.pending_block.truncate(i + 1);
+                    None
+                } else {
+                    self.pending_block.clear();
+                    Some(BlockchainUpdate::Rollback(rollback))
+                }
+            }
+        }
+    }
+
+    fn need_flush(&self) -> Option<FlushReason> {
         if self.buffer.is_empty() {
-            return false;
+            return None;
+        }
+
+        // Flush if the buffer is too big, to bound the memory, whatever is on top
+        if let Some(max_buffer_len) = self.batching_params.max_buffer_len {
+            if self.buffer.len() >= max_buffer_len {
+                return Some(FlushReason::Overflow);
+            }
         }
 
         // Don't flush if there is a rollback on top, wait for the replacement block
         if let Some(BlockchainUpdate::Rollback(_)) = self.buffer.last() {
-            return false;
+            return None;
         }
 
         // Flush if there are rollbacks in the queue, but not on top (already have a replacement block)
         if self.buffer.iter().any(|u| matches!(u, BlockchainUpdate::Rollback(_))) {
-            return true;
+            return Some(FlushReason::Rollback);
         }
 
         // Flush if there is a microblock on top + some more updates below it
@@ -136,7 +265,7 @@ impl Batcher {
         if self.buffer.len() > 1 {
             if let Some(BlockchainUpdate::Append(last_append)) = self.buffer.last() {
                 if last_append.is_microblock {
-                    return true;
+                    return Some(FlushReason::Microblock);
                 }
             }
         }
@@ -144,23 +273,23 @@ impl Batcher {
         // FLush if there are enough updates in the buffer
         if let Some(max_updates) = self.batching_params.max_updates {
             if self.buffer.len() >= max_updates {
-                return true;
+                return Some(FlushReason::Size);
             }
         }
 
         // Flush if max_delay exceeded since last flush
         if let Some(max_delay) = self.batching_params.max_delay {
             if self.last_flush.elapsed() >= max_delay {
-                return true;
+                return Some(FlushReason::Delay);
             }
         }
 
         // Don't buffer if not batching parameters set (or we risk to stash forever...)
         if self.batching_params.max_updates.is_none() && self.batching_params.max_delay.is_none() {
-            return true;
+            return Some(FlushReason::Unbatched);
         }
 
-        false
+        None
     }
 
     async fn flush(&mut self) -> Result<(), mpsc::error::SendError<Vec<BlockchainUpdate>>> {
@@ -172,11 +301,314 @@ impl Batcher {
             }
         }
         let updates = self.buffer.drain(..).collect_vec();
+        // Counted before the send, so that the receiving side never decrements it below zero
+        BATCH_QUEUE_DEPTH.inc();
         self.output.send(updates).await?;
         if let Some(delayed_update) = delayed_update {
             self.buffer.push(delayed_update);
         }
         self.last_flush = Instant::now();
+        self.state.buffer_len.store(self.buffer.len(), Ordering::Relaxed);
+        self.state
+            .last_flush_at
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
         Ok(())
     }
 }
+
+/// Microblocks have no timestamp of their own, they get it from their key block
+#[derive(Default)]
+struct TimestampPropagation {
+    last_block_timestamp: Option<u64>,
+    last_block_height: Option<u32>,
+}
+
+impl TimestampPropagation {
+    fn propagate(&mut self, append: &mut AppendBlock) {
+        // Propagate timestamp from the last known block at the same height to the microblock
+        if append.is_microblock && append.timestamp.is_none() {
+            if let Some(last_height) = self.last_block_height {
+                if last_height == append.height {
+                    assert!(
+                        self.last_block_timestamp.is_some(),
+                        "Internal error: propagate timestamp failed (no saved timestamp)"
+                    );
+                    append.timestamp = self.last_block_timestamp;
+                } else {
+                    panic!(
+                        "Internal error: propagate timestamp failed (last_height={}, append.height={})",
+                        last_height, append.height
+                    );
+                }
+            } else {
+                panic!("Internal error: propagate timestamp failed (no known block)");
+            }
+        } else {
+            self.last_block_height = Some(append.height);
+            self.last_block_timestamp = append.timestamp;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_block_height = None;
+        self.last_block_timestamp = None;
+    }
+}
+
+/// Merges a key block with its microblocks into a single block.
+/// The resulting block id is the total block id of the last microblock,
+/// which is the id the finalized block is known by.
+fn merge_microblocks(blocks: Vec<AppendBlock>) -> Option<AppendBlock> {
+    let mut blocks = blocks.into_iter();
+    let mut block = blocks.next()?;
+    for micro_block in blocks {
+        block.block_id = micro_block.block_id;
+        block.transactions.extend(micro_block.transactions);
+        block.quarantined.extend(micro_block.quarantined);
+    }
+    Some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::updates::Rollback;
+
+    fn append(id: &str, height: u32, is_microblock: bool) -> BlockchainUpdate {
+        BlockchainUpdate::Append(AppendBlock {
+            block_id: id.to_owned(),
+            height,
+            timestamp: (!is_microblock).then_some(height as u64 * 60000),
+            is_microblock,
+            transactions: Vec::new(),
+            quarantined: Vec::new(),
+        })
+    }
+
+    fn rollback(block_id: &str) -> BlockchainUpdate {
+        BlockchainUpdate::Rollback(Rollback {
+            block_id: block_id.to_owned(),
+        })
+    }
+
+    /// Pass the updates through the batcher, returns all the updates passed on
+    async fn run(params: BatchingParams, updates: Vec<BlockchainUpdate>) -> Vec<BlockchainUpdate> {
+        let (tx, rx) = mpsc::channel(updates.len().max(1));
+        for update in updates {
+            tx.send(update).await.unwrap();
+        }
+        drop(tx);
+        let mut output = start(rx, params, Arc::default());
+        let mut updates = Vec::new();
+        while let Some(batch) = output.recv().await {
+            updates.extend(batch);
+        }
+        updates
+    }
+
+    #[tokio::test]
+    async fn microblocks_are_skipped() {
+        let params = BatchingParams {
+            skip_microblocks: true,
+            queue_capacity: 10,
+            ..BatchingParams::default()
+        };
+        let updates = vec![
+            append("b1", 1, false),
+            append("m1", 1, true),
+            append("m2", 1, true),
+            append("b2", 2, false),
+            append("m3", 2, true),
+            // Microblock rolled back before its key block is finalized
+            rollback("b2"),
+            append("b3", 3, false),
+            append("m4", 3, true),
+        ];
+        let blocks = run(params, updates).await.into_iter().map(|update| match update {
+            BlockchainUpdate::Append(append) => (append.block_id, append.height, append.is_microblock),
+            BlockchainUpdate::Rollback(_) => panic!("unexpected rollback"),
+        });
+        // The last block is not finalized yet, a key block is known by the id of its last microblock
+        assert_eq!(
+            blocks.collect_vec(),
+            [("m2".to_owned(), 1, false), ("b2".to_owned(), 2, false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn buffer_is_flushed_when_input_closes() {
+        let params = BatchingParams {
+            max_updates: Some(10),
+            queue_capacity: 10,
+            ..BatchingParams::default()
+        };
+        let updates = vec![append("b1", 1, false), append("b2", 2, false), append("m1", 2, true)];
+        let block_ids = run(params, updates).await.into_iter().map(|update| match update {
+            BlockchainUpdate::Append(append) => append.block_id,
+            BlockchainUpdate::Rollback(_) => panic!("unexpected rollback"),
+        });
+        assert_eq!(block_ids.collect_vec(), ["b1", "b2", "m1"]);
+    }
+
+    #[tokio::test]
+    async fn unbatched_updates_are_passed_on_immediately() {
+        let (tx, rx) = mpsc::channel(10);
+        let mut output = start_unbatched(rx);
+        let updates = vec![
+            append("b1", 1, false),
+            append("m1", 1, true),
+            rollback("b1"),
+            append("b2", 2, false),
+        ];
+        let block_id = |update: &BlockchainUpdate| match update {
+            BlockchainUpdate::Append(append) => append.block_id.clone(),
+            BlockchainUpdate::Rollback(rollback) => format!("rollback to {}", rollback.block_id),
+        };
+        // The input stays open, so nothing is passed on at its end
+        for update in updates {
+            let expected = block_id(&update);
+            tx.send(update).await.unwrap();
+            let batch = tokio::time::timeout(Duration::from_secs(1), output.recv())
+                .await
+                .expect("update is not passed on")
+                .unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(block_id(&batch[0]), expected);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_wait_for_the_writer_up_to_queue_capacity() {
+        let params = BatchingParams {
+            queue_capacity: 3,
+            ..BatchingParams::default()
+        };
+        let (tx, rx) = mpsc::channel(10);
+        for height in 1..=5 {
+            tx.send(append(&format!("b{height}"), height, false)).await.unwrap();
+        }
+        let mut output = start(rx, params, Arc::default());
+        // Nobody reads the output, so the batcher stops once the queue is full
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut queued = 0;
+        while output.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, 3);
+    }
+
+    async fn next_batch(output: &mut mpsc::Receiver<Vec<BlockchainUpdate>>) -> Vec<BlockchainUpdate> {
+        let batch = tokio::time::timeout(Duration::from_secs(1), output.recv());
+        batch.await.expect("buffer is not flushed").unwrap()
+    }
+
+    #[tokio::test]
+    async fn overflowing_buffer_is_flushed_early() {
+        let overflows = || BATCH_FLUSHES.with_label_values(&["overflow"]).get();
+        let before = overflows();
+        let params = BatchingParams {
+            max_updates: Some(100),
+            max_delay: Some(Duration::from_secs(3600)),
+            max_buffer_len: Some(5),
+            queue_capacity: 10,
+            ..BatchingParams::default()
+        };
+        let (tx, rx) = mpsc::channel(20);
+        let mut output = start(rx, params, Arc::default());
+
+        // The input stays open and neither the size nor the delay is reached
+        for height in 1..=7 {
+            tx.send(append(&format!("b{height}"), height, false)).await.unwrap();
+        }
+        assert_eq!(next_batch(&mut output).await.len(), 5);
+        // Even with a rollback on top, it is left to the database
+        for update in [rollback("b4"), rollback("b3"), rollback("b2")] {
+            tx.send(update).await.unwrap();
+        }
+        let batch = next_batch(&mut output).await;
+        assert_eq!(batch.len(), 5);
+        assert!(matches!(batch.last(), Some(BlockchainUpdate::Rollback(_))));
+        assert_eq!(overflows(), before + 2);
+    }
+
+    fn batcher(params: BatchingParams, buffer: Vec<BlockchainUpdate>, since_last_flush: Duration) -> Batcher {
+        let (_, input) = mpsc::channel(1);
+        let (output, _) = mpsc::channel(1);
+        Batcher {
+            input,
+            output,
+            batching_params: params,
+            buffer,
+            timestamps: TimestampPropagation::default(),
+            pending_block: Vec::new(),
+            last_flush: Instant::now() - since_last_flush,
+            state: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn flush_reasons() {
+        let params = BatchingParams {
+            max_updates: Some(3),
+            max_delay: Some(Duration::from_secs(1)),
+            max_buffer_len: Some(5),
+            queue_capacity: 1,
+            ..BatchingParams::default()
+        };
+        let reason = |buffer: Vec<BlockchainUpdate>, since_last_flush: u64| {
+            let batcher = batcher(params.clone(), buffer, Duration::from_millis(since_last_flush));
+            batcher.need_flush().map(FlushReason::as_str)
+        };
+        let blocks = |count: u32| (1..=count).map(|h| append(&format!("b{h}"), h, false)).collect_vec();
+
+        assert_eq!(reason(vec![], 5000), None);
+        assert_eq!(reason(blocks(2), 0), None);
+        assert_eq!(reason(blocks(3), 0), Some("size"));
+        assert_eq!(reason(blocks(2), 1000), Some("delay"));
+        assert_eq!(
+            reason(vec![append("b1", 1, false), append("m1", 1, true)], 0),
+            Some("microblock")
+        );
+        // A lone microblock waits, it is the most likely one to be rolled back
+        assert_eq!(reason(vec![append("m1", 1, true)], 0), None);
+        assert_eq!(reason(vec![append("b1", 1, false), rollback("b0")], 5000), None);
+        assert_eq!(
+            reason(vec![rollback("b0"), append("b1", 1, false)], 0),
+            Some("rollback")
+        );
+        let mut buffer = blocks(4);
+        buffer.push(rollback("b0"));
+        assert_eq!(reason(buffer, 0), Some("overflow"));
+
+        let unbatched = batcher(BatchingParams::default(), blocks(1), Duration::ZERO);
+        assert_eq!(unbatched.need_flush().map(FlushReason::as_str), Some("unbatched"));
+    }
+
+    #[tokio::test]
+    async fn flushes_are_counted_by_reason() {
+        let flushes = |reason| BATCH_FLUSHES.with_label_values(&[reason]).get();
+        let (size_before, delay_before) = (flushes("size"), flushes("delay"));
+        let params = BatchingParams {
+            max_updates: Some(3),
+            max_delay: Some(Duration::from_millis(200)),
+            queue_capacity: 10,
+            ..BatchingParams::default()
+        };
+        let (tx, rx) = mpsc::channel(10);
+        let mut output = start(rx, params, Arc::default());
+
+        for height in 1..=3 {
+            tx.send(append(&format!("b{height}"), height, false)).await.unwrap();
+        }
+        assert_eq!(output.recv().await.unwrap().len(), 3);
+        assert_eq!(flushes("size"), size_before + 1);
+
+        tx.send(append("b4", 4, false)).await.unwrap();
+        // The delay is only checked when an update comes
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        tx.send(append("b5", 5, false)).await.unwrap();
+        assert_eq!(output.recv().await.unwrap().len(), 2);
+        assert_eq!(flushes("delay"), delay_before + 1);
+        assert_eq!(flushes("size"), size_before + 1);
+    }
+}
@@ -1,12 +1,14 @@
 //! Operation services consumer's config.
 
+use std::collections::HashMap;
 use std::time::Duration;
 
 use serde::Deserialize;
 use thiserror::Error;
 
-use crate::common::database::config::PostgresConfig;
+use crate::common::database::config::{self as db_config, PostgresConfig};
 use crate::consumer::batcher::BatchingParams;
+use crate::consumer::updates::{BinaryEncoding, GrpcCompression};
 
 #[derive(Clone)]
 pub struct ConsumerConfig {
@@ -19,8 +21,70 @@ pub struct ConsumerConfig {
     /// Batching of the database writes
     pub batching: BatchingParams,
 
+    /// Write updates one by one as received, bypassing the batcher
+    pub disable_batching: bool,
+
     /// Which port to use for the metrics web-server
     pub metrics_port: u16,
+
+    /// Prefix of all the metric names, empty by default
+    pub metrics_prefix: String,
+
+    /// Labels added to all the metrics, e.g. the network
+    pub metrics_labels: HashMap<String, String>,
+
+    /// Don't touch the database, only log what would have been written
+    pub dry_run: bool,
+
+    /// Retries of the failed database writes
+    pub write_retry: RetryParams,
+
+    /// Retries of the initial database connect
+    pub db_connect_retry: RetryParams,
+
+    /// Warn about transactions with JSON bigger than this (in bytes), if set
+    pub warn_tx_size_bytes: Option<usize>,
+
+    /// Report the consumer as not ready if a batch is not written within this interval
+    pub max_write_time: Duration,
+
+    /// Number of failed readiness checks in a row before the consumer is reported as not ready
+    pub readiness_failure_threshold: u32,
+
+    /// Publish the committed operations to Kafka, if set
+    pub kafka: Option<KafkaConfig>,
+
+    /// Re-serialize the stored transactions of this height range instead of consuming, if set
+    pub reprocess: Option<ReprocessParams>,
+
+    /// Delete the stored data from this height range up and re-import the range instead of consuming, if set
+    pub replay: Option<ReplayParams>,
+
+    /// Serve the debug endpoints on this port, if enabled
+    pub debug_port: Option<u16>,
+}
+
+/// Height range to reprocess, both bounds inclusive
+#[derive(Clone, Copy, Debug)]
+pub struct ReprocessParams {
+    pub from_height: u32,
+    pub to_height: u32,
+}
+
+/// Height range to replay, both bounds inclusive
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayParams {
+    pub from_height: u32,
+    pub to_height: u32,
+}
+
+#[derive(Clone)]
+pub struct RetryParams {
+    /// How many times to retry before giving up
+    pub max_retries: u32,
+
+    /// Delay before the first retry, doubled on each subsequent retry
+    pub delay: Duration,
 }
 
 #[derive(Deserialize, Clone)]
@@ -36,6 +100,63 @@ pub struct BlockchainUpdatesConfig {
     /// On consumer start, rollback last stored height in the database to this number of blocks (default 1)
     #[serde(default = "default_start_rollback_depth")]
     pub start_rollback_depth: u32,
+
+    /// Start from `starting_height` even if the database has data stored above it,
+    /// such data gets deleted on start (default false)
+    #[serde(default)]
+    pub force_starting_height: bool,
+
+    /// Delete all stored data on start and sync from `starting_height` anew,
+    /// for intentional re-syncs on redeploy (default false)
+    #[serde(default)]
+    pub reset_on_start: bool,
+
+    /// How many times to retry connecting to blockchain-updates on start before giving up (default 5)
+    #[serde(default = "default_grpc_connect_retries")]
+    pub grpc_connect_retries: u32,
+
+    /// Delay (in ms) before the first connect retry, doubled on each subsequent retry (default 1000)
+    #[serde(default = "default_grpc_connect_retry_delay_ms")]
+    pub grpc_connect_retry_delay_ms: u64,
+
+    /// Max size (in megabytes) of a single gRPC message, i.e. a block (default 16)
+    #[serde(default = "default_grpc_max_message_size_mb")]
+    pub grpc_max_message_size_mb: u32,
+
+    /// Compression of the blockchain updates stream, either 'gzip' or 'none' (default none)
+    #[serde(default)]
+    pub grpc_compression: GrpcCompression,
+
+    /// Max nesting depth of the invoke script arguments, deeper transactions fail to convert (default 32)
+    #[serde(default = "default_max_arg_nesting_depth")]
+    pub max_arg_nesting_depth: usize,
+
+    /// Max number of the top-level invoke script arguments to store, if set
+    #[serde(rename = "max_args")]
+    pub max_args: Option<usize>,
+
+    /// Max number of the invoke script payments to store, if set
+    #[serde(rename = "max_payments")]
+    pub max_payments: Option<usize>,
+
+    /// Fail to convert the transactions over `max_args` or `max_payments`
+    /// instead of storing them truncated (default false)
+    #[serde(default)]
+    pub reject_over_limit: bool,
+
+    /// Encoding of the binary arguments and data entries, either 'base64' or 'hex' (default base64)
+    #[serde(default)]
+    pub binary_encoding: BinaryEncoding,
+
+    /// Store the transactions that fail to convert into the `quarantine` table
+    /// instead of stopping the consumer (default false)
+    #[serde(default)]
+    pub quarantine_bad_tx: bool,
+
+    /// Skip the updates of an unexpected shape (e.g. an append without the transactions)
+    /// instead of stopping the consumer (default false)
+    #[serde(default)]
+    pub skip_unconvertible_updates: bool,
 }
 
 fn default_starting_height() -> u32 {
@@ -46,12 +167,51 @@ fn default_start_rollback_depth() -> u32 {
     1
 }
 
+fn default_grpc_connect_retries() -> u32 {
+    5
+}
+
+fn default_grpc_connect_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_grpc_max_message_size_mb() -> u32 {
+    16
+}
+
+fn default_max_arg_nesting_depth() -> usize {
+    32
+}
+
+#[derive(Clone, Debug)]
+pub struct KafkaConfig {
+    /// Comma-separated `host:port` list
+    pub bootstrap_servers: String,
+    pub topic: String,
+}
+
+#[derive(Deserialize)]
+struct KafkaRawConfig {
+    #[serde(rename = "kafka_bootstrap_servers")]
+    kafka_bootstrap_servers: Option<String>,
+    #[serde(rename = "kafka_topic")]
+    kafka_topic: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct BatchingRawConfig {
     #[serde(rename = "batch_max_size", default = "default_batch_max_size")]
     batch_max_size: u32,
     #[serde(rename = "batch_max_delay_sec", default = "default_batch_max_delay_sec")]
     batch_max_delay_sec: u32,
+    #[serde(rename = "skip_microblocks", default)]
+    skip_microblocks: bool,
+    #[serde(rename = "disable_batching", default)]
+    disable_batching: bool,
+    #[serde(rename = "batch_queue_capacity", default = "default_batch_queue_capacity")]
+    batch_queue_capacity: usize,
+    #[serde(rename = "batch_max_buffer_size", default = "default_batch_max_buffer_size")]
+    batch_max_buffer_size: u32,
 }
 
 fn default_batch_max_size() -> u32 {
@@ -62,16 +222,92 @@ fn default_batch_max_delay_sec() -> u32 {
     10
 }
 
+fn default_batch_queue_capacity() -> usize {
+    1
+}
+
+fn default_batch_max_buffer_size() -> u32 {
+    10000
+}
+
 #[derive(Deserialize)]
 struct MetricsRawConfig {
     #[serde(rename = "metrics_port", default = "default_metrics_port")]
     pub metrics_port: u16,
+    #[serde(rename = "metrics_prefix", default)]
+    pub metrics_prefix: String,
+    /// Comma-separated `name=value` pairs
+    #[serde(rename = "metrics_labels")]
+    pub metrics_labels: Option<String>,
 }
 
 fn default_metrics_port() -> u16 {
     9090
 }
 
+#[derive(Deserialize)]
+struct ConsumerRawConfig {
+    #[serde(rename = "dry_run", default)]
+    dry_run: bool,
+    #[serde(rename = "db_write_retries", default = "default_db_write_retries")]
+    db_write_retries: u32,
+    #[serde(rename = "db_write_retry_delay_ms", default = "default_db_write_retry_delay_ms")]
+    db_write_retry_delay_ms: u64,
+    #[serde(rename = "db_connect_retries", default = "default_db_connect_retries")]
+    db_connect_retries: u32,
+    #[serde(rename = "db_connect_retry_delay_ms", default = "default_db_connect_retry_delay_ms")]
+    db_connect_retry_delay_ms: u64,
+    #[serde(rename = "warn_tx_size_bytes")]
+    warn_tx_size_bytes: Option<usize>,
+    #[serde(rename = "max_write_time_secs", default = "default_max_write_time_secs")]
+    max_write_time_secs: u64,
+    #[serde(
+        rename = "readiness_failure_threshold",
+        default = "default_readiness_failure_threshold"
+    )]
+    readiness_failure_threshold: u32,
+    #[serde(rename = "reprocess_from_height")]
+    reprocess_from_height: Option<u32>,
+    #[serde(rename = "reprocess_to_height")]
+    reprocess_to_height: Option<u32>,
+    #[serde(rename = "replay_from_height")]
+    replay_from_height: Option<u32>,
+    #[serde(rename = "replay_to_height")]
+    replay_to_height: Option<u32>,
+    #[serde(rename = "debug_endpoints", default)]
+    debug_endpoints: bool,
+    #[serde(rename = "debug_port", default = "default_debug_port")]
+    debug_port: u16,
+}
+
+fn default_db_write_retries() -> u32 {
+    3
+}
+
+fn default_db_write_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_db_connect_retries() -> u32 {
+    5
+}
+
+fn default_db_connect_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_write_time_secs() -> u64 {
+    600
+}
+
+fn default_readiness_failure_threshold() -> u32 {
+    1
+}
+
+fn default_debug_port() -> u16 {
+    8080
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("configuration error: {0}")]
@@ -83,14 +319,165 @@ pub enum ConfigError {
 
 pub fn load() -> Result<ConsumerConfig, ConfigError> {
     let blockchain_updates_config = envy::from_env::<BlockchainUpdatesConfig>()?;
-    let pg_config = envy::from_env::<PostgresConfig>()?;
+    let pg_config = db_config::load().map_err(|db_config::DbConfigError(e)| e)?;
     let batch_config = envy::from_env::<BatchingRawConfig>()?;
     let metrics_config = envy::from_env::<MetricsRawConfig>()?;
+    let consumer_config = envy::from_env::<ConsumerRawConfig>()?;
+    let kafka_config = envy::from_env::<KafkaRawConfig>()?;
 
     // Need this because later we are gonna cast it to i32
     if blockchain_updates_config.starting_height > i32::MAX as u32 {
         return Err(ConfigError::ValidationError("STARTING_HEIGHT", "value is too big"));
     }
+    if blockchain_updates_config.start_rollback_depth > i32::MAX as u32 {
+        return Err(ConfigError::ValidationError("START_ROLLBACK_DEPTH", "value is too big"));
+    }
+
+    if blockchain_updates_config.grpc_max_message_size_mb == 0 {
+        return Err(ConfigError::ValidationError(
+            "GRPC_MAX_MESSAGE_SIZE_MB",
+            "must be positive",
+        ));
+    }
+
+    if blockchain_updates_config.max_arg_nesting_depth == 0 {
+        return Err(ConfigError::ValidationError(
+            "MAX_ARG_NESTING_DEPTH",
+            "must be positive",
+        ));
+    }
+
+    if blockchain_updates_config.max_args == Some(0) {
+        return Err(ConfigError::ValidationError("MAX_ARGS", "must be positive"));
+    }
+
+    if blockchain_updates_config.max_payments == Some(0) {
+        return Err(ConfigError::ValidationError("MAX_PAYMENTS", "must be positive"));
+    }
+
+    if consumer_config.max_write_time_secs == 0 {
+        return Err(ConfigError::ValidationError("MAX_WRITE_TIME_SECS", "must be positive"));
+    }
+
+    if consumer_config.readiness_failure_threshold == 0 {
+        return Err(ConfigError::ValidationError(
+            "READINESS_FAILURE_THRESHOLD",
+            "must be positive",
+        ));
+    }
+
+    if batch_config.batch_queue_capacity == 0 {
+        return Err(ConfigError::ValidationError("BATCH_QUEUE_CAPACITY", "must be positive"));
+    }
+
+    if batch_config.batch_max_buffer_size < batch_config.batch_max_size.max(1) {
+        return Err(ConfigError::ValidationError(
+            "BATCH_MAX_BUFFER_SIZE",
+            "must be positive and at least BATCH_MAX_SIZE",
+        ));
+    }
+
+    if !is_valid_metrics_prefix(&metrics_config.metrics_prefix) {
+        return Err(ConfigError::ValidationError(
+            "METRICS_PREFIX",
+            "must consist of ASCII letters, digits, underscores and colons, and not start with a digit",
+        ));
+    }
+
+    if consumer_config.debug_endpoints && consumer_config.debug_port == metrics_config.metrics_port {
+        return Err(ConfigError::ValidationError(
+            "DEBUG_PORT",
+            "must differ from METRICS_PORT",
+        ));
+    }
+
+    let metrics_labels = parse_metrics_labels(metrics_config.metrics_labels.as_deref().unwrap_or_default())?;
+
+    if batch_config.disable_batching && batch_config.skip_microblocks {
+        return Err(ConfigError::ValidationError(
+            "DISABLE_BATCHING",
+            "can't be used together with SKIP_MICROBLOCKS",
+        ));
+    }
+
+    let kafka = match (kafka_config.kafka_bootstrap_servers, kafka_config.kafka_topic) {
+        (None, None) => None,
+        (Some(bootstrap_servers), Some(topic)) => Some(KafkaConfig {
+            bootstrap_servers,
+            topic,
+        }),
+        _ => {
+            return Err(ConfigError::ValidationError(
+                "KAFKA_TOPIC",
+                "must be set together with KAFKA_BOOTSTRAP_SERVERS",
+            ))
+        }
+    };
+
+    let reprocess = match (
+        consumer_config.reprocess_from_height,
+        consumer_config.reprocess_to_height,
+    ) {
+        (None, None) => None,
+        (Some(from_height), Some(to_height)) => {
+            if from_height > to_height {
+                return Err(ConfigError::ValidationError(
+                    "REPROCESS_FROM_HEIGHT",
+                    "must not be greater than REPROCESS_TO_HEIGHT",
+                ));
+            }
+            if to_height > i32::MAX as u32 {
+                return Err(ConfigError::ValidationError("REPROCESS_TO_HEIGHT", "value is too big"));
+            }
+            if consumer_config.dry_run {
+                return Err(ConfigError::ValidationError(
+                    "REPROCESS_FROM_HEIGHT",
+                    "can't be used together with DRY_RUN",
+                ));
+            }
+            Some(ReprocessParams { from_height, to_height })
+        }
+        _ => {
+            return Err(ConfigError::ValidationError(
+                "REPROCESS_FROM_HEIGHT",
+                "must be set together with REPROCESS_TO_HEIGHT",
+            ))
+        }
+    };
+
+    let replay = match (consumer_config.replay_from_height, consumer_config.replay_to_height) {
+        (None, None) => None,
+        (Some(from_height), Some(to_height)) => {
+            if from_height == 0 || from_height > to_height {
+                return Err(ConfigError::ValidationError(
+                    "REPLAY_FROM_HEIGHT",
+                    "must be positive and not greater than REPLAY_TO_HEIGHT",
+                ));
+            }
+            if to_height > i32::MAX as u32 {
+                return Err(ConfigError::ValidationError("REPLAY_TO_HEIGHT", "value is too big"));
+            }
+            if consumer_config.dry_run {
+                return Err(ConfigError::ValidationError(
+                    "REPLAY_FROM_HEIGHT",
+                    "can't be used together with DRY_RUN",
+                ));
+            }
+            if reprocess.is_some() {
+                return Err(ConfigError::ValidationError(
+                    "REPLAY_FROM_HEIGHT",
+                    "can't be used together with REPROCESS_FROM_HEIGHT",
+                ));
+            }
+            Some(ReplayParams { from_height, to_height })
+        }
+        _ => {
+            return Err(ConfigError::ValidationError(
+                "REPLAY_FROM_HEIGHT",
+                "must be set together with REPLAY_TO_HEIGHT",
+            ))
+        }
+    };
 
     let config = ConsumerConfig {
         blockchain_updates: blockchain_updates_config,
@@ -98,9 +485,121 @@ pub fn load() -> Result<ConsumerConfig, ConfigError> {
         batching: BatchingParams {
             max_updates: Some(batch_config.batch_max_size as usize),
             max_delay: Some(Duration::from_secs(batch_config.batch_max_delay_sec as u64)),
+            skip_microblocks: batch_config.skip_microblocks,
+            max_buffer_len: Some(batch_config.batch_max_buffer_size as usize),
+            queue_capacity: batch_config.batch_queue_capacity,
         },
+        disable_batching: batch_config.disable_batching,
         metrics_port: metrics_config.metrics_port,
+        metrics_prefix: metrics_config.metrics_prefix,
+        metrics_labels,
+        dry_run: consumer_config.dry_run,
+        write_retry: RetryParams {
+            max_retries: consumer_config.db_write_retries,
+            delay: Duration::from_millis(consumer_config.db_write_retry_delay_ms),
+        },
+        db_connect_retry: RetryParams {
+            max_retries: consumer_config.db_connect_retries,
+            delay: Duration::from_millis(consumer_config.db_connect_retry_delay_ms),
+        },
+        warn_tx_size_bytes: consumer_config.warn_tx_size_bytes,
+        max_write_time: Duration::from_secs(consumer_config.max_write_time_secs),
+        readiness_failure_threshold: consumer_config.readiness_failure_threshold,
+        kafka,
+        reprocess,
+        replay,
+        debug_port: consumer_config.debug_endpoints.then_some(consumer_config.debug_port),
     };
 
     Ok(config)
 }
+
+/// Prometheus metric names must match `[a-zA-Z_:][a-zA-Z0-9_:]*`, the prefix is their start
+fn is_valid_metrics_prefix(prefix: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':';
+    prefix.chars().all(valid_char) && !prefix.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Names of the variable labels of the metrics, a constant label of the same name would be rendered twice
+const RESERVED_METRICS_LABELS: [&str; 3] = ["type", "reason", "field"];
+
+/// Parses `network=mainnet,env=prod`, label names must match `[a-zA-Z_][a-zA-Z0-9_]*`,
+/// the names starting with `__` are reserved by Prometheus
+fn parse_metrics_labels(labels: &str) -> Result<HashMap<String, String>, ConfigError> {
+    labels
+        .split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(|label| {
+            let (name, value) = label.split_once('=').ok_or(ConfigError::ValidationError(
+                "METRICS_LABELS",
+                "expected name=value pairs",
+            ))?;
+            let name = name.trim();
+            let valid_name = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid_name {
+                return Err(ConfigError::ValidationError("METRICS_LABELS", "invalid label name"));
+            }
+            if RESERVED_METRICS_LABELS.contains(&name) || name.starts_with("__") {
+                return Err(ConfigError::ValidationError("METRICS_LABELS", "reserved label name"));
+            }
+            Ok((name.to_owned(), value.trim().to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn metrics_labels() {
+        assert_eq!(parse_metrics_labels("").unwrap(), labels(&[]));
+        assert_eq!(
+            parse_metrics_labels(" network = mainnet, env=prod,").unwrap(),
+            labels(&[("network", "mainnet"), ("env", "prod")])
+        );
+        fn error(labels: &str) -> (&'static str, &'static str) {
+            match parse_metrics_labels(labels) {
+                Err(ConfigError::ValidationError(name, reason)) => (name, reason),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(error("network"), ("METRICS_LABELS", "expected name=value pairs"));
+        assert_eq!(error("1net=mainnet"), ("METRICS_LABELS", "invalid label name"));
+        assert_eq!(error("net-work=mainnet"), ("METRICS_LABELS", "invalid label name"));
+        // Taken by the metrics themselves
+        for reserved in ["type", "reason", "field", "__name__"] {
+            let labels = format!("network=mainnet,{}=x", reserved);
+            assert_eq!(error(&labels), ("METRICS_LABELS", "reserved label name"));
+        }
+    }
+
+    #[test]
+    fn grpc_compression() {
+        let config = |vars: &[(&str, &str)]| {
+            let vars = [("BLOCKCHAIN_UPDATES_URL", "http://localhost:6881")].iter().chain(vars);
+            let vars = vars.map(|&(name, value)| (name.to_owned(), value.to_owned()));
+            envy::from_iter::<_, BlockchainUpdatesConfig>(vars).map(|config| config.grpc_compression)
+        };
+        assert!(matches!(config(&[]), Ok(GrpcCompression::None)));
+        assert!(matches!(
+            config(&[("GRPC_COMPRESSION", "none")]),
+            Ok(GrpcCompression::None)
+        ));
+        assert!(matches!(
+            config(&[("GRPC_COMPRESSION", "gzip")]),
+            Ok(GrpcCompression::Gzip)
+        ));
+        assert!(config(&[("GRPC_COMPRESSION", "zstd")]).is_err());
+    }
+}
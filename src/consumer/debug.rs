@@ -0,0 +1,133 @@
+//! Debug endpoints of the consumer, for tuning, served only with `DEBUG_ENDPOINTS=true`.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use warp::{filters::BoxedFilter, reply::Json, Filter};
+
+use crate::consumer::batcher::{BatcherState, BatchingParams};
+
+/// Response of the GET `/debug/batcher` endpoint
+#[derive(Serialize)]
+struct BatcherInfo {
+    disable_batching: bool,
+    max_updates: Option<usize>,
+    max_delay_ms: Option<u64>,
+    skip_microblocks: bool,
+    max_buffer_len: Option<usize>,
+    queue_capacity: usize,
+    /// Number of the updates waiting in the buffer
+    buffer_len: usize,
+    /// Time since the last batch was passed on, `null` if there was none yet
+    last_flush_age_ms: Option<u64>,
+}
+
+pub fn routes(params: BatchingParams, disable_batching: bool, state: Arc<BatcherState>) -> BoxedFilter<(Json,)> {
+    warp::path!("debug" / "batcher")
+        .and(warp::get())
+        .map(move || {
+            warp::reply::json(&BatcherInfo {
+                disable_batching,
+                max_updates: params.max_updates,
+                max_delay_ms: params.max_delay.map(|d| d.as_millis() as u64),
+                skip_microblocks: params.skip_microblocks,
+                max_buffer_len: params.max_buffer_len,
+                queue_capacity: params.queue_capacity,
+                buffer_len: state.buffer_len(),
+                last_flush_age_ms: state.last_flush_age().map(|d| d.as_millis() as u64),
+            })
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::consumer::batcher;
+    use crate::consumer::updates::{AppendBlock, BlockchainUpdate};
+
+    fn append(id: &str, height: u32) -> BlockchainUpdate {
+        BlockchainUpdate::Append(AppendBlock {
+            block_id: id.to_owned(),
+            height,
+            timestamp: Some(height as u64 * 60000),
+            is_microblock: false,
+            transactions: Vec::new(),
+            quarantined: Vec::new(),
+        })
+    }
+
+    async fn batcher_info(routes: &BoxedFilter<(Json,)>) -> Value {
+        let res = warp::test::request().path("/debug/batcher").reply(routes).await;
+        assert_eq!(res.status(), 200);
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    /// Poll the endpoint until the buffer has the given length, the batcher runs in the background
+    async fn wait_for_buffer_len(routes: &BoxedFilter<(Json,)>, buffer_len: usize) -> Value {
+        for _ in 0..100 {
+            let info = batcher_info(routes).await;
+            if info["buffer_len"] == buffer_len {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("buffer length is not {}", buffer_len);
+    }
+
+    #[tokio::test]
+    async fn batcher_info_fields() {
+        let params = BatchingParams {
+            max_updates: Some(3),
+            max_delay: Some(Duration::from_secs(60)),
+            skip_microblocks: false,
+            max_buffer_len: Some(100),
+            queue_capacity: 5,
+        };
+        let state = Arc::new(BatcherState::default());
+        let routes = routes(params.clone(), false, state.clone());
+        let (tx, rx) = mpsc::channel(10);
+        let mut output = batcher::start(rx, params, state);
+
+        tx.send(append("b1", 1)).await.unwrap();
+        tx.send(append("b2", 2)).await.unwrap();
+        let info = wait_for_buffer_len(&routes, 2).await;
+        let expected = json!({
+            "disable_batching": false,
+            "max_updates": 3,
+            "max_delay_ms": 60000,
+            "skip_microblocks": false,
+            "max_buffer_len": 100,
+            "queue_capacity": 5,
+            "buffer_len": 2,
+            "last_flush_age_ms": null,
+        });
+        assert_eq!(info, expected);
+
+        // Fills up the batch
+        tx.send(append("b3", 3)).await.unwrap();
+        assert_eq!(output.recv().await.unwrap().len(), 3);
+        let info = wait_for_buffer_len(&routes, 0).await;
+        let age = info["last_flush_age_ms"].as_u64().unwrap();
+        assert!(age < 60000, "{}", age);
+    }
+
+    #[tokio::test]
+    async fn batching_disabled() {
+        let params = BatchingParams {
+            queue_capacity: 1,
+            ..BatchingParams::default()
+        };
+        let routes = routes(params, true, Arc::default());
+        let info = batcher_info(&routes).await;
+        assert_eq!(info["disable_batching"], true);
+        assert_eq!(info["max_updates"], Value::Null);
+        assert_eq!(info["max_delay_ms"], Value::Null);
+        assert_eq!(info["buffer_len"], 0);
+    }
+}
@@ -1,15 +1,214 @@
 //! Operations service's consumer metrics.
 
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
 use lazy_static::lazy_static;
-use prometheus::IntGauge;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Opts};
+
+static PREFIX: OnceLock<String> = OnceLock::new();
+
+/// Set the prefix of all the metric names, must be called before any metric is used.
+pub fn set_prefix(prefix: String) {
+    if PREFIX.set(prefix).is_err() {
+        log::warn!("Metrics prefix is already set");
+    }
+}
+
+static CONST_LABELS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Set the labels added to all the metrics (e.g. the network), must be called before any metric is used.
+pub fn set_const_labels(labels: HashMap<String, String>) {
+    if CONST_LABELS.set(labels).is_err() {
+        log::warn!("Metrics labels are already set");
+    }
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    let prefix = PREFIX.get().map(String::as_str).unwrap_or_default();
+    let const_labels = CONST_LABELS.get().cloned().unwrap_or_default();
+    Opts::new(metric_name(prefix, name), help).const_labels(const_labels)
+}
+
+/// Without a prefix the names stay as they have always been (`UpdatesBatchSize`),
+/// with one they follow the Prometheus convention (`operations_updates_batch_size`).
+fn metric_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        return name.to_owned();
+    }
+    let mut res = String::with_capacity(prefix.len() + name.len() + 4);
+    res.push_str(prefix);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                res.push('_');
+            }
+            res.push(c.to_ascii_lowercase());
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
 
 lazy_static! {
-    pub static ref HEIGHT: IntGauge = IntGauge::new("Height", "Currently imported height")
-        .expect("can't create Height metric");
-    pub static ref UPDATES_BATCH_SIZE: IntGauge = IntGauge::new("UpdatesBatchSize", "Number of updates in each batch")
-        .expect("can't create UpdatesBatchSize metric");
-    pub static ref UPDATES_BATCH_TIME: IntGauge = IntGauge::new("UpdatesBatchTimeMs", "Time (in ms) of each batch")
-        .expect("can't create UpdatesBatchTimeMs metric");
-    pub static ref DB_WRITE_TIME: IntGauge = IntGauge::new("DatabaseWriteTimeMs", "Time (in ms) of DB writes")
-        .expect("can't create DatabaseWriteTimeMs metric");
+    pub static ref HEIGHT: IntGauge =
+        IntGauge::with_opts(opts("Height", "Currently imported height")).expect("can't create Height metric");
+    pub static ref UPDATES_BATCH_SIZE: IntGauge =
+        IntGauge::with_opts(opts("UpdatesBatchSize", "Number of updates in each batch"))
+            .expect("can't create UpdatesBatchSize metric");
+    pub static ref UPDATES_BATCH_TIME: IntGauge =
+        IntGauge::with_opts(opts("UpdatesBatchTimeMs", "Time (in ms) of each batch"))
+            .expect("can't create UpdatesBatchTimeMs metric");
+    pub static ref DB_WRITE_TIME: IntGauge =
+        IntGauge::with_opts(opts("DatabaseWriteTimeMs", "Time (in ms) of DB writes"))
+            .expect("can't create DatabaseWriteTimeMs metric");
+    pub static ref DB_COMMITS: IntCounter =
+        IntCounter::with_opts(opts("DbCommits", "Number of batches committed to the database"))
+            .expect("can't create DbCommits metric");
+    pub static ref DB_ROLLBACKS: IntCounter = IntCounter::with_opts(opts(
+        "DbRollbacks",
+        "Number of batch writes that failed and were rolled back, retries included"
+    ))
+    .expect("can't create DbRollbacks metric");
+    pub static ref CHAIN_HEIGHT: IntGauge = IntGauge::with_opts(opts(
+        "ChainHeight",
+        "Latest height seen in blockchain updates (the chain tip once in live sync)"
+    ))
+    .expect("can't create ChainHeight metric");
+    pub static ref HEIGHT_LAG: IntGauge = IntGauge::with_opts(opts("HeightLag", "Chain height minus imported height"))
+        .expect("can't create HeightLag metric");
+    pub static ref HEIGHT_GAPS: IntCounter = IntCounter::with_opts(opts(
+        "HeightGaps",
+        "Number of times blockchain updates skipped one or more heights"
+    ))
+    .expect("can't create HeightGaps metric");
+    pub static ref LAST_WRITE_TIMESTAMP: IntGauge = IntGauge::with_opts(opts(
+        "LastWriteTimestamp",
+        "Unix time (in seconds) of the last successful database write"
+    ))
+    .expect("can't create LastWriteTimestamp metric");
+    pub static ref OVERSIZED_TRANSACTIONS: IntCounter = IntCounter::with_opts(opts(
+        "OversizedTransactions",
+        "Number of imported transactions with JSON bigger than WARN_TX_SIZE_BYTES"
+    ))
+    .expect("can't create OversizedTransactions metric");
+    pub static ref TRANSACTIONS_BY_TYPE: IntCounterVec = IntCounterVec::new(
+        opts("TransactionsByType", "Number of imported transactions by type"),
+        &["type"]
+    )
+    .expect("can't create TransactionsByType metric");
+    pub static ref BATCH_FLUSHES: IntCounterVec = IntCounterVec::new(
+        opts(
+            "BatchFlushes",
+            "Number of batches passed on by the batcher, by the flush reason"
+        ),
+        &["reason"]
+    )
+    .expect("can't create BatchFlushes metric");
+    pub static ref BATCH_QUEUE_DEPTH: IntGauge = IntGauge::with_opts(opts(
+        "BatchQueueDepth",
+        "Number of batches waiting for the database writer, above BATCH_QUEUE_CAPACITY when the batcher is blocked"
+    ))
+    .expect("can't create BatchQueueDepth metric");
+    pub static ref KAFKA_PUBLISH_ERRORS: IntCounter = IntCounter::with_opts(opts(
+        "KafkaPublishErrors",
+        "Number of committed operations that failed to publish to Kafka"
+    ))
+    .expect("can't create KafkaPublishErrors metric");
+    pub static ref TRUNCATED_TRANSACTIONS: IntCounterVec = IntCounterVec::new(
+        opts(
+            "TruncatedTransactions",
+            "Number of imported transactions with args or payments truncated to MAX_ARGS or MAX_PAYMENTS"
+        ),
+        &["field"]
+    )
+    .expect("can't create TruncatedTransactions metric");
+    pub static ref ANOMALOUS_PROOFS: IntCounter = IntCounter::with_opts(opts(
+        "AnomalousProofs",
+        "Number of imported transactions with proofs of unusual length or duplicate proofs"
+    ))
+    .expect("can't create AnomalousProofs metric");
+    pub static ref CONVERSION_ERRORS: IntCounterVec = IntCounterVec::new(
+        opts(
+            "ConversionErrors",
+            "Number of blockchain updates or transactions that failed to convert, by the reason"
+        ),
+        &["reason"]
+    )
+    .expect("can't create ConversionErrors metric");
+}
+
+/// Recompute `HEIGHT_LAG`, must be called whenever `HEIGHT` or `CHAIN_HEIGHT` changes.
+pub fn update_height_lag() {
+    if let Some(lag) = height_lag(CHAIN_HEIGHT.get(), HEIGHT.get()) {
+        HEIGHT_LAG.set(lag);
+    }
+}
+
+/// Unknown until any chain height is seen, never negative (the chain height may be a bit stale)
+fn height_lag(chain_height: i64, height: i64) -> Option<i64> {
+    if chain_height > 0 {
+        Some((chain_height - height).max(0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Encoder, Registry, TextEncoder};
+
+    use super::*;
+
+    #[test]
+    fn lag() {
+        assert_eq!(height_lag(0, 0), None);
+        assert_eq!(height_lag(0, 100), None);
+        assert_eq!(height_lag(100, 0), Some(100));
+        assert_eq!(height_lag(100, 90), Some(10));
+        assert_eq!(height_lag(100, 100), Some(0));
+        assert_eq!(height_lag(100, 101), Some(0));
+    }
+
+    #[test]
+    fn prefixed_names() {
+        assert_eq!(metric_name("", "UpdatesBatchTimeMs"), "UpdatesBatchTimeMs");
+        assert_eq!(metric_name("operations_", "Height"), "operations_height");
+        assert_eq!(
+            metric_name("operations_", "UpdatesBatchTimeMs"),
+            "operations_updates_batch_time_ms"
+        );
+        assert_eq!(metric_name("operations:", "DbCommits"), "operations:db_commits");
+    }
+
+    #[test]
+    fn prefixed_name_is_rendered() {
+        let registry = Registry::new();
+        let gauge = IntGauge::with_opts(Opts::new(metric_name("operations_", "ChainHeight"), "Chain height")).unwrap();
+        gauge.set(42);
+        registry.register(Box::new(gauge)).unwrap();
+        let mut rendered = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut rendered).unwrap();
+        let rendered = String::from_utf8(rendered).unwrap();
+        assert!(rendered.contains("\noperations_chain_height 42\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn const_labels_are_rendered() {
+        let const_labels = HashMap::from([("network".to_owned(), "mainnet".to_owned())]);
+        let registry = Registry::new();
+        let opts = Opts::new("TransactionsByType", "Transactions").const_labels(const_labels);
+        let counter = IntCounterVec::new(opts, &["type"]).unwrap();
+        counter.with_label_values(&["lease"]).inc();
+        registry.register(Box::new(counter)).unwrap();
+        let mut rendered = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut rendered).unwrap();
+        let rendered = String::from_utf8(rendered).unwrap();
+        assert!(
+            rendered.contains("\nTransactionsByType{network=\"mainnet\",type=\"lease\"} 1\n"),
+            "{}",
+            rendered
+        );
+    }
 }
@@ -2,104 +2,591 @@
 
 mod batcher;
 mod config;
+mod debug;
 mod metrics;
 mod model;
-mod storage;
+mod prepare;
+mod reprocess;
+mod sink;
+pub mod storage;
 mod updates;
+mod watchdog;
+
+#[doc(hidden)]
+pub use self::updates::convert_for_bench;
 
 pub async fn main() -> Result<(), anyhow::Error> {
     let config = config::load()?;
+    // Before any metric is created
+    metrics::set_prefix(config.metrics_prefix.clone());
+    metrics::set_const_labels(config.metrics_labels.clone());
     consumer::run(config).await
 }
 
 #[allow(clippy::module_inception)]
 mod consumer {
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::sync::Arc;
     use std::time::Instant;
 
     use diesel::{pg::PgConnection, Connection};
+    use itertools::Itertools;
     use std::time::Duration;
-    use tokio::task;
+    use tokio::{sync::mpsc, task};
+    use warp::{filters::BoxedFilter, reply::Json};
 
     use wavesexchange_liveness::channel;
-    use wx_warp::endpoints::MetricsWarpBuilder;
+    use wx_warp::endpoints::{MetricsWarpBuilder, Readiness};
 
-    use crate::consumer::batcher;
-    use crate::consumer::config::ConsumerConfig;
-    use crate::consumer::metrics::{DB_WRITE_TIME, HEIGHT, UPDATES_BATCH_SIZE, UPDATES_BATCH_TIME};
-    use crate::consumer::storage::{PostgresStorage, Repo, Storage};
-    use crate::consumer::updates::{BlockchainUpdate, BlockchainUpdates, BlockchainUpdatesSource};
+    #[cfg(feature = "sqlite")]
+    use crate::common::database::config as db_config;
+    use crate::common::database::config::PostgresConfig;
+    use crate::consumer::batcher::{self, BatcherState};
+    use crate::consumer::config::{
+        BlockchainUpdatesConfig, ConsumerConfig, ReplayParams, ReprocessParams, RetryParams,
+    };
+    use crate::consumer::debug;
+    use crate::consumer::metrics::{
+        update_height_lag, ANOMALOUS_PROOFS, BATCH_FLUSHES, BATCH_QUEUE_DEPTH, CHAIN_HEIGHT, CONVERSION_ERRORS,
+        DB_COMMITS, DB_ROLLBACKS, DB_WRITE_TIME, HEIGHT, HEIGHT_GAPS, HEIGHT_LAG, KAFKA_PUBLISH_ERRORS,
+        LAST_WRITE_TIMESTAMP, OVERSIZED_TRANSACTIONS, TRANSACTIONS_BY_TYPE, TRUNCATED_TRANSACTIONS, UPDATES_BATCH_SIZE,
+        UPDATES_BATCH_TIME,
+    };
+    use crate::consumer::prepare::{self, PreparedBatch, PreparedUpdate};
+    use crate::consumer::reprocess;
+    #[cfg(feature = "kafka")]
+    use crate::consumer::sink::KafkaSink;
+    use crate::consumer::sink::Sink;
+    #[cfg(feature = "sqlite")]
+    use crate::consumer::storage::SqliteStorage;
+    use crate::consumer::storage::{
+        is_transient, BlockUID, NewQuarantinedTransaction, NewTransaction, PostgresStorage, Repo, Storage,
+    };
+    use crate::consumer::updates::{BlockchainUpdate, BlockchainUpdates, BlockchainUpdatesSource, ConvertLimits};
+    use crate::consumer::watchdog::{self, Heartbeat};
 
     const POLL_INTERVAL_SECS: u64 = 60;
     const MAX_BLOCK_AGE: Duration = Duration::from_secs(300);
 
     pub(super) async fn run(config: ConsumerConfig) -> anyhow::Result<()> {
+        if let Some(params) = config.reprocess {
+            return run_reprocess(config, params).await;
+        }
+        if let Some(params) = config.replay {
+            return run_replay(config, params).await;
+        }
+
+        let dry_run = config.dry_run;
+        if dry_run {
+            log::warn!("Dry-run mode: nothing will be written to the database");
+        }
+
+        let force_starting_height = config.blockchain_updates.force_starting_height;
+        let reset_on_start = config.blockchain_updates.reset_on_start;
+        let configured_starting_height = config.blockchain_updates.starting_height;
+        if reset_on_start && !dry_run {
+            log::warn!(
+                "RESET_ON_START is set: ALL stored data will be deleted and re-imported from height {}",
+                configured_starting_height
+            );
+        } else if force_starting_height && !dry_run {
+            log::warn!(
+                "FORCE_STARTING_HEIGHT is set: all data from height {} and above will be deleted and re-imported",
+                configured_starting_height
+            );
+        }
+
+        let start_params = StartParams {
+            reset_on_start,
+            force_starting_height,
+            starting_height: configured_starting_height,
+            rollback_depth: config.blockchain_updates.start_rollback_depth,
+        };
+
+        #[cfg(feature = "sqlite")]
+        if let (Some(path), false) = (db_config::sqlite_database(), dry_run) {
+            return run_sqlite(config, start_params, &path).await;
+        }
+
         // Initialize connection to the database and fetch latest height
         let db_url = config.db.database_url();
-        let db_url_clone = db_url.clone();
-        let init_db_task = task::spawn(async move {
-            log::info!("Connecting to database: {:?}", config.db);
-            let conn = PgConnection::establish(&db_url_clone)?;
-            let storage = PostgresStorage::new(conn);
-            let last_height = storage
-                .transaction(move |repo| {
-                    let last_height = repo.last_height()?;
-                    log::info!("Last height stored in database is {:?}", last_height);
-                    let rollback_to_height = last_height.and_then(|h| {
-                        let rb = config.blockchain_updates.start_rollback_depth;
-                        if rb > 0 && h >= rb {
-                            Some(h - rb)
-                        } else {
-                            None
-                        }
-                    });
-                    if let Some(height) = rollback_to_height {
-                        repo.rollback_to_height(height)?;
-                        log::info!("Rolled back to height {} for safety", height);
-                    }
-                    Ok(last_height)
-                })
-                .await?;
-            Ok::<_, anyhow::Error>((storage, last_height))
-        });
+        // The config is still needed as a whole below, so the task gets its own copies
+        let init_db_task = task::spawn(init_db(
+            dry_run,
+            config.db.clone(),
+            config.db_connect_retry.clone(),
+            start_params,
+        ));
 
-        let init_updates_task = task::spawn(async move {
-            let url = config.blockchain_updates.blockchain_updates_url;
-            log::info!("Connecting to blockchain-updates at {}", url);
-            BlockchainUpdates::connect(url).await
-        });
+        let updates_config = config.blockchain_updates.clone();
+        let init_updates_task = task::spawn(async move { connect_updates(updates_config).await });
 
         let (storage, last_processed_height) = init_db_task.await??;
         let updates_source = init_updates_task.await??;
 
-        let readiness_channel = channel(db_url, POLL_INTERVAL_SECS, MAX_BLOCK_AGE, None);
-        let metrics_port = config.metrics_port;
+        let heartbeat = Arc::new(Heartbeat::default());
+        let readiness_channel = if dry_run {
+            None
+        } else {
+            let readiness_channel = channel(db_url, POLL_INTERVAL_SECS, MAX_BLOCK_AGE, None);
+            Some(watchdog::start(
+                readiness_channel,
+                heartbeat.clone(),
+                config.max_write_time,
+                config.readiness_failure_threshold,
+            ))
+        };
+        let batcher_state = Arc::new(BatcherState::default());
+        start_metrics_server(
+            config.metrics_port,
+            last_processed_height,
+            readiness_channel,
+            debug_routes(&config, &batcher_state),
+        );
+
+        let starting_height = resume_height(last_processed_height, config.blockchain_updates.starting_height);
+        log::info!("Starting to fetch updates from height {}", starting_height);
+
+        let rx = updates_source.stream(starting_height).await?;
+        let rx = start_batcher(rx, &config, batcher_state);
+        match storage {
+            Some(storage) => {
+                let sink = make_sink(&config)?;
+                write_loop(
+                    prepare::start(rx, config.warn_tx_size_bytes),
+                    storage,
+                    starting_height,
+                    config.write_retry,
+                    heartbeat,
+                    sink,
+                )
+                .await
+            }
+            None => dry_run_loop(rx, starting_height).await,
+        }
+    }
+
+    /// Connect to the database and roll back the stored data as configured,
+    /// returns the storage (none in the dry-run mode, which never touches the database) and the last stored height.
+    async fn init_db(
+        dry_run: bool,
+        db_config: PostgresConfig,
+        db_connect_retry: RetryParams,
+        start_params: StartParams,
+    ) -> anyhow::Result<(Option<PostgresStorage>, Option<u32>)> {
+        if dry_run {
+            return Ok((None, None));
+        }
+        log::info!("Connecting to database: {:?}", db_config);
+        let db_url = db_config.database_url();
+        let conn = connect_db(&db_url, &db_connect_retry).await?;
+        let storage = PostgresStorage::new(conn, &db_url);
+        let last_height = storage
+            .transaction(move |repo| init_stored_height(repo, &start_params))
+            .await?;
+        Ok((Some(storage), last_height))
+    }
+
+    /// Same as `run`, but with the SQLite storage, for local development only.
+    /// There is no readiness check: it is based on the Postgres database.
+    #[cfg(feature = "sqlite")]
+    async fn run_sqlite(config: ConsumerConfig, start_params: StartParams, path: &str) -> anyhow::Result<()> {
+        log::warn!("Using SQLite database {} (for local development only)", path);
+        let storage = SqliteStorage::connect(path)?;
+        let last_processed_height = storage
+            .transaction(move |repo| init_stored_height(repo, &start_params))
+            .await?;
+        let updates_source = connect_updates(config.blockchain_updates.clone()).await?;
+
+        let batcher_state = Arc::new(BatcherState::default());
+        start_metrics_server(
+            config.metrics_port,
+            last_processed_height,
+            None,
+            debug_routes(&config, &batcher_state),
+        );
+
+        let starting_height = resume_height(last_processed_height, config.blockchain_updates.starting_height);
+        log::info!("Starting to fetch updates from height {}", starting_height);
+
+        let rx = updates_source.stream(starting_height).await?;
+        let rx = start_batcher(rx, &config, batcher_state);
+        let sink = make_sink(&config)?;
+        write_loop(
+            prepare::start(rx, config.warn_tx_size_bytes),
+            storage,
+            starting_height,
+            config.write_retry,
+            Arc::new(Heartbeat::default()),
+            sink,
+        )
+        .await
+    }
+
+    /// Rewrite the stored transactions of the height range instead of consuming, then exit.
+    async fn run_reprocess(config: ConsumerConfig, params: ReprocessParams) -> anyhow::Result<()> {
+        log::warn!(
+            "Reprocess mode: transactions at heights {}..={} are re-fetched and their JSON is rewritten",
+            params.from_height,
+            params.to_height
+        );
+        let updates_source = connect_updates(config.blockchain_updates.clone()).await?;
+
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = db_config::sqlite_database() {
+            let storage = SqliteStorage::connect(&path)?;
+            return reprocess::run(storage, updates_source, params, config.warn_tx_size_bytes).await;
+        }
+
+        log::info!("Connecting to database: {:?}", config.db);
+        let db_url = config.db.database_url();
+        let conn = connect_db(&db_url, &config.db_connect_retry).await?;
+        let storage = PostgresStorage::new(conn, &db_url);
+        reprocess::run(storage, updates_source, params, config.warn_tx_size_bytes).await
+    }
+
+    /// Delete the stored data from the start of the height range up and re-import the range
+    /// instead of consuming, then exit.
+    async fn run_replay(config: ConsumerConfig, params: ReplayParams) -> anyhow::Result<()> {
+        log::warn!(
+            "Replay mode: all data from height {} and above is deleted, heights {}..={} are re-imported",
+            params.from_height,
+            params.from_height,
+            params.to_height
+        );
+        let updates_source = connect_updates(config.blockchain_updates.clone()).await?;
+
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = db_config::sqlite_database() {
+            let storage = SqliteStorage::connect(&path)?;
+            return replay(storage, updates_source, params, config).await;
+        }
+
+        log::info!("Connecting to database: {:?}", config.db);
+        let db_url = config.db.database_url();
+        let conn = connect_db(&db_url, &config.db_connect_retry).await?;
+        let storage = PostgresStorage::new(conn, &db_url);
+        replay(storage, updates_source, params, config).await
+    }
+
+    async fn replay(
+        storage: impl Storage + Clone,
+        updates_source: BlockchainUpdates,
+        params: ReplayParams,
+        config: ConsumerConfig,
+    ) -> anyhow::Result<()> {
+        let ReplayParams { from_height, to_height } = params;
+        storage
+            .transaction(move |repo| {
+                let last_height = repo.last_height()?;
+                // Blocks are stored in order, so the range must continue the stored ones
+                if let Some(h) = last_height.filter(|&h| from_height > h + 1) {
+                    anyhow::bail!("Can't replay from height {}: last stored height is {}", from_height, h);
+                }
+                repo.rollback_to_height(from_height - 1)?;
+                log::info!(
+                    "Rolled back to height {} (last stored height was {:?})",
+                    from_height - 1,
+                    last_height
+                );
+                Ok(())
+            })
+            .await?;
+
+        let rx = updates_source.stream_range(from_height, to_height).await?;
+        let rx = start_batcher(rx, &config, Arc::new(BatcherState::default()));
+        write_loop(
+            prepare::start(rx, config.warn_tx_size_bytes),
+            storage.clone(),
+            from_height,
+            config.write_retry.clone(),
+            Arc::new(Heartbeat::default()),
+            make_sink(&config)?,
+        )
+        .await?;
+
+        // The stream is closed on errors as well, so make sure the whole range was imported
+        let last_height = storage.transaction(|repo| repo.last_height()).await?;
+        if last_height != Some(to_height) {
+            anyhow::bail!(
+                "Blockchain updates stream closed at height {:?}, before reaching height {}",
+                last_height,
+                to_height
+            );
+        }
+        log::info!("Replayed heights {}..={}", from_height, to_height);
+        Ok(())
+    }
+
+    /// Sink of the committed operations, if configured
+    fn make_sink(config: &ConsumerConfig) -> anyhow::Result<Option<Box<dyn Sink + Send + Sync>>> {
+        let kafka = match &config.kafka {
+            Some(kafka) => kafka,
+            None => return Ok(None),
+        };
+        #[cfg(feature = "kafka")]
+        {
+            log::info!(
+                "Publishing operations to Kafka topic {} at {}",
+                kafka.topic,
+                kafka.bootstrap_servers
+            );
+            Ok(Some(Box::new(KafkaSink::new(kafka)?)))
+        }
+        #[cfg(not(feature = "kafka"))]
+        {
+            anyhow::bail!(
+                "Kafka topic {} at {} is configured, but the consumer is built without the kafka feature",
+                kafka.topic,
+                kafka.bootstrap_servers
+            )
+        }
+    }
+
+    /// Where to start from, on consumer start
+    struct StartParams {
+        reset_on_start: bool,
+        force_starting_height: bool,
+        starting_height: u32,
+        rollback_depth: u32,
+    }
+
+    /// Roll back the stored data as configured, returns the height of the last stored block after that,
+    /// if anything is stored (or the configured starting height must be used anyway).
+    fn init_stored_height(repo: &mut impl Repo, params: &StartParams) -> anyhow::Result<Option<u32>> {
+        let last_height = repo.last_height()?;
+        log::info!("Last height stored in database is {:?}", last_height);
+        if params.reset_on_start {
+            repo.truncate_all()?;
+            log::warn!(
+                "Reset on start: deleted all stored data (up to height {:?}), syncing from the configured height {}",
+                last_height,
+                params.starting_height
+            );
+            // Nothing is stored anymore, so the configured starting height is used
+            return Ok(None);
+        }
+        if params.force_starting_height {
+            let height = params.starting_height.saturating_sub(1);
+            repo.rollback_to_height(height)?;
+            log::warn!(
+                "Forced rollback to height {} to start from the configured height {}",
+                height,
+                params.starting_height
+            );
+            // Pretend there is nothing stored, so that the configured starting height is used
+            return Ok(None);
+        }
+        let depth = params.rollback_depth;
+        let rollback_to_height = match last_height {
+            None => None,
+            Some(h) if depth == 0 => {
+                log::info!("Start rollback is disabled (START_ROLLBACK_DEPTH is 0)");
+                // The last height is not delivered again, so its microblocks may be outdated by now
+                if let Some(key_block_uid) = repo.key_block_uid(h)? {
+                    repo.rollback_to_block(key_block_uid)?;
+                    log::info!("Rolled back the microblocks of height {} to its key block", h);
+                }
+                None
+            }
+            Some(h) if depth > h => {
+                log::warn!(
+                    "Start rollback skipped: START_ROLLBACK_DEPTH {} exceeds the stored height {}",
+                    depth,
+                    h
+                );
+                None
+            }
+            Some(h) => Some(h - depth),
+        };
+        match rollback_to_height {
+            Some(height) => {
+                repo.rollback_to_height(height)?;
+                log::info!("Rolled back to height {} for safety", height);
+                Ok(Some(height))
+            }
+            None => Ok(last_height),
+        }
+    }
+
+    /// Height to subscribe from: the one after the last stored block, so that it is not delivered
+    /// (and inserted) again, or the configured one if nothing is stored.
+    ///
+    /// The microblocks of the last stored height are not delivered either: the ones received after
+    /// the consumer has stopped are never stored, hence `init_stored_height` rolling them back
+    /// to the key block (rather than keeping the ones that may have been replaced since) when
+    /// `START_ROLLBACK_DEPTH` is 0. A rollback depth of at least 1 re-imports the last height in full.
+    fn resume_height(stored_height: Option<u32>, starting_height: u32) -> u32 {
+        stored_height.map_or(starting_height, |height| height + 1)
+    }
+
+    async fn connect_updates(config: BlockchainUpdatesConfig) -> anyhow::Result<BlockchainUpdates> {
+        let url = config.blockchain_updates_url;
+        let max_message_size = config.grpc_max_message_size_mb as usize * 1024 * 1024;
+        let limits = ConvertLimits {
+            max_arg_depth: config.max_arg_nesting_depth,
+            max_args: config.max_args,
+            max_payments: config.max_payments,
+            reject_over_limit: config.reject_over_limit,
+            binary_encoding: config.binary_encoding,
+        };
+        let retry = RetryParams {
+            max_retries: config.grpc_connect_retries,
+            delay: Duration::from_millis(config.grpc_connect_retry_delay_ms),
+        };
+        // The node may be still starting up, so a failed connect is retried instead of failing the consumer
+        with_retries("connect to blockchain-updates", &retry, || {
+            log::info!("Connecting to blockchain-updates at {}", url);
+            BlockchainUpdates::connect(
+                url.clone(),
+                max_message_size,
+                config.grpc_compression,
+                limits,
+                config.quarantine_bad_tx,
+                config.skip_unconvertible_updates,
+            )
+        })
+        .await
+    }
+
+    /// Connect to Postgres, retrying while it is unreachable (e.g. in the middle of a failover)
+    async fn connect_db(db_url: &str, retry: &RetryParams) -> anyhow::Result<PgConnection> {
+        with_retries("connect to database", retry, || async {
+            Ok(PgConnection::establish(db_url)?)
+        })
+        .await
+    }
+
+    /// Call `f` until it succeeds, at most `retry.max_retries` more times, with exponential backoff
+    async fn with_retries<T, F, Fut>(what: &str, retry: &RetryParams, mut f: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut delay = retry.delay;
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(err) if attempt < retry.max_retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Failed to {} (retry {} of {} in {:?}): {}",
+                        what,
+                        attempt,
+                        retry.max_retries,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn start_batcher(
+        rx: mpsc::Receiver<BlockchainUpdate>,
+        config: &ConsumerConfig,
+        state: Arc<BatcherState>,
+    ) -> mpsc::Receiver<Vec<BlockchainUpdate>> {
+        if config.disable_batching {
+            log::warn!(
+                "Batching is disabled: updates are written one by one, short rollbacks are not handled in memory"
+            );
+            batcher::start_unbatched(rx)
+        } else {
+            batcher::start(rx, config.batching.clone(), state)
+        }
+    }
+
+    /// Port and routes of the debug endpoints, if enabled
+    fn debug_routes(config: &ConsumerConfig, state: &Arc<BatcherState>) -> Option<(u16, BoxedFilter<(Json,)>)> {
+        config.debug_port.map(|port| {
+            log::info!("Debug endpoints are served on port {}", port);
+            let routes = debug::routes(config.batching.clone(), config.disable_batching, state.clone());
+            (port, routes)
+        })
+    }
+
+    fn start_metrics_server(
+        metrics_port: u16,
+        last_processed_height: Option<u32>,
+        readiness_channel: Option<mpsc::UnboundedReceiver<Readiness>>,
+        debug: Option<(u16, BoxedFilter<(Json,)>)>,
+    ) {
         task::spawn(async move {
             if let Some(height) = last_processed_height {
                 HEIGHT.set(height as i64);
+                update_height_lag();
             }
-            MetricsWarpBuilder::new()
+            let mut metrics = MetricsWarpBuilder::new()
                 .with_metric(&*HEIGHT)
                 .with_metric(&*UPDATES_BATCH_SIZE)
                 .with_metric(&*UPDATES_BATCH_TIME)
                 .with_metric(&*DB_WRITE_TIME)
-                .with_metrics_port(metrics_port)
-                .with_readiness_channel(readiness_channel)
-                .run_async()
-                .await;
+                .with_metric(&*DB_COMMITS)
+                .with_metric(&*DB_ROLLBACKS)
+                .with_metric(&*CHAIN_HEIGHT)
+                .with_metric(&*HEIGHT_LAG)
+                .with_metric(&*HEIGHT_GAPS)
+                .with_metric(&*LAST_WRITE_TIMESTAMP)
+                .with_metric(&*OVERSIZED_TRANSACTIONS)
+                .with_metric(&*TRANSACTIONS_BY_TYPE)
+                .with_metric(&*BATCH_QUEUE_DEPTH)
+                .with_metric(&*BATCH_FLUSHES)
+                .with_metric(&*KAFKA_PUBLISH_ERRORS)
+                .with_metric(&*TRUNCATED_TRANSACTIONS)
+                .with_metric(&*ANOMALOUS_PROOFS)
+                .with_metric(&*CONVERSION_ERRORS)
+                .with_metrics_port(metrics_port);
+            if let Some(readiness_channel) = readiness_channel {
+                metrics = metrics.with_readiness_channel(readiness_channel);
+            }
+            match debug {
+                Some((port, routes)) => {
+                    metrics
+                        .with_main_routes(routes)
+                        .with_main_routes_port(port)
+                        .run_async()
+                        .await
+                }
+                None => metrics.run_async().await,
+            }
         });
+    }
 
-        let starting_height = last_processed_height.unwrap_or(config.blockchain_updates.starting_height);
-        log::info!("Starting to fetch updates from height {}", starting_height);
-
-        let rx = updates_source.stream(starting_height).await?;
-        let mut rx = batcher::start(rx, config.batching);
+    /// Write prepared batches one by one, in order.
+    /// Next batch is being prepared concurrently while the current one is written.
+    async fn write_loop(
+        mut rx: mpsc::Receiver<PreparedBatch>,
+        storage: impl Storage + Clone,
+        starting_height: u32,
+        retry: RetryParams,
+        heartbeat: Arc<Heartbeat>,
+        sink: Option<Box<dyn Sink + Send + Sync>>,
+    ) -> anyhow::Result<()> {
         let mut last_height = starting_height;
+        // Read from the database by the first batch, then kept from the previous ones
+        let mut stored_height = None;
         while let Some(updates) = rx.recv().await {
+            let updates = Arc::new(updates?);
             let count = updates.len();
             let start = Instant::now();
             log::debug!("Writing batch of {} updates", count);
-            let new_last_height = write_batch(updates, storage.clone()).await?;
-            last_height = new_last_height.unwrap_or(last_height);
+            heartbeat.write_started();
+            let new_stored_height =
+                write_batch_with_retries(updates.clone(), storage.clone(), stored_height, &retry).await?;
+            stored_height = Some(new_stored_height);
+            heartbeat.write_finished();
+            // Only what is committed is published
+            if let Some(sink) = &sink {
+                sink.publish(&updates).await;
+            }
+            last_height = new_stored_height.unwrap_or(last_height);
+            LAST_WRITE_TIMESTAMP.set(chrono::Utc::now().timestamp());
             let elapsed = start.elapsed();
             log::info!(
                 "Saved {} updates in {:?}, last height is {}",
@@ -111,31 +598,180 @@ mod consumer {
         Ok(())
     }
 
-    async fn write_batch(batch: Vec<BlockchainUpdate>, storage: impl Storage) -> anyhow::Result<Option<u32>> {
-        storage
-            .transaction(|repo| {
+    async fn dry_run_loop(mut rx: mpsc::Receiver<Vec<BlockchainUpdate>>, starting_height: u32) -> anyhow::Result<()> {
+        let mut last_height = starting_height;
+        while let Some(updates) = rx.recv().await {
+            BATCH_QUEUE_DEPTH.dec();
+            let count = updates.len();
+            log::debug!("Dry-run batch of {} updates", count);
+            let new_last_height = dry_run_batch(updates);
+            last_height = new_last_height.unwrap_or(last_height);
+            log::info!("Processed {} updates, last height is {}", count, last_height);
+        }
+        Ok(())
+    }
+
+    /// Log what `write_batch` would have written, without touching the database.
+    fn dry_run_batch(batch: Vec<BlockchainUpdate>) -> Option<u32> {
+        let mut last_height = None;
+        for update in batch {
+            match update {
+                BlockchainUpdate::Append(append) => {
+                    log::info!(
+                        "[dry-run] Append block {} at height {} with {} transactions",
+                        append.block_id,
+                        append.height,
+                        append.transactions.len()
+                    );
+                    for tx in append.transactions {
+                        log::debug!("[dry-run] Transaction {} from {}", tx.id, tx.sender);
+                    }
+                    for tx in append.quarantined {
+                        log::warn!("[dry-run] Quarantined transaction {}: {}", tx.id, tx.reason);
+                    }
+                    last_height = Some(append.height);
+                }
+                BlockchainUpdate::Rollback(rollback) => {
+                    log::info!("[dry-run] Rollback to block {}", rollback.block_id);
+                }
+            }
+        }
+        if let Some(height) = last_height {
+            HEIGHT.set(height as i64);
+            update_height_lag();
+        }
+        last_height
+    }
+
+    /// Write the batch, retrying it as a whole on a transient failure, on a new connection.
+    /// It is safe because the batch is written within a single database transaction.
+    async fn write_batch_with_retries(
+        batch: Arc<Vec<PreparedUpdate>>,
+        storage: impl Storage + Clone,
+        mut stored_height: Option<Option<u32>>,
+        retry: &RetryParams,
+    ) -> anyhow::Result<Option<u32>> {
+        let mut delay = retry.delay;
+        let mut attempt = 0;
+        loop {
+            match write_batch(batch.clone(), storage.clone(), stored_height).await {
+                Ok(stored_height) => return Ok(stored_height),
+                Err(err) if attempt < retry.max_retries && is_transient(&err) => {
+                    attempt += 1;
+                    // Read it again on the new connection, there is no telling what the failure left behind
+                    stored_height = None;
+                    log::warn!(
+                        "Failed to write batch (retry {} of {} in {:?}): {}",
+                        attempt,
+                        retry.max_retries,
+                        delay,
+                        err
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    // Failing to reconnect is not fatal: the retry fails on the old connection then
+                    if let Err(err) = storage.reconnect().await {
+                        log::warn!("Failed to reconnect to the database: {}", err);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Write the batch within a single database transaction, returns the height of the last stored block then.
+    /// `stored_height` is the one before the batch, as returned by the previous write, or `None` to read it.
+    async fn write_batch(
+        batch: Arc<Vec<PreparedUpdate>>,
+        storage: impl Storage,
+        stored_height: Option<Option<u32>>,
+    ) -> anyhow::Result<Option<u32>> {
+        let tx_counts = batch
+            .iter()
+            .flat_map(|update| match update {
+                PreparedUpdate::Append(block) => block.transactions.as_slice(),
+                PreparedUpdate::Rollback(_) => &[],
+            })
+            .map(|tx| tx.type_label)
+            .counts();
+        let stored_height = storage
+            .transaction(move |repo| {
                 let start = Instant::now();
                 let mut last_height = None;
-                for update in batch {
+                let mut stored_height = match stored_height {
+                    Some(height) => height,
+                    None => repo.last_height()?,
+                };
+                // Uids of the blocks inserted by this batch (and not rolled back since),
+                // so that a rollback to one of them doesn't need a lookup
+                let mut inserted_uids = HashMap::<&str, BlockUID>::new();
+                for update in batch.iter() {
                     match update {
-                        BlockchainUpdate::Append(append) => {
-                            let block_id = append.block_id;
-                            let block_height = append.height;
-                            let block_timestamp = append.timestamp.expect("block timestamp");
-                            let block_uid = repo.insert_block(&block_id, block_height, block_timestamp)?;
-                            for tx in append.transactions {
-                                let tx_id = tx.id.as_str();
-                                let tx_type = tx.tx_type as u8;
-                                let sender = tx.sender.as_str();
-                                let tx_body = serde_json::to_value(&tx)?;
-                                //log::trace!("tx_json = {}", tx_body.to_string());
-                                repo.insert_tx(tx_id, block_uid, sender, tx_type, tx_body)?;
+                        PreparedUpdate::Append(block) => {
+                            // A key block must be above everything stored, otherwise it replaces the stored blocks
+                            // as if there was a rollback (e.g. the node re-sent a height), never stored twice
+                            let replaced_height = stored_height.filter(|&h| !block.is_microblock && block.height <= h);
+                            if let Some(height) = replaced_height {
+                                log::warn!(
+                                    "Block {} at height {} without a rollback, while height {} is stored: \
+                                     rolling back to height {}",
+                                    block.block_id,
+                                    block.height,
+                                    height,
+                                    block.height - 1
+                                );
+                                repo.rollback_to_height(block.height - 1)?;
+                                inserted_uids.clear();
                             }
-                            last_height = Some(append.height);
+                            // Blocks without any operations are stored as well:
+                            // the height must advance and rollbacks to such blocks must be resolvable
+                            let block_uid =
+                                repo.insert_block(&block.block_id, block.height, block.timestamp, block.is_microblock)?;
+                            inserted_uids.insert(&block.block_id, block_uid);
+                            for tx in &block.transactions {
+                                let new_tx = NewTransaction {
+                                    id: &tx.id,
+                                    block_uid,
+                                    sender: &tx.sender,
+                                    tx_type: tx.tx_type as i16,
+                                    op_type: tx.op_type,
+                                    fee_amount: tx.fee_amount,
+                                    fee_asset: &tx.fee_asset,
+                                    operation: &tx.body,
+                                    height: block.height as i32,
+                                };
+                                repo.insert_tx(&new_tx)?;
+                            }
+                            for tx in &block.quarantined {
+                                let new_tx = NewQuarantinedTransaction {
+                                    id: &tx.id,
+                                    block_uid,
+                                    raw: &tx.raw,
+                                    reason: &tx.reason,
+                                };
+                                repo.insert_quarantined(&new_tx)?;
+                            }
+                            last_height = Some(block.height);
+                            stored_height = Some(block.height);
                         }
-                        BlockchainUpdate::Rollback(rollback) => {
-                            let block_uid = repo.block_uid(&rollback.block_id)?;
-                            repo.rollback_to_block(block_uid)?;
+                        PreparedUpdate::Rollback(rollback) => {
+                            let block_uid = match inserted_uids.get(rollback.block_id.as_str()) {
+                                Some(&block_uid) => Some(block_uid),
+                                None => repo.block_uid(&rollback.block_id)?,
+                            };
+                            match block_uid {
+                                Some(block_uid) => {
+                                    stored_height = repo.rollback_to_block(block_uid)?;
+                                    inserted_uids.retain(|_, &mut uid| uid <= block_uid);
+                                }
+                                None => {
+                                    // Most likely a rollback deeper than anything we have stored
+                                    log::warn!(
+                                        "Rollback to unknown block {} ignored: block not found in the database",
+                                        rollback.block_id
+                                    );
+                                }
+                            }
                         }
                     }
                 }
@@ -144,9 +780,857 @@ mod consumer {
                 DB_WRITE_TIME.set(elapsed_ms);
                 if let Some(height) = last_height {
                     HEIGHT.set(height as i64);
+                    update_height_lag();
                 }
-                Ok(last_height)
+                Ok(stored_height)
             })
+            .await;
+        // Any failure means the database transaction was rolled back (or never started)
+        match &stored_height {
+            Ok(_) => DB_COMMITS.inc(),
+            Err(_) => DB_ROLLBACKS.inc(),
+        }
+        let stored_height = stored_height?;
+        // Only count transactions that were actually committed
+        for (type_label, count) in tx_counts {
+            TRANSACTIONS_BY_TYPE
+                .with_label_values(&[type_label])
+                .inc_by(count as u64);
+        }
+        Ok(stored_height)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use diesel::connection::SimpleConnection;
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        use super::*;
+        use crate::common::database::types::OperationType;
+        use crate::consumer::batcher::BatchingParams;
+        use crate::consumer::model::Transaction;
+        use crate::consumer::prepare::{PreparedBlock, PreparedRollback, PreparedTransaction};
+        use crate::consumer::storage::MemoryStorage;
+        use crate::consumer::updates::test_server::{self, block_update, lease_cancel};
+        use crate::consumer::updates::{AppendBlock, QuarantinedTransaction, Rollback};
+        use waves_protobuf_schemas::waves::events::BlockchainUpdated;
+
+        fn block(id: &str, height: u32, tx_ids: &[&str]) -> PreparedUpdate {
+            let transactions = tx_ids.iter().map(|&id| PreparedTransaction {
+                id: id.to_owned(),
+                sender: "3PSender".to_owned(),
+                tx_type: 16,
+                op_type: OperationType::InvokeScript,
+                fee_amount: 500000,
+                fee_asset: "WAVES".to_owned(),
+                body: serde_json::json!({ "id": id }),
+                type_label: "invoke_script",
+            });
+            PreparedUpdate::Append(PreparedBlock {
+                block_id: id.to_owned(),
+                height,
+                timestamp: height as u64 * 60000,
+                is_microblock: false,
+                transactions: transactions.collect(),
+                quarantined: Vec::new(),
+            })
+        }
+
+        fn microblock(id: &str, height: u32, tx_ids: &[&str]) -> PreparedUpdate {
+            match block(id, height, tx_ids) {
+                PreparedUpdate::Append(block) => PreparedUpdate::Append(PreparedBlock {
+                    is_microblock: true,
+                    ..block
+                }),
+                PreparedUpdate::Rollback(_) => unreachable!(),
+            }
+        }
+
+        fn rollback(block_id: &str) -> PreparedUpdate {
+            PreparedUpdate::Rollback(PreparedRollback {
+                block_id: block_id.to_owned(),
+            })
+        }
+
+        /// Held by the tests writing transactions, so that the counted ones can be checked
+        static WRITE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+        async fn write(storage: &MemoryStorage, batch: Vec<PreparedUpdate>) -> anyhow::Result<Option<u32>> {
+            let _lock = WRITE_LOCK.lock().await;
+            write_batch(Arc::new(batch), storage.clone(), None).await
+        }
+
+        fn block_ids(storage: &MemoryStorage) -> Vec<String> {
+            storage.data().blocks.into_iter().map(|block| block.id).collect()
+        }
+
+        fn tx_ids(storage: &MemoryStorage) -> Vec<String> {
+            storage.data().transactions.into_iter().map(|tx| tx.id).collect()
+        }
+
+        #[tokio::test]
+        async fn block_without_operations_is_stored() {
+            let storage = MemoryStorage::default();
+            let _lock = WRITE_LOCK.lock().await;
+            let batch = vec![block("b1", 1, &["t1"]), block("b2", 2, &[]), block("b3", 3, &[])];
+            let last_height = write_batch(Arc::new(batch), storage.clone(), None).await.unwrap();
+            assert_eq!(last_height, Some(3));
+            assert_eq!(HEIGHT.get(), 3);
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3"]);
+            assert_eq!(tx_ids(&storage), ["t1"]);
+        }
+
+        #[tokio::test]
+        async fn quarantined_transactions_are_stored_with_the_block() {
+            let storage = MemoryStorage::default();
+            let mut update = block("b1", 1, &["t1", "t3"]);
+            if let PreparedUpdate::Append(block) = &mut update {
+                block.quarantined.push(QuarantinedTransaction {
+                    id: "t2".to_owned(),
+                    raw: vec![1, 2, 3],
+                    reason: "transaction timestamp is zero".to_owned(),
+                });
+            }
+            write(&storage, vec![update]).await.unwrap();
+            assert_eq!(tx_ids(&storage), ["t1", "t3"]);
+            assert_eq!(storage.data().quarantined, ["t2"]);
+        }
+
+        #[tokio::test]
+        async fn lower_height_append_replaces_stored_blocks() {
+            let storage = MemoryStorage::default();
+            write(
+                &storage,
+                vec![
+                    block("b1", 1, &["t1"]),
+                    block("b2", 2, &["t2"]),
+                    block("b3", 3, &["t3"]),
+                ],
+            )
             .await
+            .unwrap();
+
+            // Height 2 again, without a rollback
+            let last_height = write(&storage, vec![block("b2'", 2, &["t4"])]).await.unwrap();
+            assert_eq!(last_height, Some(2));
+            assert_eq!(block_ids(&storage), ["b1", "b2'"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t4"]);
+
+            // Within a batch as well, a microblock on top is fine
+            let batch = vec![
+                block("b3", 3, &["t5"]),
+                microblock("m3", 3, &["t6"]),
+                block("b3'", 3, &["t7"]),
+            ];
+            write(&storage, batch).await.unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "b2'", "b3'"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t4", "t7"]);
+            let heights = storage.data().blocks.into_iter().map(|block| block.height);
+            assert_eq!(heights.collect::<Vec<_>>(), [1, 2, 3]);
+        }
+
+        #[tokio::test]
+        async fn rollback_to_unknown_block_is_ignored() {
+            let storage = MemoryStorage::default();
+            write(&storage, vec![block("b1", 1, &["t1"]), block("b2", 2, &["t2"])])
+                .await
+                .unwrap();
+
+            let last_height = write(&storage, vec![rollback("unknown"), block("b3", 3, &["t3"])])
+                .await
+                .unwrap();
+            assert_eq!(last_height, Some(3));
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t2", "t3"]);
+        }
+
+        #[tokio::test]
+        async fn rollback_to_block_of_previous_batch() {
+            let storage = MemoryStorage::default();
+            write(&storage, vec![block("b1", 1, &["t1"]), microblock("m1", 1, &["t2"])])
+                .await
+                .unwrap();
+
+            write(&storage, vec![rollback("b1"), microblock("m2", 1, &["t3"])])
+                .await
+                .unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "m2"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t3"]);
+        }
+
+        #[tokio::test]
+        async fn rollback_to_block_of_same_batch_is_not_looked_up() {
+            let storage = MemoryStorage::default();
+            let batch = vec![
+                block("b1", 1, &["t1"]),
+                microblock("m1", 1, &["t2"]),
+                microblock("m2", 1, &["t3"]),
+                rollback("m1"),
+                microblock("m3", 1, &["t4"]),
+                rollback("b1"),
+                microblock("m4", 1, &["t5"]),
+            ];
+            write(&storage, batch).await.unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "m4"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t5"]);
+            assert_eq!(storage.data().block_lookups, 0);
+
+            // Blocks rolled back are forgotten, so are the ones of the previous batches
+            write(
+                &storage,
+                vec![rollback("m3"), rollback("b1"), microblock("m5", 1, &["t6"])],
+            )
+            .await
+            .unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "m5"]);
+            assert_eq!(storage.data().block_lookups, 2);
+        }
+
+        #[tokio::test]
+        async fn stored_height_is_not_read_again() {
+            let storage = MemoryStorage::default();
+            let _lock = WRITE_LOCK.lock().await;
+            let batch = vec![
+                block("b1", 1, &["t1"]),
+                microblock("m1", 1, &["t2"]),
+                block("b2", 2, &["t3"]),
+                rollback("m1"),
+                block("b2'", 2, &["t4"]),
+            ];
+            let stored_height = write_batch(Arc::new(batch), storage.clone(), Some(None)).await.unwrap();
+            assert_eq!(stored_height, Some(2));
+            assert_eq!(block_ids(&storage), ["b1", "m1", "b2'"]);
+
+            // The height of the block of a previous batch comes with the rollback as well
+            let batch = vec![rollback("b1"), block("b2''", 2, &["t5"]), rollback("b1")];
+            let stored_height = write_batch(Arc::new(batch), storage.clone(), Some(stored_height))
+                .await
+                .unwrap();
+            assert_eq!(stored_height, Some(1));
+            assert_eq!(block_ids(&storage), ["b1"]);
+            assert_eq!(tx_ids(&storage), ["t1"]);
+            assert_eq!(storage.data().height_lookups, 0);
+
+            // Unless it is not known
+            write_batch(Arc::new(vec![block("b2", 2, &["t6"])]), storage.clone(), None)
+                .await
+                .unwrap();
+            assert_eq!(storage.data().height_lookups, 1);
+        }
+
+        fn append(id: &str, height: u32, tx_ids: &[&str]) -> BlockchainUpdate {
+            let transactions = tx_ids.iter().map(|&id| Transaction::for_test(id, height));
+            BlockchainUpdate::Append(AppendBlock::for_test(id, height, transactions.collect()))
+        }
+
+        #[tokio::test]
+        async fn prepared_batches_are_written_in_order() {
+            let (tx, rx) = mpsc::channel(10);
+            let batches = vec![
+                vec![append("b1", 1, &["t1"]), append("b2", 2, &["t2", "t3"])],
+                vec![append("b3", 3, &["t4"])],
+                // Rollback after an append, to a block of the previous batch
+                vec![
+                    BlockchainUpdate::Rollback(Rollback {
+                        block_id: "b2".to_owned(),
+                    }),
+                    append("b3'", 3, &["t5"]),
+                ],
+                vec![append("b4", 4, &["t6"])],
+            ];
+            for batch in batches {
+                tx.send(batch).await.unwrap();
+            }
+            drop(tx);
+
+            let storage = MemoryStorage::default();
+            let retry = RetryParams {
+                max_retries: 0,
+                delay: Duration::ZERO,
+            };
+            let heartbeat = Arc::new(Heartbeat::default());
+            let _lock = WRITE_LOCK.lock().await;
+            write_loop(prepare::start(rx, None), storage.clone(), 1, retry, heartbeat, None)
+                .await
+                .unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3'", "b4"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t2", "t3", "t5", "t6"]);
+            let heights = storage.data().blocks.into_iter().map(|block| block.height);
+            assert_eq!(heights.collect::<Vec<_>>(), [1, 2, 3, 4]);
+        }
+
+        #[tokio::test]
+        async fn last_write_timestamp_is_set_after_batch() {
+            let (tx, rx) = mpsc::channel(10);
+            tx.send(vec![append("b1", 1, &["t1"])]).await.unwrap();
+            drop(tx);
+
+            let retry = RetryParams {
+                max_retries: 0,
+                delay: Duration::ZERO,
+            };
+            let heartbeat = Arc::new(Heartbeat::default());
+            let _lock = WRITE_LOCK.lock().await;
+            LAST_WRITE_TIMESTAMP.set(0);
+            let before = chrono::Utc::now().timestamp();
+            write_loop(
+                prepare::start(rx, None),
+                MemoryStorage::default(),
+                1,
+                retry,
+                heartbeat,
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(LAST_WRITE_TIMESTAMP.get() >= before);
+            assert!(LAST_WRITE_TIMESTAMP.get() <= chrono::Utc::now().timestamp());
+        }
+
+        /// Stands in for the Kafka producer, records the published (key, payload) messages
+        #[derive(Clone, Default)]
+        struct MockSink(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+        #[async_trait::async_trait]
+        impl Sink for MockSink {
+            async fn publish(&self, batch: &[PreparedUpdate]) {
+                let mut messages = self.0.lock().unwrap();
+                for update in batch {
+                    if let PreparedUpdate::Append(block) = update {
+                        let transactions = block.transactions.iter();
+                        messages.extend(transactions.map(|tx| (tx.sender.clone(), tx.body.to_string())));
+                    }
+                }
+            }
+        }
+
+        #[tokio::test]
+        async fn committed_operations_are_published() {
+            let storage = MemoryStorage::default();
+            let sink = MockSink::default();
+            let retry = RetryParams {
+                max_retries: 0,
+                delay: Duration::ZERO,
+            };
+            let run = |batches: Vec<Vec<BlockchainUpdate>>| {
+                let (tx, rx) = mpsc::channel(10);
+                for batch in batches {
+                    tx.try_send(batch).unwrap();
+                }
+                let heartbeat = Arc::new(Heartbeat::default());
+                let sink: Box<dyn Sink + Send + Sync> = Box::new(sink.clone());
+                write_loop(
+                    prepare::start(rx, None),
+                    storage.clone(),
+                    1,
+                    retry.clone(),
+                    heartbeat,
+                    Some(sink),
+                )
+            };
+            let _lock = WRITE_LOCK.lock().await;
+
+            let batches = vec![vec![append("b1", 1, &["t1", "t2"])], vec![append("b2", 2, &["t3"])]];
+            run(batches).await.unwrap();
+            let messages = sink.0.lock().unwrap().clone();
+            assert_eq!(messages.len(), 3);
+            assert_eq!(messages[0].0, "3PSender");
+            let ids = messages.iter().map(|(_, payload)| {
+                let operation: serde_json::Value = serde_json::from_str(payload).unwrap();
+                operation["id"].as_str().unwrap().to_owned()
+            });
+            assert_eq!(ids.collect::<Vec<_>>(), tx_ids(&storage));
+
+            // Nothing committed, nothing published
+            storage.fail_inserts([database_error(DatabaseErrorKind::UniqueViolation)]);
+            assert!(run(vec![vec![append("b3", 3, &["t4"])]]).await.is_err());
+            assert_eq!(sink.0.lock().unwrap().len(), 3);
+        }
+
+        #[tokio::test]
+        async fn queue_depth_rises_while_the_writer_is_stalled() {
+            let (tx, rx) = mpsc::channel(10);
+            for height in 1..=6 {
+                tx.send(append(&format!("b{}", height), height, &[])).await.unwrap();
+            }
+            drop(tx);
+
+            let _lock = WRITE_LOCK.lock().await;
+            let before = BATCH_QUEUE_DEPTH.get();
+            let params = BatchingParams {
+                queue_capacity: 3,
+                ..BatchingParams::default()
+            };
+            let output = batcher::start(rx, params, Arc::default());
+            // The writer is not started yet, the batches pile up in the queue
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(BATCH_QUEUE_DEPTH.get() - before >= 3);
+
+            let storage = MemoryStorage::default();
+            let retry = RetryParams {
+                max_retries: 0,
+                delay: Duration::ZERO,
+            };
+            let heartbeat = Arc::new(Heartbeat::default());
+            write_loop(prepare::start(output, None), storage.clone(), 1, retry, heartbeat, None)
+                .await
+                .unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4", "b5", "b6"]);
+        }
+
+        /// Block with a transaction of every given type
+        fn typed_block(id: &str, height: u32, types: &[&'static str]) -> PreparedUpdate {
+            let tx_ids = (0..types.len()).map(|i| format!("{}-{}", id, i)).collect_vec();
+            let mut update = block(id, height, &tx_ids.iter().map(String::as_str).collect_vec());
+            if let PreparedUpdate::Append(block) = &mut update {
+                for (tx, &type_label) in block.transactions.iter_mut().zip(types) {
+                    tx.type_label = type_label;
+                }
+            }
+            update
+        }
+
+        #[tokio::test]
+        async fn transactions_counted_by_type() {
+            let labels = [
+                "invoke_script",
+                "ethereum",
+                "lease",
+                "lease_cancel",
+                "data_transaction",
+                "exchange",
+            ];
+            let storage = MemoryStorage::default();
+            let _lock = WRITE_LOCK.lock().await;
+            let counts = || labels.map(|label| TRANSACTIONS_BY_TYPE.with_label_values(&[label]).get());
+            let before = counts();
+
+            let batch = vec![
+                typed_block("b1", 1, &["invoke_script", "ethereum", "invoke_script"]),
+                typed_block("b2", 2, &["lease", "lease_cancel", "exchange"]),
+                typed_block("b3", 3, &["data_transaction", "ethereum", "invoke_script"]),
+            ];
+            write_batch(Arc::new(batch), storage.clone(), None).await.unwrap();
+            // Not committed, so not counted
+            storage.fail_inserts(vec![anyhow::anyhow!("insert failed")]);
+            let failed = write_batch(Arc::new(vec![typed_block("b4", 4, &["lease"])]), storage.clone(), None).await;
+            assert!(failed.is_err());
+
+            let after = counts();
+            let deltas = labels
+                .iter()
+                .zip(after.iter().zip(before))
+                .map(|(label, (after, before))| (*label, after - before));
+            assert_eq!(
+                deltas.collect::<Vec<_>>(),
+                [
+                    ("invoke_script", 3),
+                    ("ethereum", 2),
+                    ("lease", 1),
+                    ("lease_cancel", 1),
+                    ("data_transaction", 1),
+                    ("exchange", 1),
+                ]
+            );
+        }
+
+        fn database_error(kind: DatabaseErrorKind) -> anyhow::Error {
+            DieselError::DatabaseError(kind, Box::new("database error".to_owned())).into()
+        }
+
+        async fn write_with_retries(storage: &MemoryStorage, max_retries: u32) -> anyhow::Result<Option<u32>> {
+            let retry = RetryParams {
+                max_retries,
+                delay: Duration::ZERO,
+            };
+            let batch = vec![block("b1", 1, &["t1"]), block("b2", 2, &["t2"])];
+            let _lock = WRITE_LOCK.lock().await;
+            write_batch_with_retries(Arc::new(batch), storage.clone(), Some(None), &retry).await
+        }
+
+        #[tokio::test]
+        async fn failed_write_is_retried_on_new_connection() {
+            let storage = MemoryStorage::default();
+            storage.fail_inserts([
+                database_error(DatabaseErrorKind::ClosedConnection),
+                database_error(DatabaseErrorKind::SerializationFailure),
+            ]);
+
+            let last_height = write_with_retries(&storage, 3).await.unwrap();
+            assert_eq!(last_height, Some(2));
+            assert_eq!(storage.transaction_count(), 3);
+            assert_eq!(storage.reconnect_count(), 2);
+            // Known before the first attempt, read again on retry
+            assert_eq!(storage.data().height_lookups, 1);
+            // The failed attempts left nothing behind
+            assert_eq!(block_ids(&storage), ["b1", "b2"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t2"]);
+        }
+
+        #[tokio::test]
+        async fn retries_are_limited() {
+            let storage = MemoryStorage::default();
+            storage.fail_inserts((0..3).map(|_| database_error(DatabaseErrorKind::ClosedConnection)));
+
+            assert!(write_with_retries(&storage, 2).await.is_err());
+            assert_eq!(storage.transaction_count(), 3);
+            assert!(block_ids(&storage).is_empty());
+        }
+
+        #[tokio::test]
+        async fn constraint_violation_is_not_retried() {
+            let storage = MemoryStorage::default();
+            storage.fail_inserts([database_error(DatabaseErrorKind::UniqueViolation)]);
+
+            assert!(write_with_retries(&storage, 3).await.is_err());
+            assert_eq!(storage.transaction_count(), 1);
+            assert_eq!(storage.reconnect_count(), 0);
+            assert!(block_ids(&storage).is_empty());
+        }
+
+        /// Result of `with_retries` over a call failing `failures` times, with the number of calls
+        async fn retried(failures: usize, max_retries: u32) -> (anyhow::Result<&'static str>, usize) {
+            let retry = RetryParams {
+                max_retries,
+                delay: Duration::from_secs(1),
+            };
+            let mut calls = 0;
+            let res = with_retries("connect", &retry, || {
+                calls += 1;
+                let res = if calls > failures {
+                    Ok("connected")
+                } else {
+                    Err(anyhow::anyhow!("connection refused"))
+                };
+                async move { res }
+            })
+            .await;
+            (res, calls)
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn connect_is_retried_with_backoff() {
+            let started = tokio::time::Instant::now();
+            let (res, calls) = retried(2, 5).await;
+            assert_eq!(res.unwrap(), "connected");
+            assert_eq!(calls, 3);
+            // 1s, then 2s
+            assert_eq!(started.elapsed(), Duration::from_secs(3));
+
+            let (res, calls) = retried(6, 5).await;
+            assert!(res.is_err());
+            assert_eq!(calls, 6);
+        }
+
+        /// Proxy to the Postgres of `TEST_DATABASE_URL` dropping the first connection,
+        /// returns the URL to connect through it
+        async fn flaky_database() -> Option<String> {
+            let url = match std::env::var("TEST_DATABASE_URL") {
+                Ok(url) => url,
+                Err(_) => {
+                    eprintln!("TEST_DATABASE_URL is not set, skipping the database test");
+                    return None;
+                }
+            };
+            let (credentials, address) = url.rsplit_once('@').expect("TEST_DATABASE_URL without user");
+            let (server, database) = address.split_once('/').unwrap_or((address, ""));
+            let server = server.to_owned();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_url = format!("{}@{}/{}", credentials, listener.local_addr().unwrap(), database);
+            tokio::spawn(async move {
+                // Not ready yet
+                drop(listener.accept().await);
+                while let Ok((mut client, _)) = listener.accept().await {
+                    let mut server = tokio::net::TcpStream::connect(&server).await.unwrap();
+                    tokio::spawn(async move { tokio::io::copy_bidirectional(&mut client, &mut server).await });
+                }
+            });
+            Some(proxy_url)
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn database_connect_is_retried() {
+            let Some(url) = flaky_database().await else {
+                return;
+            };
+            let retry = |max_retries| RetryParams {
+                max_retries,
+                delay: Duration::from_millis(10),
+            };
+            let mut conn = connect_db(&url, &retry(1)).await.unwrap();
+            conn.batch_execute("SELECT 1").unwrap();
+
+            // Fail fast without the retries
+            let Some(url) = flaky_database().await else {
+                return;
+            };
+            assert!(connect_db(&url, &retry(0)).await.is_err());
+        }
+
+        fn replay_config(blockchain_updates_url: String) -> ConsumerConfig {
+            let blockchain_updates = serde_json::json!({ "blockchain_updates_url": blockchain_updates_url });
+            let no_retries = RetryParams {
+                max_retries: 0,
+                delay: Duration::ZERO,
+            };
+            ConsumerConfig {
+                blockchain_updates: serde_json::from_value(blockchain_updates).unwrap(),
+                db: PostgresConfig::default(),
+                batching: BatchingParams {
+                    max_updates: Some(10),
+                    queue_capacity: 1,
+                    ..BatchingParams::default()
+                },
+                disable_batching: false,
+                metrics_port: 0,
+                metrics_prefix: String::new(),
+                metrics_labels: Default::default(),
+                dry_run: false,
+                write_retry: no_retries.clone(),
+                db_connect_retry: no_retries,
+                warn_tx_size_bytes: None,
+                max_write_time: Duration::from_secs(60),
+                readiness_failure_threshold: 1,
+                kafka: None,
+                reprocess: None,
+                replay: None,
+                debug_port: None,
+            }
+        }
+
+        async fn run_replay(
+            storage: &MemoryStorage,
+            updates: Vec<BlockchainUpdated>,
+            from_height: u32,
+            to_height: u32,
+        ) -> anyhow::Result<()> {
+            let config = replay_config(test_server::start(updates, None));
+            let updates_source = connect_updates(config.blockchain_updates.clone()).await?;
+            let params = ReplayParams { from_height, to_height };
+            replay(storage.clone(), updates_source, params, config).await
+        }
+
+        #[tokio::test]
+        async fn replay_reimports_range() {
+            let storage = MemoryStorage::default();
+            let blocks = (1..=5).map(|h| block(&format!("b{}", h), h, &[&format!("t{}", h)]));
+            write(&storage, blocks.collect()).await.unwrap();
+
+            let updates = vec![block_update(3, vec![lease_cancel()]), block_update(4, vec![])];
+            run_replay(&storage, updates, 3, 4).await.unwrap();
+
+            // Everything from the start of the range up is replaced, the test server's block ids are the heights
+            let block_id = |height: u8| bs58::encode([height; 64]).into_string();
+            assert_eq!(
+                block_ids(&storage),
+                ["b1".to_owned(), "b2".to_owned(), block_id(3), block_id(4)]
+            );
+            let tx_id = bs58::encode([0; 32]).into_string();
+            assert_eq!(tx_ids(&storage), ["t1".to_owned(), "t2".to_owned(), tx_id]);
+            let transactions = storage.data().transactions;
+            assert_eq!(transactions[2].operation["type"], "lease_cancel");
+        }
+
+        #[tokio::test]
+        async fn replay_must_continue_stored_blocks() {
+            let storage = storage_with_blocks(2).await;
+            let err = run_replay(&storage, vec![block_update(5, vec![])], 5, 5)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("last stored height is 2"), "{}", err);
+            assert_eq!(block_ids(&storage), ["b1", "b2"]);
+        }
+
+        #[tokio::test]
+        async fn replay_fails_on_incomplete_range() {
+            let storage = storage_with_blocks(2).await;
+            let err = run_replay(&storage, vec![block_update(3, vec![])], 3, 4)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("closed at height Some(3)"), "{}", err);
+        }
+
+        #[tokio::test]
+        async fn commits_and_rollbacks_are_counted() {
+            let retry = RetryParams {
+                max_retries: 1,
+                delay: Duration::ZERO,
+            };
+            let batch = || Arc::new(vec![block("b1", 1, &["t1"])]);
+            let counts = || (DB_COMMITS.get(), DB_ROLLBACKS.get());
+            // The other tests write under the lock too
+            let _lock = WRITE_LOCK.lock().await;
+
+            // Failed once, then committed on retry
+            let storage = MemoryStorage::default();
+            storage.fail_inserts([database_error(DatabaseErrorKind::ClosedConnection)]);
+            let (commits, rollbacks) = counts();
+            write_batch_with_retries(batch(), storage, None, &retry).await.unwrap();
+            assert_eq!(counts(), (commits + 1, rollbacks + 1));
+
+            let storage = MemoryStorage::default();
+            storage.fail_inserts([database_error(DatabaseErrorKind::UniqueViolation)]);
+            let (commits, rollbacks) = counts();
+            assert!(write_batch_with_retries(batch(), storage, None, &retry).await.is_err());
+            assert_eq!(counts(), (commits, rollbacks + 1));
+        }
+
+        fn start_params() -> StartParams {
+            StartParams {
+                reset_on_start: false,
+                force_starting_height: false,
+                starting_height: 1,
+                rollback_depth: 1,
+            }
+        }
+
+        #[tokio::test]
+        async fn dry_run_does_not_connect_to_database() {
+            // Nothing listens there, and the retries would take hours
+            let db_config = PostgresConfig {
+                host: "unreachable.invalid".to_owned(),
+                port: 1,
+                ..PostgresConfig::default()
+            };
+            let retry = RetryParams {
+                max_retries: 10,
+                delay: Duration::from_secs(3600),
+            };
+            let init = init_db(true, db_config, retry, start_params());
+            let (storage, last_height) = tokio::time::timeout(Duration::from_secs(1), init)
+                .await
+                .expect("dry run must not connect")
+                .unwrap();
+            assert!(storage.is_none());
+            assert_eq!(last_height, None);
+        }
+
+        /// Storage with key blocks `b1`..`b<height>` at heights 1..=`height`
+        async fn storage_with_blocks(height: u32) -> MemoryStorage {
+            let storage = MemoryStorage::default();
+            let blocks = (1..=height).map(|h| block(&format!("b{}", h), h, &[]));
+            write(&storage, blocks.collect()).await.unwrap();
+            storage
+        }
+
+        async fn init(storage: &MemoryStorage, params: StartParams) -> Option<u32> {
+            storage
+                .transaction(move |repo| init_stored_height(repo, &params))
+                .await
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn forced_starting_height() {
+            let storage = storage_with_blocks(5).await;
+            let params = StartParams {
+                force_starting_height: true,
+                starting_height: 3,
+                ..start_params()
+            };
+            assert_eq!(init(&storage, params).await, None);
+            assert_eq!(block_ids(&storage), ["b1", "b2"]);
+        }
+
+        #[tokio::test]
+        async fn stored_height_wins_by_default() {
+            let storage = storage_with_blocks(5).await;
+            let params = StartParams {
+                starting_height: 3,
+                rollback_depth: 0,
+                ..start_params()
+            };
+            assert_eq!(init(&storage, params).await, Some(5));
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4", "b5"]);
+        }
+
+        #[tokio::test]
+        async fn start_rollback_depth() {
+            let with_depth = |rollback_depth| StartParams {
+                rollback_depth,
+                ..start_params()
+            };
+
+            let storage = storage_with_blocks(5).await;
+            assert_eq!(init(&storage, with_depth(0)).await, Some(5));
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4", "b5"]);
+
+            let storage = storage_with_blocks(5).await;
+            assert_eq!(init(&storage, with_depth(1)).await, Some(4));
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4"]);
+
+            // Deeper than the stored height, the rollback is skipped
+            let storage = storage_with_blocks(5).await;
+            assert_eq!(init(&storage, with_depth(6)).await, Some(5));
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4", "b5"]);
+
+            // Nothing stored, nothing to roll back
+            let storage = MemoryStorage::default();
+            assert_eq!(init(&storage, with_depth(1)).await, None);
+        }
+
+        #[tokio::test]
+        async fn reset_on_start() {
+            let storage = MemoryStorage::default();
+            let blocks = vec![block("b1", 1, &["t1"]), block("b2", 2, &["t2", "t3"])];
+            write(&storage, blocks).await.unwrap();
+            let params = StartParams {
+                reset_on_start: true,
+                // Wins over the forced starting height too
+                force_starting_height: true,
+                starting_height: 10,
+                ..start_params()
+            };
+            let stored_height = init(&storage, params).await;
+            assert_eq!(stored_height, None);
+            assert!(block_ids(&storage).is_empty());
+            assert!(tx_ids(&storage).is_empty());
+            // Synced again from the configured height
+            assert_eq!(resume_height(stored_height, 10), 10);
+        }
+
+        #[tokio::test]
+        async fn resume_without_rollback() {
+            let storage = storage_with_blocks(5).await;
+            let params = StartParams {
+                rollback_depth: 0,
+                ..start_params()
+            };
+            let stored_height = init(&storage, params).await;
+            assert_eq!(stored_height, Some(5));
+            // The stored block is not delivered again, so nothing is duplicated
+            let starting_height = resume_height(stored_height, 1);
+            assert_eq!(starting_height, 6);
+            write(&storage, vec![block("b6", starting_height, &["t6"])])
+                .await
+                .unwrap();
+            assert_eq!(block_ids(&storage), ["b1", "b2", "b3", "b4", "b5", "b6"]);
+
+            assert_eq!(resume_height(None, 1), 1);
+            assert_eq!(resume_height(Some(4), 1), 5);
+        }
+
+        #[tokio::test]
+        async fn resume_without_rollback_drops_microblocks_of_last_height() {
+            let storage = MemoryStorage::default();
+            let blocks = vec![
+                block("b1", 1, &["t1"]),
+                microblock("m1", 1, &["t2"]),
+                block("b2", 2, &["t3"]),
+                microblock("m2", 2, &["t4"]),
+                microblock("m3", 2, &["t5"]),
+            ];
+            write(&storage, blocks).await.unwrap();
+            let params = StartParams {
+                rollback_depth: 0,
+                ..start_params()
+            };
+            let stored_height = init(&storage, params).await;
+            assert_eq!(stored_height, Some(2));
+            // Only the last height is not delivered again
+            assert_eq!(block_ids(&storage), ["b1", "m1", "b2"]);
+            assert_eq!(tx_ids(&storage), ["t1", "t2", "t3"]);
+            assert_eq!(resume_height(stored_height, 1), 3);
+        }
     }
 }
@@ -3,9 +3,14 @@
 use serde::Serialize;
 use serde_repr::Serialize_repr;
 
+/// Version of the serialized `Transaction`, stored in every operation JSON.
+/// Bump it on any change of the model that changes the JSON.
+pub const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct Transaction {
+    pub schema_version: u32,
     pub id: String,
     #[serde(rename = "type")]
     pub op_type: OperationType,
@@ -18,24 +23,169 @@ pub struct Transaction {
     pub sender: String,
     pub sender_public_key: String,
     pub proofs: Vec<String>,
-    pub dapp: String,
-    pub payment: Vec<Amount>,
-    pub call: Call,
+    #[serde(flatten)]
+    pub data: OperationData,
+}
+
+impl Transaction {
+    /// Type of the transaction as reported in metrics
+    pub fn type_label(&self) -> &'static str {
+        match self.tx_type {
+            TransactionType::EthereumTransaction => "ethereum",
+            _ => self.op_type.as_str(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Transaction {
+    /// Lease cancel paid in Waves, for the tests
+    pub fn for_test(id: &str, height: u32) -> Self {
+        Transaction {
+            schema_version: SCHEMA_VERSION,
+            id: id.to_owned(),
+            op_type: OperationType::LeaseCancel,
+            tx_type: TransactionType::LeaseCancel,
+            height,
+            timestamp: "2020-09-13T12:26:40Z".to_owned(),
+            fee: Amount::new(100000, None),
+            sender: "3PSender".to_owned(),
+            sender_public_key: "PublicKey".to_owned(),
+            proofs: Vec::new(),
+            data: OperationData::LeaseCancel(LeaseCancel {
+                lease_id: "Lease".to_owned(),
+            }),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum OperationType {
     InvokeScript,
+    Lease,
+    LeaseCancel,
+    DataTransaction,
+    Exchange,
+}
+
+impl OperationType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OperationType::InvokeScript => "invoke_script",
+            OperationType::Lease => "lease",
+            OperationType::LeaseCancel => "lease_cancel",
+            OperationType::DataTransaction => "data_transaction",
+            OperationType::Exchange => "exchange",
+        }
+    }
+}
+
+impl From<OperationType> for crate::common::database::types::OperationType {
+    fn from(op_type: OperationType) -> Self {
+        match op_type {
+            OperationType::InvokeScript => Self::InvokeScript,
+            OperationType::Lease => Self::Lease,
+            OperationType::LeaseCancel => Self::LeaseCancel,
+            OperationType::DataTransaction => Self::DataTransaction,
+            OperationType::Exchange => Self::Exchange,
+        }
+    }
 }
 
 #[repr(u8)]
 #[derive(Copy, Clone, Serialize_repr, Debug)]
 pub enum TransactionType {
+    Exchange = 7,
+    Lease = 8,
+    LeaseCancel = 9,
+    DataTransaction = 12,
     InvokeScript = 16,
     EthereumTransaction = 18,
 }
 
+/// Operation-specific part of the transaction, its fields are inlined into the transaction
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum OperationData {
+    InvokeScript(InvokeScript),
+    Lease(Lease),
+    LeaseCancel(LeaseCancel),
+    DataTransaction(DataTransaction),
+    Exchange(Exchange),
+}
+
+#[derive(Serialize, Debug)]
+pub struct InvokeScript {
+    pub dapp: String,
+    pub payment: Vec<Amount>,
+    pub call: Call,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Lease {
+    pub recipient: String,
+    pub amount: Amount,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LeaseCancel {
+    pub lease_id: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DataTransaction {
+    pub data: Vec<DataEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Exchange {
+    pub amount: i64,
+    pub price: i64,
+    pub order1: Order,
+    pub order2: Order,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Order {
+    pub side: OrderSide,
+    pub asset_pair: AssetPair,
+    pub amount: i64,
+    pub price: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AssetPair {
+    pub amount_asset: String,
+    pub price_asset: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DataEntry {
+    pub key: String,
+    #[serde(flatten)]
+    pub value: DataValue,
+}
+
+/// Value of the data entry, `Delete` means that the entry is removed from the account storage
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "value")]
+#[serde(rename_all = "snake_case")]
+pub enum DataValue {
+    Integer(i64),
+    Boolean(bool),
+    Binary(String),
+    String(String),
+    Delete,
+}
+
 #[derive(Serialize, Debug)]
 pub struct Amount {
     #[serde(rename = "amount")]
@@ -46,7 +196,7 @@ pub struct Amount {
 }
 
 impl Amount {
-    const WAVES_ASSET_ID: &'static str = "WAVES";
+    pub const WAVES_ASSET_ID: &'static str = "WAVES";
 
     pub fn new(amount: i64, asset_id: Option<String>) -> Self {
         Amount {
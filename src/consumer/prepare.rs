@@ -0,0 +1,194 @@
+//! Preparation of the update batches for the database writer.
+//!
+//! Serializes the transactions of the next batch while the previous one
+//! is being committed, so that this CPU work overlaps with the database writes.
+//!
+//! Batches are passed on strictly in the order they were received,
+//! the writer is still responsible for committing them one by one.
+
+use std::io;
+
+use tokio::{sync::mpsc, task};
+
+use crate::common::database::types::OperationType;
+use crate::consumer::metrics::{BATCH_QUEUE_DEPTH, OVERSIZED_TRANSACTIONS};
+use crate::consumer::updates::{BlockchainUpdate, QuarantinedTransaction};
+
+pub enum PreparedUpdate {
+    Append(PreparedBlock),
+    Rollback(PreparedRollback),
+}
+
+pub struct PreparedBlock {
+    pub block_id: String,
+    pub height: u32,
+    pub timestamp: u64,
+    pub is_microblock: bool,
+    pub transactions: Vec<PreparedTransaction>,
+    pub quarantined: Vec<QuarantinedTransaction>,
+}
+
+pub struct PreparedTransaction {
+    pub id: String,
+    pub sender: String,
+    pub tx_type: u8,
+    pub op_type: OperationType,
+    pub fee_amount: i64,
+    pub fee_asset: String,
+    pub body: serde_json::Value,
+    pub type_label: &'static str,
+}
+
+pub struct PreparedRollback {
+    pub block_id: String,
+}
+
+pub type PreparedBatch = anyhow::Result<Vec<PreparedUpdate>>;
+
+/// Transactions with JSON bigger than `warn_tx_size` (in bytes) are reported, if set
+pub fn start(
+    mut input: mpsc::Receiver<Vec<BlockchainUpdate>>,
+    warn_tx_size: Option<usize>,
+) -> mpsc::Receiver<PreparedBatch> {
+    // Only one prepared batch is waiting for the writer, the rest are waiting in the batcher
+    let (tx, rx) = mpsc::channel::<PreparedBatch>(1);
+    task::spawn(async move {
+        while let Some(batch) = input.recv().await {
+            BATCH_QUEUE_DEPTH.dec();
+            let prepared = task::spawn_blocking(move || prepare_batch(batch, warn_tx_size))
+                .await
+                .expect("sync task panicked");
+            let failed = prepared.is_err();
+            if tx.send(prepared).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn prepare_batch(batch: Vec<BlockchainUpdate>, warn_tx_size: Option<usize>) -> PreparedBatch {
+    batch
+        .into_iter()
+        .map(|update| {
+            let prepared = match update {
+                BlockchainUpdate::Append(append) => {
+                    let transactions = append
+                        .transactions
+                        .iter()
+                        .map(|tx| {
+                            let body = serde_json::to_value(tx)?;
+                            if let Some(warn_tx_size) = warn_tx_size {
+                                let size = json_size(&body)?;
+                                if size > warn_tx_size {
+                                    OVERSIZED_TRANSACTIONS.inc();
+                                    log::warn!("Transaction {} is oversized: {} bytes of JSON", tx.id, size);
+                                }
+                            }
+                            //log::trace!("tx_json = {}", body.to_string());
+                            Ok(PreparedTransaction {
+                                id: tx.id.clone(),
+                                sender: tx.sender.clone(),
+                                tx_type: tx.tx_type as u8,
+                                op_type: tx.op_type.into(),
+                                fee_amount: tx.fee.amount,
+                                fee_asset: tx.fee.asset_id.clone(),
+                                body,
+                                type_label: tx.type_label(),
+                            })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    PreparedUpdate::Append(PreparedBlock {
+                        block_id: append.block_id,
+                        height: append.height,
+                        timestamp: append.timestamp.expect("block timestamp"),
+                        is_microblock: append.is_microblock,
+                        transactions,
+                        quarantined: append.quarantined,
+                    })
+                }
+                BlockchainUpdate::Rollback(rollback) => PreparedUpdate::Rollback(PreparedRollback {
+                    block_id: rollback.block_id,
+                }),
+            };
+            Ok(prepared)
+        })
+        .collect()
+}
+
+/// Size of the serialized JSON, without actually keeping it
+fn json_size(value: &serde_json::Value) -> serde_json::Result<usize> {
+    struct ByteCounter(usize);
+
+    impl io::Write for ByteCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, value)?;
+    Ok(counter.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::model::{Amount, Transaction, SCHEMA_VERSION};
+    use crate::consumer::updates::AppendBlock;
+
+    fn prepare_transactions(transactions: Vec<Transaction>) -> Vec<PreparedTransaction> {
+        let append = AppendBlock::for_test("block1", 1, transactions);
+        let mut batch = prepare_batch(vec![BlockchainUpdate::Append(append)], None).unwrap();
+        match batch.pop() {
+            Some(PreparedUpdate::Append(block)) => block.transactions,
+            _ => panic!("not an append"),
+        }
+    }
+
+    #[test]
+    fn fee_columns_match_json() {
+        let mut in_asset = Transaction::for_test("tx2", 1);
+        in_asset.fee = Amount::new(12345, Some("Asset".to_owned()));
+        let prepared = prepare_transactions(vec![Transaction::for_test("tx1", 1), in_asset]);
+
+        let fees = prepared.iter().map(|tx| (tx.fee_amount, tx.fee_asset.as_str()));
+        assert_eq!(fees.collect::<Vec<_>>(), [(100000, "WAVES"), (12345, "Asset")]);
+        for tx in &prepared {
+            assert_eq!(tx.body["fee"]["amount"], tx.fee_amount, "{}", tx.body);
+            assert_eq!(tx.body["fee"]["id"], tx.fee_asset, "{}", tx.body);
+        }
+    }
+
+    #[test]
+    fn schema_version_is_stored() {
+        let prepared = prepare_transactions(vec![Transaction::for_test("tx1", 1)]);
+        assert_eq!(
+            prepared[0].body["schema_version"], SCHEMA_VERSION,
+            "{}",
+            prepared[0].body
+        );
+    }
+
+    #[test]
+    fn oversized_transactions_are_counted() {
+        let mut oversized = Transaction::for_test("tx2", 1);
+        oversized.proofs = vec!["P".repeat(2000)];
+        let transactions = vec![Transaction::for_test("tx1", 1), oversized];
+        let append = AppendBlock::for_test("block1", 1, transactions);
+
+        let before = OVERSIZED_TRANSACTIONS.get();
+        let batch = prepare_batch(vec![BlockchainUpdate::Append(append)], Some(1000)).unwrap();
+        assert_eq!(OVERSIZED_TRANSACTIONS.get(), before + 1);
+        // Only reported, still stored
+        match &batch[..] {
+            [PreparedUpdate::Append(block)] => assert_eq!(block.transactions.len(), 2),
+            _ => panic!("not an append"),
+        }
+    }
+}
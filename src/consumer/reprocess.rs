@@ -0,0 +1,178 @@
+//! Reprocessing of the stored transactions.
+//!
+//! Raw transactions are not stored, so the blocks of the height range are fetched
+//! from the blockchain updates once again, converted with the current model
+//! and the `operation` JSON of the already stored transactions is rewritten.
+//! Nothing is inserted or deleted: the stored blocks and transactions stay as they are.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+
+use crate::consumer::batcher;
+use crate::consumer::config::ReprocessParams;
+use crate::consumer::prepare::{self, PreparedUpdate};
+use crate::consumer::storage::{Repo, Storage};
+use crate::consumer::updates::BlockchainUpdates;
+
+pub async fn run(
+    storage: impl Storage,
+    updates_source: BlockchainUpdates,
+    params: ReprocessParams,
+    warn_tx_size: Option<usize>,
+) -> Result<()> {
+    let ReprocessParams { from_height, to_height } = params;
+
+    let (last_height, stored_ids) = storage
+        .transaction(move |repo| Ok((repo.last_height()?, repo.transaction_ids(from_height, to_height)?)))
+        .await?;
+    match last_height {
+        Some(last_height) if last_height >= to_height => {}
+        _ => bail!(
+            "Can't reprocess heights {}..={}: last stored height is {:?}",
+            from_height,
+            to_height,
+            last_height
+        ),
+    }
+    log::info!(
+        "Reprocessing {} stored transactions at heights {}..={}",
+        stored_ids.len(),
+        from_height,
+        to_height
+    );
+    let mut remaining = stored_ids.into_iter().collect::<HashSet<_>>();
+
+    let rx = updates_source.stream_range(from_height, to_height).await?;
+    let mut rx = prepare::start(batcher::start_unbatched(rx), warn_tx_size);
+
+    let mut reached_height = None;
+    let mut rewritten_count = 0;
+    while let Some(batch) = rx.recv().await {
+        let batch = batch?;
+        let (rewritten, last_height) = storage
+            .transaction(move |repo| {
+                let mut rewritten = Vec::new();
+                let mut last_height = None;
+                for update in batch {
+                    match update {
+                        PreparedUpdate::Append(block) => {
+                            for tx in block.transactions {
+                                if repo.update_operation(&tx.id, &tx.body)? {
+                                    rewritten.push(tx.id);
+                                } else {
+                                    log::warn!("Transaction {} at height {} is not stored", tx.id, block.height);
+                                }
+                            }
+                            last_height = Some(block.height);
+                        }
+                        PreparedUpdate::Rollback(rollback) => {
+                            // Not expected below the stored height, there is nothing to roll back anyway
+                            log::warn!("Rollback to block {} ignored while reprocessing", rollback.block_id);
+                        }
+                    }
+                }
+                Ok((rewritten, last_height))
+            })
+            .await?;
+        rewritten_count += rewritten.len();
+        for id in &rewritten {
+            remaining.remove(id);
+        }
+        reached_height = last_height.or(reached_height);
+    }
+
+    // The stream is closed on errors as well, so make sure the whole range was received
+    if reached_height != Some(to_height) {
+        bail!(
+            "Blockchain updates stream closed at height {:?}, before reaching height {}",
+            reached_height,
+            to_height
+        );
+    }
+
+    log::info!("Reprocessed {} transactions", rewritten_count);
+    if !remaining.is_empty() {
+        log::warn!(
+            "{} stored transactions were not found in the blockchain updates and left as is",
+            remaining.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::database::types::OperationType;
+    use crate::consumer::storage::{MemoryStorage, NewTransaction};
+    use crate::consumer::updates::test_server::{self, lease_cancel};
+    use crate::consumer::updates::{BinaryEncoding, ConvertLimits, GrpcCompression};
+
+    async fn updates_source(url: String) -> BlockchainUpdates {
+        let limits = ConvertLimits {
+            max_arg_depth: 10,
+            max_args: None,
+            max_payments: None,
+            reject_over_limit: false,
+            binary_encoding: BinaryEncoding::Base64,
+        };
+        BlockchainUpdates::connect(url, 4 * 1024 * 1024, GrpcCompression::None, limits, false, false)
+            .await
+            .expect("failed to connect")
+    }
+
+    #[tokio::test]
+    async fn stored_operation_is_rewritten() {
+        let storage = MemoryStorage::default();
+        // Transaction ids of the test server blocks are 0, 1, 2...
+        let tx_id = bs58::encode([0; 32]).into_string();
+        let id = tx_id.clone();
+        storage
+            .transaction(move |repo| {
+                let block_uid = repo.insert_block("block", 5, 1_600_000_000_000, false)?;
+                repo.insert_tx(&NewTransaction {
+                    id: &id,
+                    block_uid,
+                    sender: "3PSender",
+                    tx_type: 9,
+                    op_type: OperationType::LeaseCancel,
+                    fee_amount: 100_000,
+                    fee_asset: "WAVES",
+                    operation: &serde_json::json!({ "id": id, "stale": true }),
+                    height: 5,
+                })
+            })
+            .await
+            .unwrap();
+
+        let url = test_server::start(vec![test_server::block_update(5, vec![lease_cancel()])], None);
+        let params = ReprocessParams {
+            from_height: 5,
+            to_height: 5,
+        };
+        run(storage.clone(), updates_source(url).await, params, None)
+            .await
+            .unwrap();
+
+        let transactions = storage.data().transactions;
+        assert_eq!(transactions.len(), 1);
+        let operation = &transactions[0].operation;
+        assert_eq!(operation["id"], tx_id);
+        assert_eq!(operation["type"], "lease_cancel");
+        assert_eq!(operation["lease_id"], bs58::encode([5; 32]).into_string());
+        assert!(operation.get("stale").is_none());
+    }
+
+    #[tokio::test]
+    async fn range_above_stored_height_is_rejected() {
+        let storage = MemoryStorage::default();
+        let url = test_server::start(vec![test_server::block_update(5, vec![lease_cancel()])], None);
+        let params = ReprocessParams {
+            from_height: 5,
+            to_height: 5,
+        };
+        let err = run(storage, updates_source(url).await, params, None).await.unwrap_err();
+        assert!(err.to_string().contains("last stored height is None"), "{}", err);
+    }
+}
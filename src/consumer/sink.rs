@@ -0,0 +1,82 @@
+//! Publishing of the stored operations to the external consumers.
+//!
+//! The batch is published only after it is committed to the database, so nothing rolled back
+//! on the write is ever published. A batch that failed to publish is not retried by the consumer
+//! (the database is the source of truth), the failures are logged and counted instead.
+//! Rollbacks of the already published blocks are not published.
+
+use async_trait::async_trait;
+
+use crate::consumer::prepare::PreparedUpdate;
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::KafkaSink;
+
+#[async_trait]
+pub trait Sink {
+    /// Publish the operations of the committed batch, in blockchain order
+    async fn publish(&self, batch: &[PreparedUpdate]);
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::future::join_all;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+
+    use super::Sink;
+    use crate::consumer::config::KafkaConfig;
+    use crate::consumer::metrics::KAFKA_PUBLISH_ERRORS;
+    use crate::consumer::prepare::PreparedUpdate;
+
+    /// How long a message may wait in the producer queue when it is full
+    const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Publishes every operation as JSON to the topic, keyed by the sender,
+    /// so that the operations of a sender stay in order within their partition.
+    pub struct KafkaSink {
+        producer: FutureProducer,
+        topic: String,
+    }
+
+    impl KafkaSink {
+        pub fn new(config: &KafkaConfig) -> anyhow::Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", &config.bootstrap_servers)
+                // Producer retries must not reorder the messages
+                .set("enable.idempotence", "true")
+                .create()?;
+            Ok(KafkaSink {
+                producer,
+                topic: config.topic.clone(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Sink for KafkaSink {
+        async fn publish(&self, batch: &[PreparedUpdate]) {
+            let payloads = batch
+                .iter()
+                .flat_map(|update| match update {
+                    PreparedUpdate::Append(block) => block.transactions.as_slice(),
+                    PreparedUpdate::Rollback(_) => &[],
+                })
+                .map(|tx| (tx, tx.body.to_string()))
+                .collect::<Vec<_>>();
+            let sends = payloads.iter().map(|(tx, payload)| {
+                let record = FutureRecord::to(&self.topic).key(&tx.sender).payload(payload);
+                self.producer.send(record, QUEUE_TIMEOUT)
+            });
+            for (res, (tx, _)) in join_all(sends).await.into_iter().zip(&payloads) {
+                if let Err((err, _message)) = res {
+                    KAFKA_PUBLISH_ERRORS.inc();
+                    log::error!("Failed to publish operation {} to Kafka: {}", tx.id, err);
+                }
+            }
+        }
+    }
+}
@@ -2,8 +2,19 @@
 
 use anyhow::Result;
 use async_trait::async_trait;
+use diesel::result::{ConnectionError, DatabaseErrorKind, Error as DieselError};
 
+use crate::common::database::types::OperationType;
+use crate::schema::{quarantine, transactions};
+
+#[cfg(test)]
+pub use self::memory_storage::MemoryStorage;
 pub use self::postgres_storage::PostgresStorage;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite_storage::SqliteStorage;
+
+/// Unique id of the stored block, increases with each block or microblock inserted
+pub type BlockUID = i64;
 
 #[async_trait]
 pub trait Storage {
@@ -15,24 +26,72 @@ pub trait Storage {
         F: FnOnce(&mut Self::Repo) -> Result<R>,
         F: Send + 'static,
         R: Send + 'static;
+
+    /// Replace the connection with a new one, as a failure may have left the current one broken.
+    async fn reconnect(&self) -> Result<()>;
 }
 
 pub trait Repo {
-    type BlockUID: Copy;
-
     fn last_height(&mut self) -> Result<Option<u32>>;
     fn rollback_to_height(&mut self, height: u32) -> Result<()>;
-    fn rollback_to_block(&mut self, block_uid: Self::BlockUID) -> Result<()>;
-    fn insert_block(&mut self, id: &str, height: u32, timestamp: u64) -> Result<Self::BlockUID>;
-    fn insert_tx(
-        &mut self,
-        id: &str,
-        block_uid: Self::BlockUID,
-        sender: &str,
-        tx_type: u8,
-        operation: serde_json::Value,
-    ) -> Result<()>;
-    fn block_uid(&mut self, block_id: &str) -> Result<Self::BlockUID>;
+    /// Delete everything stored after the block, returns its height (the last stored height then)
+    /// or `None` if there is no such block
+    fn rollback_to_block(&mut self, block_uid: BlockUID) -> Result<Option<u32>>;
+    fn insert_block(&mut self, id: &str, height: u32, timestamp: u64, is_microblock: bool) -> Result<BlockUID>;
+    fn insert_tx(&mut self, tx: &NewTransaction) -> Result<()>;
+    fn insert_quarantined(&mut self, tx: &NewQuarantinedTransaction) -> Result<()>;
+    fn block_uid(&mut self, block_id: &str) -> Result<Option<BlockUID>>;
+    /// Uid of the key block stored at the height, microblocks are not taken into account
+    fn key_block_uid(&mut self, height: u32) -> Result<Option<BlockUID>>;
+    /// Ids of the transactions stored within the height range (both bounds inclusive), in blockchain order
+    fn transaction_ids(&mut self, from_height: u32, to_height: u32) -> Result<Vec<String>>;
+    /// Replace the operation JSON of a stored transaction, returns `false` if there is no such transaction
+    fn update_operation(&mut self, id: &str, operation: &serde_json::Value) -> Result<bool>;
+    /// Delete all stored blocks, transactions and quarantined transactions
+    fn truncate_all(&mut self) -> Result<()>;
+}
+
+/// Whether the failure may go away on retry (e.g. a lost connection, a failover or a serialization failure),
+/// as opposed to a constraint violation or a bug, which fails the same way every time.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| match cause.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(kind, _)) => !matches!(
+            kind,
+            DatabaseErrorKind::UniqueViolation
+                | DatabaseErrorKind::ForeignKeyViolation
+                | DatabaseErrorKind::NotNullViolation
+                | DatabaseErrorKind::CheckViolation
+        ),
+        Some(DieselError::BrokenTransactionManager) => true,
+        Some(_) => false,
+        None => cause.is::<ConnectionError>(),
+    })
+}
+
+/// Transaction row to be inserted into the database
+#[derive(Insertable)]
+#[diesel(table_name = transactions)]
+pub struct NewTransaction<'a> {
+    pub id: &'a str,
+    pub block_uid: BlockUID,
+    pub sender: &'a str,
+    pub tx_type: i16,
+    pub op_type: OperationType,
+    pub fee_amount: i64,
+    pub fee_asset: &'a str,
+    pub operation: &'a serde_json::Value,
+    /// Height of the block, duplicated as the partition key
+    pub height: i32,
+}
+
+/// Row of a transaction that failed to convert
+#[derive(Insertable)]
+#[diesel(table_name = quarantine)]
+pub struct NewQuarantinedTransaction<'a> {
+    pub id: &'a str,
+    pub block_uid: BlockUID,
+    pub raw: &'a [u8],
+    pub reason: &'a str,
 }
 
 mod postgres_storage {
@@ -40,23 +99,26 @@ mod postgres_storage {
 
     use anyhow::Result;
     use async_trait::async_trait;
-    use diesel::{dsl::max, ExpressionMethods, QueryDsl, RunQueryDsl};
+    use diesel::{dsl::max, sql_types::Integer, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
     use diesel::{pg::PgConnection, Connection};
     use tokio::task;
 
-    use super::{Repo, Storage};
-    use crate::common::database::types::OperationType;
-    use crate::schema::{blocks_microblocks, transactions};
+    use super::{BlockUID, NewQuarantinedTransaction, NewTransaction, Repo, Storage};
+    use crate::schema::{blocks_microblocks, quarantine, transactions};
 
     #[derive(Clone)]
     pub struct PostgresStorage {
         conn: Arc<Mutex<Option<Box<PgConnection>>>>,
+        /// To reconnect to the same database
+        db_url: Arc<str>,
     }
 
     impl PostgresStorage {
-        pub fn new(conn: PgConnection) -> Self {
+        /// The connection must be established with `db_url`
+        pub fn new(conn: PgConnection, db_url: &str) -> Self {
             PostgresStorage {
                 conn: Arc::new(Mutex::new(Some(Box::new(conn)))),
+                db_url: db_url.into(),
             }
         }
     }
@@ -82,11 +144,21 @@ mod postgres_storage {
             .await
             .expect("sync task panicked")
         }
+
+        async fn reconnect(&self) -> Result<()> {
+            let conn_arc = self.conn.clone();
+            let db_url = self.db_url.clone();
+            task::spawn_blocking(move || {
+                let conn = PgConnection::establish(&db_url)?;
+                *conn_arc.lock().unwrap() = Some(Box::new(conn));
+                Ok(())
+            })
+            .await
+            .expect("sync task panicked")
+        }
     }
 
     impl Repo for PgConnection {
-        type BlockUID = i64;
-
         fn last_height(&mut self) -> Result<Option<u32>> {
             log::timer!("last_height()", level = trace);
             let height: Option<i32> = blocks_microblocks::table
@@ -97,25 +169,40 @@ mod postgres_storage {
 
         fn rollback_to_height(&mut self, height: u32) -> Result<()> {
             log::timer!("rollback_to_height()", level = trace);
+            drop_partitions_above(self, height as i32)?;
             let _row_count =
                 diesel::delete(blocks_microblocks::table.filter(blocks_microblocks::height.gt(height as i32)))
                     .execute(self)?;
             Ok(())
         }
 
-        fn rollback_to_block(&mut self, block_uid: Self::BlockUID) -> Result<()> {
+        fn rollback_to_block(&mut self, block_uid: BlockUID) -> Result<Option<u32>> {
             log::timer!("rollback_to_block()", level = trace);
+            // Blocks above it are all at its height or above
+            let height = blocks_microblocks::table
+                .select(blocks_microblocks::height)
+                .filter(blocks_microblocks::uid.eq(block_uid))
+                .first::<i32>(self)
+                .optional()?;
+            if let Some(height) = height {
+                drop_partitions_above(self, height)?;
+            }
             let _row_count = diesel::delete(blocks_microblocks::table.filter(blocks_microblocks::uid.gt(block_uid)))
                 .execute(self)?;
-            Ok(())
+            Ok(height.map(|h| h as u32))
         }
 
-        fn insert_block(&mut self, id: &str, height: u32, timestamp: u64) -> Result<Self::BlockUID> {
+        fn insert_block(&mut self, id: &str, height: u32, timestamp: u64, is_microblock: bool) -> Result<BlockUID> {
             log::timer!("insert_block()", level = trace);
+            // Does nothing unless the tables are partitioned
+            diesel::sql_query("SELECT create_height_partitions($1)")
+                .bind::<Integer, _>(height as i32)
+                .execute(self)?;
             let values = (
                 blocks_microblocks::id.eq(id),
                 blocks_microblocks::height.eq(height as i32),
                 blocks_microblocks::time_stamp.eq(timestamp as i64),
+                blocks_microblocks::is_microblock.eq(is_microblock),
             );
             let res = diesel::insert_into(blocks_microblocks::table)
                 .values(&values)
@@ -125,35 +212,486 @@ mod postgres_storage {
             Ok(res[0])
         }
 
-        fn insert_tx(
-            &mut self,
-            id: &str,
-            block_uid: Self::BlockUID,
-            sender: &str,
-            tx_type: u8,
-            operation: serde_json::Value,
-        ) -> Result<()> {
+        fn insert_tx(&mut self, tx: &NewTransaction) -> Result<()> {
             log::timer!("insert_tx()", level = trace);
+            let row_count = diesel::insert_into(transactions::table).values(tx).execute(self)?;
+            assert_eq!(row_count, 1);
+            Ok(())
+        }
+
+        fn insert_quarantined(&mut self, tx: &NewQuarantinedTransaction) -> Result<()> {
+            log::timer!("insert_quarantined()", level = trace);
+            let row_count = diesel::insert_into(quarantine::table).values(tx).execute(self)?;
+            assert_eq!(row_count, 1);
+            Ok(())
+        }
+
+        fn block_uid(&mut self, block_id: &str) -> Result<Option<BlockUID>> {
+            log::timer!("block_uid()", level = trace);
+            let res = blocks_microblocks::table
+                .select(blocks_microblocks::uid)
+                .filter(blocks_microblocks::id.eq(block_id))
+                .get_result(self)
+                .optional()?;
+            Ok(res)
+        }
+
+        fn key_block_uid(&mut self, height: u32) -> Result<Option<BlockUID>> {
+            log::timer!("key_block_uid()", level = trace);
+            let res = blocks_microblocks::table
+                .select(blocks_microblocks::uid)
+                .filter(blocks_microblocks::height.eq(height as i32))
+                .filter(blocks_microblocks::is_microblock.eq(false))
+                .order(blocks_microblocks::uid.desc())
+                .first(self)
+                .optional()?;
+            Ok(res)
+        }
+
+        fn transaction_ids(&mut self, from_height: u32, to_height: u32) -> Result<Vec<String>> {
+            log::timer!("transaction_ids()", level = trace);
+            let ids = transactions::table
+                .select(transactions::id)
+                .filter(transactions::height.ge(from_height as i32))
+                .filter(transactions::height.le(to_height as i32))
+                .order(transactions::uid)
+                .load(self)?;
+            Ok(ids)
+        }
+
+        fn update_operation(&mut self, id: &str, operation: &serde_json::Value) -> Result<bool> {
+            log::timer!("update_operation()", level = trace);
+            let row_count = diesel::update(transactions::table.filter(transactions::id.eq(id)))
+                .set(transactions::operation.eq(operation))
+                .execute(self)?;
+            Ok(row_count > 0)
+        }
+
+        fn truncate_all(&mut self) -> Result<()> {
+            log::timer!("truncate_all()", level = trace);
+            // Partitions, if any, are truncated along with their parent tables, and the keys by the trigger
+            diesel::sql_query("TRUNCATE transactions, quarantine, blocks_microblocks RESTART IDENTITY CASCADE")
+                .execute(self)?;
+            Ok(())
+        }
+    }
+
+    /// Drop the partitions entirely above the height, if the tables are partitioned,
+    /// which is much faster than deleting their rows. The rows above the height in the rest are left to the caller.
+    fn drop_partitions_above(conn: &mut PgConnection, height: i32) -> Result<()> {
+        diesel::sql_query("SELECT drop_height_partitions_above($1)")
+            .bind::<Integer, _>(height)
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+/// Same as the Postgres storage, but on SQLite, for local development only
+#[cfg(feature = "sqlite")]
+mod sqlite_storage {
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use diesel::{dsl::max, ExpressionMethods, NullableExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
+    use diesel::{sqlite::SqliteConnection, Connection};
+    use tokio::task;
+
+    use super::{BlockUID, NewQuarantinedTransaction, NewTransaction, Repo, Storage};
+    use crate::schema_sqlite::{blocks_microblocks, quarantine, transactions};
+
+    #[derive(Clone)]
+    pub struct SqliteStorage {
+        conn: Arc<Mutex<Option<Box<SqliteConnection>>>>,
+        path: Arc<str>,
+    }
+
+    impl SqliteStorage {
+        pub fn connect(path: &str) -> Result<Self> {
+            Ok(SqliteStorage {
+                conn: Arc::new(Mutex::new(Some(Box::new(establish(path)?)))),
+                path: path.into(),
+            })
+        }
+    }
+
+    fn establish(path: &str) -> Result<SqliteConnection> {
+        let mut conn = SqliteConnection::establish(path)?;
+        // Rollbacks rely on the cascade deletes, which SQLite only does when asked to
+        diesel::sql_query("PRAGMA foreign_keys = ON").execute(&mut conn)?;
+        Ok(conn)
+    }
+
+    #[async_trait]
+    impl Storage for SqliteStorage {
+        type Repo = SqliteConnection;
+
+        async fn transaction<F, R>(&self, f: F) -> Result<R>
+        where
+            F: FnOnce(&mut Self::Repo) -> Result<R>,
+            F: Send + 'static,
+            R: Send + 'static,
+        {
+            let conn_arc = self.conn.clone();
+            task::spawn_blocking(move || {
+                let mut conn_guard = conn_arc.lock().unwrap();
+                let mut conn = conn_guard.take().expect("connection is gone");
+                let result = conn.transaction(|conn| f(conn));
+                *conn_guard = Some(conn);
+                result
+            })
+            .await
+            .expect("sync task panicked")
+        }
+
+        async fn reconnect(&self) -> Result<()> {
+            let conn_arc = self.conn.clone();
+            let path = self.path.clone();
+            task::spawn_blocking(move || {
+                let conn = establish(&path)?;
+                *conn_arc.lock().unwrap() = Some(Box::new(conn));
+                Ok(())
+            })
+            .await
+            .expect("sync task panicked")
+        }
+    }
+
+    impl Repo for SqliteConnection {
+        fn last_height(&mut self) -> Result<Option<u32>> {
+            let height: Option<i32> = blocks_microblocks::table
+                .select(max(blocks_microblocks::height))
+                .first(self)?;
+            Ok(height.map(|h| h as u32))
+        }
+
+        fn rollback_to_height(&mut self, height: u32) -> Result<()> {
+            diesel::delete(blocks_microblocks::table.filter(blocks_microblocks::height.gt(height as i32)))
+                .execute(self)?;
+            Ok(())
+        }
+
+        fn rollback_to_block(&mut self, block_uid: BlockUID) -> Result<Option<u32>> {
+            let height = blocks_microblocks::table
+                .select(blocks_microblocks::height)
+                .filter(blocks_microblocks::uid.eq(block_uid))
+                .first::<i32>(self)
+                .optional()?;
+            diesel::delete(blocks_microblocks::table.filter(blocks_microblocks::uid.gt(block_uid))).execute(self)?;
+            Ok(height.map(|h| h as u32))
+        }
+
+        fn insert_block(&mut self, id: &str, height: u32, timestamp: u64, is_microblock: bool) -> Result<BlockUID> {
+            let values = (
+                blocks_microblocks::id.eq(id),
+                blocks_microblocks::height.eq(height as i32),
+                blocks_microblocks::time_stamp.eq(timestamp as i64),
+                blocks_microblocks::is_microblock.eq(is_microblock),
+            );
+            let uid = diesel::insert_into(blocks_microblocks::table)
+                .values(&values)
+                .returning(blocks_microblocks::uid)
+                .get_result(self)?;
+            Ok(uid)
+        }
+
+        fn insert_tx(&mut self, tx: &NewTransaction) -> Result<()> {
             let values = (
-                transactions::id.eq(id),
-                transactions::block_uid.eq(block_uid),
-                transactions::sender.eq(sender),
-                transactions::tx_type.eq(tx_type as i16),
-                transactions::op_type.eq(OperationType::InvokeScript),
-                transactions::operation.eq(operation),
+                transactions::id.eq(tx.id),
+                transactions::block_uid.eq(tx.block_uid),
+                transactions::sender.eq(tx.sender),
+                transactions::tx_type.eq(tx.tx_type),
+                transactions::op_type.eq(tx.op_type.as_str()),
+                transactions::operation.eq(tx.operation.to_string()),
+                transactions::fee_amount.eq(tx.fee_amount),
+                transactions::fee_asset.eq(tx.fee_asset),
+                transactions::height.eq(tx.height),
+                // No column default for it in SQLite
+                transactions::created_at.eq(diesel::dsl::now.nullable()),
             );
             let row_count = diesel::insert_into(transactions::table).values(&values).execute(self)?;
             assert_eq!(row_count, 1);
             Ok(())
         }
 
-        fn block_uid(&mut self, block_id: &str) -> Result<Self::BlockUID> {
-            log::timer!("block_uid()", level = trace);
+        fn insert_quarantined(&mut self, tx: &NewQuarantinedTransaction) -> Result<()> {
+            let values = (
+                quarantine::id.eq(tx.id),
+                quarantine::block_uid.eq(tx.block_uid),
+                quarantine::raw.eq(tx.raw),
+                quarantine::reason.eq(tx.reason),
+            );
+            let row_count = diesel::insert_into(quarantine::table).values(&values).execute(self)?;
+            assert_eq!(row_count, 1);
+            Ok(())
+        }
+
+        fn block_uid(&mut self, block_id: &str) -> Result<Option<BlockUID>> {
             let res = blocks_microblocks::table
                 .select(blocks_microblocks::uid)
                 .filter(blocks_microblocks::id.eq(block_id))
-                .get_result(self)?;
+                .get_result(self)
+                .optional()?;
+            Ok(res)
+        }
+
+        fn key_block_uid(&mut self, height: u32) -> Result<Option<BlockUID>> {
+            let res = blocks_microblocks::table
+                .select(blocks_microblocks::uid)
+                .filter(blocks_microblocks::height.eq(height as i32))
+                .filter(blocks_microblocks::is_microblock.eq(false))
+                .order(blocks_microblocks::uid.desc())
+                .first(self)
+                .optional()?;
+            Ok(res)
+        }
+
+        fn transaction_ids(&mut self, from_height: u32, to_height: u32) -> Result<Vec<String>> {
+            let ids = transactions::table
+                .select(transactions::id)
+                .filter(transactions::height.ge(from_height as i32))
+                .filter(transactions::height.le(to_height as i32))
+                .order(transactions::uid)
+                .load(self)?;
+            Ok(ids)
+        }
+
+        fn update_operation(&mut self, id: &str, operation: &serde_json::Value) -> Result<bool> {
+            let row_count = diesel::update(transactions::table.filter(transactions::id.eq(id)))
+                .set(transactions::operation.eq(operation.to_string()))
+                .execute(self)?;
+            Ok(row_count > 0)
+        }
+
+        fn truncate_all(&mut self) -> Result<()> {
+            // Dependent tables first, not to rely on the cascade deletes
+            diesel::delete(transactions::table).execute(self)?;
+            diesel::delete(quarantine::table).execute(self)?;
+            diesel::delete(blocks_microblocks::table).execute(self)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database_error(kind: DatabaseErrorKind) -> anyhow::Error {
+        DieselError::DatabaseError(kind, Box::new("database error".to_owned())).into()
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&database_error(DatabaseErrorKind::ClosedConnection)));
+        assert!(is_transient(&database_error(DatabaseErrorKind::SerializationFailure)));
+        assert!(is_transient(&database_error(DatabaseErrorKind::ReadOnlyTransaction)));
+        assert!(is_transient(&database_error(DatabaseErrorKind::Unknown)));
+        assert!(is_transient(&DieselError::BrokenTransactionManager.into()));
+        assert!(is_transient(
+            &anyhow::Error::from(ConnectionError::BadConnection("refused".to_owned())).context("reconnect")
+        ));
+
+        assert!(!is_transient(&database_error(DatabaseErrorKind::UniqueViolation)));
+        assert!(!is_transient(&database_error(DatabaseErrorKind::ForeignKeyViolation)));
+        assert!(!is_transient(&database_error(DatabaseErrorKind::NotNullViolation)));
+        assert!(!is_transient(&database_error(DatabaseErrorKind::CheckViolation)));
+        assert!(!is_transient(&DieselError::NotFound.into()));
+        assert!(!is_transient(&anyhow::anyhow!("something else")));
+    }
+}
+
+/// In-memory storage for the tests of the consumer.
+/// A transaction works on a copy of the data which replaces the data only if the transaction succeeds,
+/// so a failed one leaves nothing behind, same as in the database.
+#[cfg(test)]
+mod memory_storage {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use super::{BlockUID, NewQuarantinedTransaction, NewTransaction, Repo, Storage};
+
+    #[derive(Clone, Default)]
+    pub struct MemoryStorage {
+        data: Arc<Mutex<MemoryRepo>>,
+        transaction_count: Arc<AtomicUsize>,
+        reconnect_count: Arc<AtomicUsize>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct StoredBlock {
+        pub uid: BlockUID,
+        pub id: String,
+        pub height: u32,
+        pub timestamp: u64,
+        pub is_microblock: bool,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct StoredTransaction {
+        pub id: String,
+        pub block_uid: BlockUID,
+        pub sender: String,
+        pub height: i32,
+        pub operation: serde_json::Value,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct MemoryRepo {
+        pub blocks: Vec<StoredBlock>,
+        pub transactions: Vec<StoredTransaction>,
+        /// Ids of the quarantined transactions
+        pub quarantined: Vec<String>,
+        /// Number of the `block_uid` lookups
+        pub block_lookups: usize,
+        /// Number of the `last_height` lookups
+        pub height_lookups: usize,
+        last_uid: BlockUID,
+        /// Errors to fail the next `insert_tx` calls with, shared by all the copies of the data
+        insert_errors: Arc<Mutex<VecDeque<anyhow::Error>>>,
+    }
+
+    impl MemoryStorage {
+        /// Copy of the committed data
+        pub fn data(&self) -> MemoryRepo {
+            self.data.lock().unwrap().clone()
+        }
+
+        /// Number of the transactions started so far, committed or not
+        pub fn transaction_count(&self) -> usize {
+            self.transaction_count.load(Ordering::Relaxed)
+        }
+
+        /// Number of the reconnects so far
+        pub fn reconnect_count(&self) -> usize {
+            self.reconnect_count.load(Ordering::Relaxed)
+        }
+
+        /// Fail the next `insert_tx` calls with the given errors, one by one
+        pub fn fail_inserts(&self, errors: impl IntoIterator<Item = anyhow::Error>) {
+            let data = self.data.lock().unwrap();
+            data.insert_errors.lock().unwrap().extend(errors);
+        }
+    }
+
+    #[async_trait]
+    impl Storage for MemoryStorage {
+        type Repo = MemoryRepo;
+
+        async fn transaction<F, R>(&self, f: F) -> Result<R>
+        where
+            F: FnOnce(&mut Self::Repo) -> Result<R>,
+            F: Send + 'static,
+            R: Send + 'static,
+        {
+            self.transaction_count.fetch_add(1, Ordering::Relaxed);
+            let mut data = self.data.lock().unwrap();
+            let mut copy = data.clone();
+            let res = f(&mut copy)?;
+            *data = copy;
             Ok(res)
         }
+
+        async fn reconnect(&self) -> Result<()> {
+            self.reconnect_count.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    impl MemoryRepo {
+        fn remove_blocks(&mut self, keep: impl Fn(&StoredBlock) -> bool) {
+            self.blocks.retain(|block| keep(block));
+            let uids = self.blocks.iter().map(|block| block.uid).collect::<Vec<_>>();
+            self.transactions.retain(|tx| uids.contains(&tx.block_uid));
+        }
+    }
+
+    impl Repo for MemoryRepo {
+        fn last_height(&mut self) -> Result<Option<u32>> {
+            self.height_lookups += 1;
+            Ok(self.blocks.iter().map(|block| block.height).max())
+        }
+
+        fn rollback_to_height(&mut self, height: u32) -> Result<()> {
+            self.remove_blocks(|block| block.height <= height);
+            Ok(())
+        }
+
+        fn rollback_to_block(&mut self, block_uid: BlockUID) -> Result<Option<u32>> {
+            self.remove_blocks(|block| block.uid <= block_uid);
+            let block = self.blocks.iter().find(|block| block.uid == block_uid);
+            Ok(block.map(|block| block.height))
+        }
+
+        fn insert_block(&mut self, id: &str, height: u32, timestamp: u64, is_microblock: bool) -> Result<BlockUID> {
+            anyhow::ensure!(self.blocks.iter().all(|block| block.id != id), "duplicate block {}", id);
+            self.last_uid += 1;
+            self.blocks.push(StoredBlock {
+                uid: self.last_uid,
+                id: id.to_owned(),
+                height,
+                timestamp,
+                is_microblock,
+            });
+            Ok(self.last_uid)
+        }
+
+        fn insert_tx(&mut self, tx: &NewTransaction) -> Result<()> {
+            if let Some(err) = self.insert_errors.lock().unwrap().pop_front() {
+                return Err(err);
+            }
+            self.transactions.push(StoredTransaction {
+                id: tx.id.to_owned(),
+                block_uid: tx.block_uid,
+                sender: tx.sender.to_owned(),
+                height: tx.height,
+                operation: tx.operation.clone(),
+            });
+            Ok(())
+        }
+
+        fn insert_quarantined(&mut self, tx: &NewQuarantinedTransaction) -> Result<()> {
+            self.quarantined.push(tx.id.to_owned());
+            Ok(())
+        }
+
+        fn block_uid(&mut self, block_id: &str) -> Result<Option<BlockUID>> {
+            self.block_lookups += 1;
+            Ok(self
+                .blocks
+                .iter()
+                .find(|block| block.id == block_id)
+                .map(|block| block.uid))
+        }
+
+        fn key_block_uid(&mut self, height: u32) -> Result<Option<BlockUID>> {
+            let key_blocks = self
+                .blocks
+                .iter()
+                .filter(|block| block.height == height && !block.is_microblock);
+            Ok(key_blocks.map(|block| block.uid).max())
+        }
+
+        fn transaction_ids(&mut self, from_height: u32, to_height: u32) -> Result<Vec<String>> {
+            let heights = from_height as i32..=to_height as i32;
+            let ids = self.transactions.iter().filter(|tx| heights.contains(&tx.height));
+            Ok(ids.map(|tx| tx.id.clone()).collect())
+        }
+
+        fn update_operation(&mut self, id: &str, operation: &serde_json::Value) -> Result<bool> {
+            let tx = self.transactions.iter_mut().find(|tx| tx.id == id);
+            Ok(tx.map(|tx| tx.operation = operation.clone()).is_some())
+        }
+
+        fn truncate_all(&mut self) -> Result<()> {
+            self.blocks.clear();
+            self.transactions.clear();
+            self.quarantined.clear();
+            Ok(())
+        }
     }
 }
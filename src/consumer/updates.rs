@@ -2,11 +2,14 @@
 
 use anyhow::Error;
 use async_trait::async_trait;
+use serde::Deserialize;
 use tokio::sync::mpsc;
 
 use crate::consumer::model::Transaction;
 
-pub use self::updates_impl::BlockchainUpdates;
+#[cfg(test)]
+pub use self::updates_impl::test_server;
+pub use self::updates_impl::{convert_for_bench, BlockchainUpdates};
 
 #[async_trait]
 pub trait BlockchainUpdatesSource {
@@ -26,6 +29,32 @@ pub struct AppendBlock {
     pub timestamp: Option<u64>,
     pub is_microblock: bool,
     pub transactions: Vec<Transaction>,
+    /// Transactions that failed to convert, only if quarantining is enabled
+    pub quarantined: Vec<QuarantinedTransaction>,
+}
+
+#[cfg(test)]
+impl AppendBlock {
+    /// Key block with the given transactions, for the tests
+    pub fn for_test(id: &str, height: u32, transactions: Vec<Transaction>) -> Self {
+        AppendBlock {
+            block_id: id.to_owned(),
+            height,
+            timestamp: Some(height as u64 * 60000),
+            is_microblock: false,
+            transactions,
+            quarantined: Vec::new(),
+        }
+    }
+}
+
+/// Transaction that failed to convert, kept as is for later investigation
+#[derive(Debug)]
+pub struct QuarantinedTransaction {
+    pub id: String,
+    /// Protobuf-encoded `SignedTransaction`
+    pub raw: Vec<u8>,
+    pub reason: String,
 }
 
 #[derive(Debug)]
@@ -33,40 +62,175 @@ pub struct Rollback {
     pub block_id: String,
 }
 
+/// Encoding of the binary values (invoke script arguments and data entries)
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BinaryEncoding {
+    /// `base64:` prefixed, as in the node's REST API
+    #[default]
+    Base64,
+    /// Lowercase hex, without a prefix
+    Hex,
+}
+
+/// Compression of the blockchain updates stream
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// Limits on the transaction contents, to protect the converter from malicious data,
+/// and the encoding of the binary values
+#[derive(Clone, Copy, Debug)]
+pub struct ConvertLimits {
+    /// Max nesting depth of the invoke script arguments (lists in lists)
+    pub max_arg_depth: usize,
+    /// Max number of the top-level invoke script arguments, if set
+    pub max_args: Option<usize>,
+    /// Max number of the invoke script payments, if set
+    pub max_payments: Option<usize>,
+    /// Fail to convert the transactions over `max_args` or `max_payments` instead of truncating them
+    pub reject_over_limit: bool,
+    pub binary_encoding: BinaryEncoding,
+}
+
 mod updates_impl {
     use async_trait::async_trait;
-    use tokio::{sync::mpsc, task};
+    use tokio::{net::UnixStream, sync::mpsc, task};
+    use tower::service_fn;
 
     use waves_protobuf_schemas::{
-        tonic,
-        waves::events::grpc::{
-            blockchain_updates_api_client::BlockchainUpdatesApiClient, SubscribeEvent, SubscribeRequest,
+        tonic::{
+            self,
+            codec::CompressionEncoding,
+            transport::{Channel, Endpoint, Uri},
+        },
+        waves::events::{
+            grpc::{blockchain_updates_api_client::BlockchainUpdatesApiClient, SubscribeEvent, SubscribeRequest},
+            BlockchainUpdated,
         },
     };
 
-    use super::{BlockchainUpdate, BlockchainUpdatesSource};
+    use super::{BinaryEncoding, BlockchainUpdate, BlockchainUpdatesSource, ConvertLimits, GrpcCompression};
+    use crate::consumer::metrics::{update_height_lag, CHAIN_HEIGHT, CONVERSION_ERRORS, HEIGHT_GAPS};
 
     #[derive(Clone)]
-    pub struct BlockchainUpdates(BlockchainUpdatesApiClient<tonic::transport::Channel>);
+    pub struct BlockchainUpdates {
+        grpc_client: BlockchainUpdatesApiClient<tonic::transport::Channel>,
+        limits: ConvertLimits,
+        quarantine_bad_tx: bool,
+        skip_unconvertible_updates: bool,
+    }
 
     impl BlockchainUpdates {
-        pub async fn connect(blockchain_updates_url: String) -> Result<Self, anyhow::Error> {
-            const MAX_MSG_SIZE: usize = 8 * 1024 * 1024; // 8 MB instead of the default 4 MB
-            let grpc_client = BlockchainUpdatesApiClient::connect(blockchain_updates_url)
-                .await?
-                .max_decoding_message_size(MAX_MSG_SIZE);
-            Ok(BlockchainUpdates(grpc_client))
+        /// Max message size (in bytes) overrides the default 4 MB decoding limit of the gRPC client,
+        /// blocks with large invoke script arguments don't fit into it.
+        ///
+        /// With `quarantine_bad_tx` a transaction that fails to convert is passed on
+        /// as a quarantined one instead of failing the whole stream.
+        /// With `skip_unconvertible_updates` an update of an unexpected shape (see `ConvertError::is_soft`)
+        /// is skipped instead of failing the whole stream.
+        ///
+        /// A `unix:///path/to/socket` URL connects over a unix domain socket, for a node on the same host.
+        ///
+        /// With gzip `compression` the client only announces that it accepts compressed responses,
+        /// which is where the data is, and a server without gzip simply keeps sending them uncompressed.
+        /// The requests are not compressed, such a server would reject them.
+        pub async fn connect(
+            blockchain_updates_url: String,
+            max_message_size: usize,
+            compression: GrpcCompression,
+            limits: ConvertLimits,
+            quarantine_bad_tx: bool,
+            skip_unconvertible_updates: bool,
+        ) -> Result<Self, anyhow::Error> {
+            let grpc_client = match blockchain_updates_url.strip_prefix("unix:") {
+                Some(path) => BlockchainUpdatesApiClient::new(connect_unix(path.trim_start_matches("//")).await?),
+                None => BlockchainUpdatesApiClient::connect(blockchain_updates_url).await?,
+            };
+            let grpc_client = grpc_client.max_decoding_message_size(max_message_size);
+            let grpc_client = match compression {
+                GrpcCompression::None => grpc_client,
+                GrpcCompression::Gzip => grpc_client.accept_compressed(CompressionEncoding::Gzip),
+            };
+            Ok(BlockchainUpdates {
+                grpc_client,
+                limits,
+                quarantine_bad_tx,
+                skip_unconvertible_updates,
+            })
+        }
+    }
+
+    /// Count the gaps in the heights of the appends and keep the chain height up to date,
+    /// `last_height` is the height of the last append seen, unknown at start and after a rollback
+    fn track_height(last_height: &mut Option<u32>, update: &BlockchainUpdate) {
+        match update {
+            BlockchainUpdate::Append(append) => {
+                // Microblocks come at the same height as their key block, so only a jump is a gap
+                if let Some(last) = *last_height {
+                    if append.height > last + 1 {
+                        HEIGHT_GAPS.inc();
+                        log::warn!(
+                            "Gap in blockchain updates: expected height {}, got {}",
+                            last + 1,
+                            append.height
+                        );
+                    }
+                }
+                *last_height = Some(append.height);
+                CHAIN_HEIGHT.set(append.height as i64);
+                update_height_lag();
+            }
+            BlockchainUpdate::Rollback(_) => *last_height = None,
         }
     }
 
+    async fn connect_unix(path: &str) -> Result<Channel, tonic::transport::Error> {
+        let path = path.to_owned();
+        // The endpoint needs some URI, but the connector ignores it and always connects to the socket
+        Endpoint::from_static("http://localhost")
+            .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+            .await
+    }
+
     #[async_trait]
     impl BlockchainUpdatesSource for BlockchainUpdates {
         async fn stream(self, from_height: u32) -> Result<mpsc::Receiver<BlockchainUpdate>, anyhow::Error> {
-            let BlockchainUpdates(mut grpc_client) = self;
+            self.subscribe(from_height, 0).await
+        }
+    }
+
+    impl BlockchainUpdates {
+        /// Stream of the updates of a fixed height range (both bounds inclusive),
+        /// closed by the server once the last block is sent.
+        pub async fn stream_range(
+            self,
+            from_height: u32,
+            to_height: u32,
+        ) -> Result<mpsc::Receiver<BlockchainUpdate>, anyhow::Error> {
+            self.subscribe(from_height, to_height).await
+        }
+
+        /// Zero `to_height` means no upper bound
+        async fn subscribe(
+            self,
+            from_height: u32,
+            to_height: u32,
+        ) -> Result<mpsc::Receiver<BlockchainUpdate>, anyhow::Error> {
+            let BlockchainUpdates {
+                mut grpc_client,
+                limits,
+                quarantine_bad_tx,
+                skip_unconvertible_updates,
+            } = self;
 
             let request = tonic::Request::new(SubscribeRequest {
                 from_height: from_height as i32,
-                to_height: 0,
+                to_height: to_height as i32,
             });
 
             let stream = grpc_client.subscribe(request).await?.into_inner();
@@ -74,7 +238,7 @@ mod updates_impl {
             let (tx, rx) = mpsc::channel::<BlockchainUpdate>(16); // Buffer size is arbitrary
 
             task::spawn(async move {
-                let res = pump_messages(stream, tx).await;
+                let res = pump_messages(stream, tx, limits, quarantine_bad_tx, skip_unconvertible_updates).await;
                 if let Err(err) = res {
                     log::error!("Error receiving blockchain updates: {}", err);
                 } else {
@@ -85,10 +249,27 @@ mod updates_impl {
             async fn pump_messages(
                 mut stream: tonic::Streaming<SubscribeEvent>,
                 tx: mpsc::Sender<BlockchainUpdate>,
+                limits: ConvertLimits,
+                quarantine_bad_tx: bool,
+                skip_unconvertible_updates: bool,
             ) -> anyhow::Result<()> {
+                // Height of the last append seen, unknown at start and after a rollback
+                let mut last_height: Option<u32> = None;
                 while let Some(event) = stream.message().await? {
                     if let Some(update) = event.update {
-                        let update = convert::convert_update(update)?;
+                        let update = match convert::convert_update(update, &limits, quarantine_bad_tx) {
+                            Ok(Some(update)) => update,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                CONVERSION_ERRORS.with_label_values(&[err.reason()]).inc();
+                                if skip_unconvertible_updates && err.is_soft() {
+                                    log::error!("Skipping blockchain update: {}", err);
+                                    continue;
+                                }
+                                return Err(err.into());
+                            }
+                        };
+                        track_height(&mut last_height, &update);
                         tx.send(update).await?;
                     }
                 }
@@ -99,75 +280,191 @@ mod updates_impl {
         }
     }
 
+    /// Converts a blockchain update as the consumer does with the default limits,
+    /// returns the number of operations in it. Not a part of the API, only for the benchmarks.
+    #[doc(hidden)]
+    pub fn convert_for_bench(update: BlockchainUpdated) -> anyhow::Result<usize> {
+        let limits = ConvertLimits {
+            max_arg_depth: 32,
+            max_args: None,
+            max_payments: None,
+            reject_over_limit: false,
+            binary_encoding: BinaryEncoding::Base64,
+        };
+        match convert::convert_update(update, &limits, false)? {
+            Some(BlockchainUpdate::Append(append)) => Ok(append.transactions.len()),
+            _ => Ok(0),
+        }
+    }
+
     mod convert {
+        use std::cell::RefCell;
+        use std::fmt;
+
         use itertools::Itertools;
-        use thiserror::Error;
 
         use waves_protobuf_schemas::waves::invoke_script_result::call::argument::Value;
         use waves_protobuf_schemas::waves::{
+            data_transaction_data::{data_entry::Value as DataEntryValue, DataEntry},
             events::{
                 blockchain_updated::{
                     append::{BlockAppend, Body, MicroBlockAppend},
                     Append, Update,
                 },
-                transaction_metadata::{ethereum_metadata::Action, EthereumMetadata, InvokeScriptMetadata, Metadata},
+                transaction_metadata::{
+                    ethereum_metadata::Action, EthereumMetadata, InvokeScriptMetadata, LeaseMetadata, Metadata,
+                },
                 BlockchainUpdated, TransactionMetadata,
             },
             invoke_script_result::call::Argument,
+            order::Side as WavesOrderSide,
             signed_transaction::Transaction as TransactionEnum,
             transaction::Data as WavesTxData,
-            Amount as WavesAmount, Block, InvokeScriptTransactionData, MicroBlock, SignedMicroBlock, SignedTransaction,
-            Transaction as WavesTransaction,
+            Amount as WavesAmount, Block, ExchangeTransactionData, InvokeScriptTransactionData, LeaseTransactionData,
+            MicroBlock, Order as WavesOrder, SignedMicroBlock, SignedTransaction, Transaction as WavesTransaction,
+        };
+
+        use prost::Message;
+
+        use super::super::{
+            AppendBlock, BinaryEncoding, BlockchainUpdate, ConvertLimits, QuarantinedTransaction, Rollback,
         };
+        use crate::consumer::metrics::{ANOMALOUS_PROOFS, CONVERSION_ERRORS, TRUNCATED_TRANSACTIONS};
+        use crate::consumer::model::{
+            Amount, Arg, AssetPair, Call, DataEntry as ModelDataEntry, DataTransaction, DataValue, Exchange,
+            InvokeScript, Lease, LeaseCancel, OperationData, OperationType, Order, OrderSide, Transaction,
+            TransactionType, SCHEMA_VERSION,
+        };
+
+        /// Reason of the failure with the context of the failed update, where known
+        #[derive(Debug)]
+        pub(super) struct ConvertError {
+            reason: &'static str,
+            height: Option<u32>,
+            tx_id: Option<String>,
+            soft: bool,
+        }
+
+        impl ConvertError {
+            fn new(reason: &'static str) -> Self {
+                ConvertError {
+                    reason,
+                    height: None,
+                    tx_id: None,
+                    soft: false,
+                }
+            }
+
+            /// The update as a whole has an unexpected shape, with nothing to convert in it
+            fn soft(self) -> Self {
+                ConvertError { soft: true, ..self }
+            }
+
+            fn at_height(self, height: u32) -> Self {
+                ConvertError {
+                    height: Some(height),
+                    ..self
+                }
+            }
 
-        use super::super::{AppendBlock, BlockchainUpdate, Rollback};
-        use crate::consumer::model::{Amount, Arg, Call, OperationType, Transaction, TransactionType};
+            fn in_tx(self, tx_id: &str) -> Self {
+                ConvertError {
+                    tx_id: Some(tx_id.to_owned()),
+                    ..self
+                }
+            }
+
+            /// Reason without the context, fine as a metric label
+            pub(super) fn reason(&self) -> &'static str {
+                self.reason
+            }
 
-        #[derive(Error, Debug)]
-        #[error("failed to convert blockchain update: {0}")]
-        pub(super) struct ConvertError(&'static str);
+            /// Whether the update can be skipped without losing anything that could be converted,
+            /// as opposed to a failure to convert a transaction
+            pub(super) fn is_soft(&self) -> bool {
+                self.soft
+            }
+        }
 
-        pub(super) fn convert_update(src: BlockchainUpdated) -> Result<BlockchainUpdate, ConvertError> {
+        impl fmt::Display for ConvertError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "failed to convert blockchain update: {}", self.reason)?;
+                if let Some(height) = self.height {
+                    write!(f, " (height {})", height)?;
+                }
+                if let Some(tx_id) = &self.tx_id {
+                    write!(f, " (transaction {})", tx_id)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl std::error::Error for ConvertError {}
+
+        /// Returns `None` for the updates that must be skipped,
+        /// i.e. appends at height 0 which a freshly started node may send before the genesis block.
+        pub(super) fn convert_update(
+            src: BlockchainUpdated,
+            limits: &ConvertLimits,
+            quarantine_bad_tx: bool,
+        ) -> Result<Option<BlockchainUpdate>, ConvertError> {
             let height = src.height as u32;
+            let error = |reason| ConvertError::new(reason).at_height(height);
             let update = src.update;
             match update {
+                Some(Update::Append(_)) if height == 0 => {
+                    // Heights start at 1 (genesis), there is no block to store
+                    log::warn!("Skipping append at height 0");
+                    Ok(None)
+                }
                 Some(Update::Append(append)) => {
-                    let body = append.body.ok_or(ConvertError("append body is None"))?;
+                    let body = append.body.ok_or_else(|| error("append body is None").soft())?;
                     let Append {
                         transaction_ids,
                         transactions_metadata,
                         ..
                     } = append;
                     let is_microblock =
-                        extract_is_microblock(&body).ok_or(ConvertError("failed to extract is_microblock"))?;
-                    let id = extract_id(&body, &src.id).ok_or(ConvertError("failed to extract block id"))?;
+                        extract_is_microblock(&body).ok_or_else(|| error("failed to extract is_microblock").soft())?;
+                    let id = extract_id(&body, &src.id).ok_or_else(|| error("failed to extract block id"))?;
                     let id = base58(id);
                     let timestamp = extract_timestamp(&body);
-                    let transactions = extract_transactions(body).ok_or(ConvertError("transactions is None"))?;
+                    if timestamp == Some(0) {
+                        return Err(error("block timestamp is zero"));
+                    }
+                    let transactions =
+                        extract_transactions(body).ok_or_else(|| error("transactions is None").soft())?;
                     assert!(
                         transaction_ids.len() == transactions.len()
                             && transactions.len() == transactions_metadata.len()
                     );
                     let block_info = BlockInfo { height, timestamp };
-                    let transactions =
-                        convert_transactions(transaction_ids, transactions, transactions_metadata, block_info)?;
+                    let (transactions, quarantined) = convert_transactions(
+                        transaction_ids,
+                        transactions,
+                        transactions_metadata,
+                        block_info,
+                        limits,
+                        quarantine_bad_tx,
+                    )?;
                     let append = AppendBlock {
                         block_id: id,
                         height,
                         timestamp,
                         is_microblock,
                         transactions,
+                        quarantined,
                     };
-                    Ok(BlockchainUpdate::Append(append))
+                    Ok(Some(BlockchainUpdate::Append(append)))
                 }
                 Some(Update::Rollback(_)) => {
                     let rollback_to_block_id = base58(&src.id);
                     let rollback = Rollback {
                         block_id: rollback_to_block_id,
                     };
-                    Ok(BlockchainUpdate::Rollback(rollback))
+                    Ok(Some(BlockchainUpdate::Rollback(rollback)))
                 }
-                _ => Err(ConvertError("failed to parse blockchain update")),
+                _ => Err(error("failed to parse blockchain update").soft()),
             }
         }
 
@@ -232,71 +529,234 @@ mod updates_impl {
             timestamp: Option<u64>, // Not usable, only present for full blocks
         }
 
+        /// Converts the transactions that are operations and skips the rest,
+        /// so the result is empty for a block without operations (which is fine).
+        ///
+        /// With `quarantine` the transactions that fail to convert are returned separately,
+        /// otherwise the first failure fails the whole block.
         fn convert_transactions(
             transaction_ids: Vec<Vec<u8>>,
             transactions: Vec<SignedTransaction>,
             transactions_metadata: Vec<TransactionMetadata>,
             block_info: BlockInfo,
-        ) -> Result<Vec<Transaction>, ConvertError> {
+            limits: &ConvertLimits,
+            quarantine: bool,
+        ) -> Result<(Vec<Transaction>, Vec<QuarantinedTransaction>), ConvertError> {
             let ids = transaction_ids.into_iter();
             let txs = transactions.into_iter();
             let met = transactions_metadata.into_iter();
             let iter = ids.zip(txs).zip(met);
-            iter.filter_map(|((id, tx), meta)| convert_tx(id, tx, meta, &block_info).transpose())
-                .collect()
+            let mut converted = Vec::new();
+            let mut quarantined = Vec::new();
+            for ((id, tx), meta) in iter {
+                let id = base58(&id);
+                match convert_tx(&id, &tx, &meta, &block_info, limits) {
+                    Ok(Some(tx)) => converted.push(tx),
+                    Ok(None) => {}
+                    Err(err) => {
+                        let err = err.at_height(block_info.height).in_tx(&id);
+                        if !quarantine {
+                            return Err(err);
+                        }
+                        log::error!("Transaction quarantined: {}", err);
+                        CONVERSION_ERRORS.with_label_values(&[err.reason()]).inc();
+                        quarantined.push(QuarantinedTransaction {
+                            id,
+                            raw: tx.encode_to_vec(),
+                            reason: err.to_string(),
+                        });
+                    }
+                }
+            }
+            Ok((converted, quarantined))
         }
 
         fn convert_tx(
-            id: Vec<u8>,
-            tx: SignedTransaction,
-            meta: TransactionMetadata,
+            id: &str,
+            tx: &SignedTransaction,
+            meta: &TransactionMetadata,
             block_info: &BlockInfo,
+            limits: &ConvertLimits,
         ) -> Result<Option<Transaction>, ConvertError> {
-            let tx = match extract_op_type(&meta) {
-                Some(op_type @ OperationType::InvokeScript) => {
-                    let tx_type = extract_tx_type(&meta).ok_or(ConvertError("missing tx type"))?;
-                    let tx_data = extract_transaction_data(&tx, &meta).ok_or(ConvertError("missing tx data"))?;
-                    let invoke_script_data = extract_invoke_script_data(&tx, &meta)?;
-                    Transaction {
-                        id: base58(&id),
-                        op_type,
-                        tx_type,
-                        height: block_info.height,
-                        timestamp: convert_timestamp(tx_data.get_timestamp()),
-                        //block_timestamp: convert_timestamp(block_info.timestamp.unwrap_or_default()), //TODO unusable
-                        fee: tx_data.get_fee().ok_or(ConvertError("fee"))?,
-                        sender: base58(&meta.sender_address),
-                        sender_public_key: base58(tx_data.get_sender_public_key()),
-                        proofs: tx.proofs.iter().map(|p| base58(p)).collect_vec(),
+            let op_type = match extract_op_type(tx, meta) {
+                Some(op_type) => op_type,
+                None => return Ok(None),
+            };
+
+            let data = match op_type {
+                OperationType::InvokeScript => {
+                    let invoke_script_data = extract_invoke_script_data(tx, meta)?;
+                    let payment = limit_items(
+                        id,
+                        invoke_script_data.get_payments(),
+                        limits.max_payments,
+                        limits.reject_over_limit,
+                        "payments",
+                    )?;
+                    let mut call = invoke_script_data.get_call(limits.max_arg_depth, limits.binary_encoding)?;
+                    call.args = limit_items(id, call.args, limits.max_args, limits.reject_over_limit, "args")?;
+                    OperationData::InvokeScript(InvokeScript {
                         dapp: base58(&invoke_script_data.meta.d_app_address),
-                        payment: invoke_script_data.get_payments(),
-                        call: invoke_script_data.get_call()?,
-                    }
+                        payment,
+                        call,
+                    })
+                }
+                OperationType::Lease => {
+                    let (lease_data, lease_meta) = extract_lease_data(tx, meta)?;
+                    OperationData::Lease(Lease {
+                        recipient: base58(&lease_meta.recipient_address),
+                        amount: Amount::new(lease_data.amount, None),
+                    })
+                }
+                OperationType::LeaseCancel => {
+                    let lease_cancel_data = match waves_tx_data(tx) {
+                        Some(WavesTxData::LeaseCancel(data)) => data,
+                        _ => return Err(ConvertError::new("unexpected LeaseCancel transaction contents")),
+                    };
+                    OperationData::LeaseCancel(LeaseCancel {
+                        lease_id: base58(&lease_cancel_data.lease_id),
+                    })
+                }
+                OperationType::DataTransaction => {
+                    let data_tx_data = match waves_tx_data(tx) {
+                        Some(WavesTxData::DataTransaction(data)) => data,
+                        _ => return Err(ConvertError::new("unexpected DataTransaction transaction contents")),
+                    };
+                    OperationData::DataTransaction(DataTransaction {
+                        data: data_tx_data
+                            .data
+                            .iter()
+                            .map(|entry| convert_data_entry(entry, limits.binary_encoding))
+                            .collect_vec(),
+                    })
+                }
+                OperationType::Exchange => {
+                    let exchange_data = match waves_tx_data(tx) {
+                        Some(WavesTxData::Exchange(data)) => data,
+                        _ => return Err(ConvertError::new("unexpected Exchange transaction contents")),
+                    };
+                    OperationData::Exchange(convert_exchange(exchange_data)?)
                 }
-                None => return Ok(None),
+            };
+
+            check_proofs(id, &tx.proofs);
+
+            let tx_type = extract_tx_type(tx, meta).ok_or(ConvertError::new("missing tx type"))?;
+            let tx_data = extract_transaction_data(tx, meta).ok_or(ConvertError::new("missing tx data"))?;
+            let tx = Transaction {
+                schema_version: SCHEMA_VERSION,
+                id: id.to_owned(),
+                op_type,
+                tx_type,
+                height: block_info.height,
+                timestamp: convert_timestamp(tx_data.get_timestamp())?,
+                //block_timestamp: convert_timestamp(block_info.timestamp.unwrap_or_default()), //TODO unusable
+                fee: tx_data.get_fee().ok_or(ConvertError::new("fee"))?,
+                sender: base58(&meta.sender_address),
+                sender_public_key: base58(tx_data.get_sender_public_key()),
+                proofs: tx.proofs.iter().map(|p| base58(p)).collect_vec(),
+                data,
             };
 
             Ok(Some(tx))
         }
 
-        fn extract_op_type(meta: &TransactionMetadata) -> Option<OperationType> {
-            match meta.metadata {
-                Some(Metadata::InvokeScript(_)) => Some(OperationType::InvokeScript),
-                Some(Metadata::Ethereum(EthereumMetadata {
-                    action: Some(Action::Invoke(_)),
-                    ..
-                })) => Some(OperationType::InvokeScript),
+        /// Keep only the first `max` items, or fail with `reject`.
+        /// `what` is the metric label of the truncated list, `args` or `payments`.
+        fn limit_items<T>(
+            id: &str,
+            mut items: Vec<T>,
+            max: Option<usize>,
+            reject: bool,
+            what: &'static str,
+        ) -> Result<Vec<T>, ConvertError> {
+            let max = match max {
+                Some(max) if items.len() > max => max,
+                _ => return Ok(items),
+            };
+            if reject {
+                return Err(ConvertError::new(match what {
+                    "args" => "too many invoke script arguments",
+                    _ => "too many invoke script payments",
+                }));
+            }
+            TRUNCATED_TRANSACTIONS.with_label_values(&[what]).inc();
+            log::warn!(
+                "Transaction {} has {} {}, only the first {} are stored",
+                id,
+                items.len(),
+                what,
+                max
+            );
+            items.truncate(max);
+            Ok(items)
+        }
+
+        /// Length of a Curve25519 signature, which is what the proofs of the most transactions are
+        const SIGNATURE_LENGTH: usize = 64;
+
+        /// Report the proofs that don't look like signatures, or duplicate ones.
+        /// Such proofs can be legit (smart accounts accept arbitrary proofs), so the transaction is kept as is,
+        /// even the duplicates are not removed because scripts refer to the proofs by index.
+        fn check_proofs(id: &str, proofs: &[Vec<u8>]) {
+            let odd_length = proofs.iter().find(|p| p.len() != SIGNATURE_LENGTH);
+            let has_duplicates = !proofs.iter().all_unique();
+            if odd_length.is_none() && !has_duplicates {
+                return;
+            }
+            ANOMALOUS_PROOFS.inc();
+            if let Some(proof) = odd_length {
+                log::warn!(
+                    "Transaction {} has a proof of {} bytes, expected {}",
+                    id,
+                    proof.len(),
+                    SIGNATURE_LENGTH
+                );
+            }
+            if has_duplicates {
+                log::warn!("Transaction {} has duplicate proofs", id);
+            }
+        }
+
+        fn waves_tx_data(tx: &SignedTransaction) -> Option<&WavesTxData> {
+            match &tx.transaction {
+                Some(TransactionEnum::WavesTransaction(WavesTransaction { data, .. })) => data.as_ref(),
                 _ => None,
             }
         }
 
-        fn extract_tx_type(meta: &TransactionMetadata) -> Option<TransactionType> {
-            match meta.metadata {
-                Some(Metadata::InvokeScript(_)) => Some(TransactionType::InvokeScript),
-                Some(Metadata::Ethereum(EthereumMetadata {
-                    action: Some(Action::Invoke(_)),
-                    ..
-                })) => Some(TransactionType::EthereumTransaction),
+        fn extract_op_type(tx: &SignedTransaction, meta: &TransactionMetadata) -> Option<OperationType> {
+            match (&meta.metadata, waves_tx_data(tx)) {
+                (Some(Metadata::InvokeScript(_)), _) => Some(OperationType::InvokeScript),
+                (
+                    Some(Metadata::Ethereum(EthereumMetadata {
+                        action: Some(Action::Invoke(_)),
+                        ..
+                    })),
+                    _,
+                ) => Some(OperationType::InvokeScript),
+                (_, Some(WavesTxData::Lease(_))) => Some(OperationType::Lease),
+                (_, Some(WavesTxData::LeaseCancel(_))) => Some(OperationType::LeaseCancel),
+                (_, Some(WavesTxData::DataTransaction(_))) => Some(OperationType::DataTransaction),
+                (_, Some(WavesTxData::Exchange(_))) => Some(OperationType::Exchange),
+                _ => None,
+            }
+        }
+
+        fn extract_tx_type(tx: &SignedTransaction, meta: &TransactionMetadata) -> Option<TransactionType> {
+            match (&meta.metadata, waves_tx_data(tx)) {
+                (Some(Metadata::InvokeScript(_)), _) => Some(TransactionType::InvokeScript),
+                (
+                    Some(Metadata::Ethereum(EthereumMetadata {
+                        action: Some(Action::Invoke(_)),
+                        ..
+                    })),
+                    _,
+                ) => Some(TransactionType::EthereumTransaction),
+                (_, Some(WavesTxData::Lease(_))) => Some(TransactionType::Lease),
+                (_, Some(WavesTxData::LeaseCancel(_))) => Some(TransactionType::LeaseCancel),
+                (_, Some(WavesTxData::DataTransaction(_))) => Some(TransactionType::DataTransaction),
+                (_, Some(WavesTxData::Exchange(_))) => Some(TransactionType::Exchange),
                 _ => None,
             }
         }
@@ -324,7 +784,7 @@ mod updates_impl {
                     ..
                 })) => Some(data),
                 Some(TransactionEnum::EthereumTransaction(_)) => None,
-                _ => return Err(ConvertError("unexpected InvokeScript transaction contents")),
+                _ => return Err(ConvertError::new("unexpected InvokeScript transaction contents")),
             };
 
             let meta = match &meta.metadata {
@@ -333,12 +793,29 @@ mod updates_impl {
                     action: Some(Action::Invoke(meta)),
                     ..
                 })) => meta,
-                _ => return Err(ConvertError("unexpected InvokeScript metadata contents")),
+                _ => return Err(ConvertError::new("unexpected InvokeScript metadata contents")),
             };
 
             Ok(InvokeScriptData { waves_data, meta })
         }
 
+        fn extract_lease_data<'a>(
+            tx: &'a SignedTransaction,
+            meta: &'a TransactionMetadata,
+        ) -> Result<(&'a LeaseTransactionData, &'a LeaseMetadata), ConvertError> {
+            let data = match waves_tx_data(tx) {
+                Some(WavesTxData::Lease(data)) => data,
+                _ => return Err(ConvertError::new("unexpected Lease transaction contents")),
+            };
+
+            let meta = match &meta.metadata {
+                Some(Metadata::Lease(meta)) => meta,
+                _ => return Err(ConvertError::new("unexpected Lease metadata contents")),
+            };
+
+            Ok((data, meta))
+        }
+
         enum TransactionData<'a> {
             Waves(&'a WavesTransaction),
             Ethereum(&'a EthereumMetadata),
@@ -383,23 +860,31 @@ mod updates_impl {
                 payments.iter().map(convert_amount).collect_vec()
             }
 
-            fn get_call(&self) -> Result<Call, ConvertError> {
+            /// Max depth of 1 means only top-level arguments, no lists in lists
+            fn get_call(&self, max_arg_depth: usize, encoding: BinaryEncoding) -> Result<Call, ConvertError> {
                 let function = self.meta.function_name.clone();
-                let args = convert_args(&self.meta.arguments)?;
+                let args = convert_args(&self.meta.arguments, max_arg_depth, encoding)?;
 
-                fn convert_args(args: &[Argument]) -> Result<Vec<Arg>, ConvertError> {
+                fn convert_args(
+                    args: &[Argument],
+                    depth_left: usize,
+                    encoding: BinaryEncoding,
+                ) -> Result<Vec<Arg>, ConvertError> {
+                    if depth_left == 0 {
+                        return Err(ConvertError::new("argument nesting too deep"));
+                    }
                     args.iter()
                         .map(|arg| {
                             arg.value
                                 .as_ref()
-                                .ok_or(ConvertError("missing argument"))
+                                .ok_or(ConvertError::new("missing argument"))
                                 .map(|arg| match arg {
                                     Value::IntegerValue(v) => Ok(Arg::Integer(*v)),
-                                    Value::BinaryValue(v) => Ok(Arg::Binary(base64(v))),
+                                    Value::BinaryValue(v) => Ok(Arg::Binary(binary(v, encoding))),
                                     Value::StringValue(v) => Ok(Arg::String(fix_unicode_string(v))),
                                     Value::BooleanValue(v) => Ok(Arg::Boolean(*v)),
-                                    Value::CaseObj(v) => Ok(Arg::CaseObj(base64(v))),
-                                    Value::List(vv) => convert_args(&vv.items).map(Arg::List),
+                                    Value::CaseObj(v) => Ok(Arg::CaseObj(binary(v, encoding))),
+                                    Value::List(vv) => convert_args(&vv.items, depth_left - 1, encoding).map(Arg::List),
                                 })
                                 .and_then(|r| r)
                         })
@@ -410,6 +895,54 @@ mod updates_impl {
             }
         }
 
+        fn convert_data_entry(entry: &DataEntry, encoding: BinaryEncoding) -> ModelDataEntry {
+            let value = match &entry.value {
+                Some(DataEntryValue::IntValue(v)) => DataValue::Integer(*v),
+                Some(DataEntryValue::BoolValue(v)) => DataValue::Boolean(*v),
+                Some(DataEntryValue::BinaryValue(v)) => DataValue::Binary(binary(v, encoding)),
+                Some(DataEntryValue::StringValue(v)) => DataValue::String(fix_unicode_string(v)),
+                None => DataValue::Delete,
+            };
+            ModelDataEntry {
+                key: fix_unicode_string(&entry.key),
+                value,
+            }
+        }
+
+        fn convert_exchange(data: &ExchangeTransactionData) -> Result<Exchange, ConvertError> {
+            let (order1, order2) = match data.orders.as_slice() {
+                [order1, order2] => (convert_order(order1)?, convert_order(order2)?),
+                _ => return Err(ConvertError::new("exchange must have exactly two orders")),
+            };
+            Ok(Exchange {
+                amount: data.amount,
+                price: data.price,
+                order1,
+                order2,
+            })
+        }
+
+        fn convert_order(order: &WavesOrder) -> Result<Order, ConvertError> {
+            let asset_pair = order
+                .asset_pair
+                .as_ref()
+                .ok_or(ConvertError::new("missing order asset pair"))?;
+            let side = match WavesOrderSide::try_from(order.order_side) {
+                Ok(WavesOrderSide::Buy) => OrderSide::Buy,
+                Ok(WavesOrderSide::Sell) => OrderSide::Sell,
+                Err(_) => return Err(ConvertError::new("unknown order side")),
+            };
+            Ok(Order {
+                side,
+                asset_pair: AssetPair {
+                    amount_asset: convert_asset_id(&asset_pair.amount_asset_id),
+                    price_asset: convert_asset_id(&asset_pair.price_asset_id),
+                },
+                amount: order.amount,
+                price: order.price,
+            })
+        }
+
         fn convert_amount(a: &WavesAmount) -> Amount {
             let amount = a.amount;
             let asset_id = if a.asset_id.is_empty() {
@@ -420,22 +953,70 @@ mod updates_impl {
             Amount::new(amount, asset_id)
         }
 
-        fn convert_timestamp(ts: u64) -> String {
+        /// Empty asset id means Waves
+        fn convert_asset_id(asset_id: &[u8]) -> String {
+            if asset_id.is_empty() {
+                Amount::WAVES_ASSET_ID.to_owned()
+            } else {
+                base58(asset_id)
+            }
+        }
+
+        /// Zero timestamp is never valid, so it is an error instead of the misleading 1970-01-01,
+        /// as well as a timestamp out of the supported date range
+        fn convert_timestamp(ts: u64) -> Result<String, ConvertError> {
             use chrono::{SecondsFormat, TimeZone, Utc};
-            Utc.timestamp_millis_opt(ts as i64)
+            if ts == 0 {
+                return Err(ConvertError::new("transaction timestamp is zero"));
+            }
+            let ts = i64::try_from(ts).map_err(|_| ConvertError::new("invalid timestamp"))?;
+            let timestamp = Utc
+                .timestamp_millis_opt(ts)
                 .single()
-                .expect("timestamp")
-                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .ok_or(ConvertError::new("invalid timestamp"))?
+                .to_rfc3339_opts(SecondsFormat::Millis, true);
+            Ok(timestamp)
         }
 
+        /// Encodes into a reused thread-local buffer, so the only allocation is the exactly sized result
+        /// (`into_string` allocates for the worst case, about 1.4 times the input length, for every string).
         fn base58(bytes: &[u8]) -> String {
-            bs58::encode(bytes).into_string()
+            thread_local! {
+                static BUF: RefCell<String> = const { RefCell::new(String::new()) };
+            }
+            BUF.with(|buf| {
+                let mut buf = buf.borrow_mut();
+                buf.clear();
+                bs58::encode(bytes).onto(&mut *buf).expect("a string fits any length");
+                buf.as_str().to_owned()
+            })
+        }
+
+        fn binary(bytes: &[u8], encoding: BinaryEncoding) -> String {
+            match encoding {
+                BinaryEncoding::Base64 => base64(bytes),
+                BinaryEncoding::Hex => hex(bytes),
+            }
+        }
+
+        /// Makes exactly one allocation, the output is pre-sized precisely.
+        fn hex(bytes: &[u8]) -> String {
+            const DIGITS: &[u8; 16] = b"0123456789abcdef";
+            let mut buf = String::with_capacity(2 * bytes.len());
+            for &b in bytes {
+                buf.push(DIGITS[(b >> 4) as usize] as char);
+                buf.push(DIGITS[(b & 0x0f) as usize] as char);
+            }
+            buf
         }
 
+        /// Makes exactly one allocation, the output is pre-sized precisely.
         fn base64(bytes: &[u8]) -> String {
             use base64::engine::{general_purpose::STANDARD, Engine};
-            let mut buf = String::with_capacity(6 + 4 * (bytes.len() + 2) / 3);
-            buf.push_str("base64:");
+            const PREFIX: &str = "base64:";
+            let encoded_len = 4 * bytes.len().div_ceil(3); // With padding
+            let mut buf = String::with_capacity(PREFIX.len() + encoded_len);
+            buf.push_str(PREFIX);
             STANDARD.encode_string(bytes, &mut buf);
             buf
         }
@@ -481,5 +1062,994 @@ mod updates_impl {
 
             String::from_utf16(&data16).map_err(|_| ())
         }
+
+        #[cfg(test)]
+        mod tests {
+            use waves_protobuf_schemas::waves::{
+                block::Header, invoke_script_result::call::argument::List as ArgumentList, AssetPair,
+                DataTransactionData, LeaseCancelTransactionData, TransferTransactionData,
+            };
+
+            use super::*;
+
+            const LIMITS: ConvertLimits = ConvertLimits {
+                max_arg_depth: 10,
+                max_args: None,
+                max_payments: None,
+                reject_over_limit: false,
+                binary_encoding: BinaryEncoding::Base64,
+            };
+
+            fn signed_tx(data: WavesTxData) -> SignedTransaction {
+                SignedTransaction {
+                    transaction: Some(TransactionEnum::WavesTransaction(WavesTransaction {
+                        chain_id: 'W' as i32,
+                        sender_public_key: vec![1; 32],
+                        fee: Some(WavesAmount {
+                            asset_id: vec![],
+                            amount: 100_000,
+                        }),
+                        timestamp: 1_600_000_000_000,
+                        version: 3,
+                        data: Some(data),
+                    })),
+                    proofs: vec![vec![2; 64]],
+                }
+            }
+
+            fn tx_meta(metadata: Option<Metadata>) -> TransactionMetadata {
+                TransactionMetadata {
+                    sender_address: vec![3; 26],
+                    metadata,
+                }
+            }
+
+            /// Converts the transaction and returns its JSON as stored in the database
+            fn convert(tx: &SignedTransaction, meta: &TransactionMetadata) -> serde_json::Value {
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let tx = convert_tx("tx1", tx, meta, &block_info, &LIMITS)
+                    .expect("conversion failed")
+                    .expect("not an operation");
+                serde_json::to_value(tx).unwrap()
+            }
+
+            #[test]
+            fn lease() {
+                let tx = signed_tx(WavesTxData::Lease(LeaseTransactionData {
+                    recipient: None,
+                    amount: 12_345,
+                }));
+                let meta = tx_meta(Some(Metadata::Lease(LeaseMetadata {
+                    recipient_address: vec![4; 26],
+                })));
+
+                let json = convert(&tx, &meta);
+                assert_eq!(json["type"], "lease");
+                assert_eq!(json["origin_transaction_type"], 8);
+                assert_eq!(json["height"], 42);
+                assert_eq!(json["sender"], base58(&[3; 26]));
+                assert_eq!(json["recipient"], base58(&[4; 26]));
+                assert_eq!(json["amount"], serde_json::json!({ "amount": 12_345, "id": "WAVES" }));
+                assert_eq!(json["fee"], serde_json::json!({ "amount": 100_000, "id": "WAVES" }));
+            }
+
+            /// Invoke with a single argument, lists nested so that the integer is at the given depth
+            fn invoke_nested(depth: usize) -> (SignedTransaction, TransactionMetadata) {
+                let mut arg = Argument {
+                    value: Some(Value::IntegerValue(1)),
+                };
+                for _ in 1..depth {
+                    arg = Argument {
+                        value: Some(Value::List(ArgumentList { items: vec![arg] })),
+                    };
+                }
+                let tx = signed_tx(WavesTxData::InvokeScript(InvokeScriptTransactionData {
+                    d_app: None,
+                    function_call: vec![],
+                    payments: vec![],
+                }));
+                let meta = tx_meta(Some(Metadata::InvokeScript(InvokeScriptMetadata {
+                    d_app_address: vec![4; 26],
+                    function_name: "call".to_owned(),
+                    arguments: vec![arg],
+                    ..Default::default()
+                })));
+                (tx, meta)
+            }
+
+            #[test]
+            fn nested_arguments() {
+                let (tx, meta) = invoke_nested(LIMITS.max_arg_depth);
+                let json = convert(&tx, &meta);
+                let mut arg = &json["call"]["args"][0];
+                for _ in 1..LIMITS.max_arg_depth {
+                    assert_eq!(arg["type"], "list");
+                    arg = &arg["value"][0];
+                }
+                assert_eq!(arg, &serde_json::json!({ "type": "integer", "value": 1 }));
+
+                let (tx, meta) = invoke_nested(LIMITS.max_arg_depth + 1);
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let result = convert_tx("tx1", &tx, &meta, &block_info, &LIMITS);
+                assert_eq!(result.unwrap_err().reason(), "argument nesting too deep");
+            }
+
+            #[test]
+            fn args_and_payments_over_the_limit() {
+                let payments = (1..=3).map(|amount| WavesAmount {
+                    asset_id: vec![],
+                    amount,
+                });
+                let payments = payments.collect::<Vec<_>>();
+                let tx = signed_tx(WavesTxData::InvokeScript(InvokeScriptTransactionData {
+                    d_app: None,
+                    function_call: vec![],
+                    payments: payments.clone(),
+                }));
+                let args = (1..=3).map(|i| Argument {
+                    value: Some(Value::IntegerValue(i)),
+                });
+                let meta = tx_meta(Some(Metadata::InvokeScript(InvokeScriptMetadata {
+                    d_app_address: vec![4; 26],
+                    function_name: "call".to_owned(),
+                    arguments: args.collect(),
+                    payments,
+                    ..Default::default()
+                })));
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let limits = |max_args, max_payments, reject_over_limit| ConvertLimits {
+                    max_args,
+                    max_payments,
+                    reject_over_limit,
+                    ..LIMITS
+                };
+                let convert = |limits| convert_tx("tx1", &tx, &meta, &block_info, &limits).map(Option::unwrap);
+
+                // At the limit
+                let json = serde_json::to_value(convert(limits(Some(3), Some(3), true)).unwrap()).unwrap();
+                assert_eq!(json["call"]["args"].as_array().unwrap().len(), 3);
+                assert_eq!(json["payment"].as_array().unwrap().len(), 3);
+
+                let truncated_args = TRUNCATED_TRANSACTIONS.with_label_values(&["args"]);
+                let truncated_payments = TRUNCATED_TRANSACTIONS.with_label_values(&["payments"]);
+                let (args_before, payments_before) = (truncated_args.get(), truncated_payments.get());
+                let json = serde_json::to_value(convert(limits(Some(2), Some(1), false)).unwrap()).unwrap();
+                assert_eq!(json["call"]["args"][1]["value"], 2);
+                assert_eq!(json["call"]["args"].as_array().unwrap().len(), 2);
+                assert_eq!(json["payment"], serde_json::json!([{ "amount": 1, "id": "WAVES" }]));
+                assert_eq!(truncated_args.get(), args_before + 1);
+                assert_eq!(truncated_payments.get(), payments_before + 1);
+
+                let error = convert(limits(Some(2), None, true)).unwrap_err();
+                assert_eq!(error.reason(), "too many invoke script arguments");
+                let error = convert(limits(None, Some(2), true)).unwrap_err();
+                assert_eq!(error.reason(), "too many invoke script payments");
+                // Rejected ones are not counted as truncated
+                assert_eq!(truncated_args.get(), args_before + 1);
+                assert_eq!(truncated_payments.get(), payments_before + 1);
+            }
+
+            fn order(side: WavesOrderSide, amount: i64, price: i64) -> WavesOrder {
+                WavesOrder {
+                    asset_pair: Some(AssetPair {
+                        amount_asset_id: vec![5; 32],
+                        price_asset_id: vec![],
+                    }),
+                    order_side: side as i32,
+                    amount,
+                    price,
+                    ..Default::default()
+                }
+            }
+
+            fn exchange(orders: Vec<WavesOrder>) -> SignedTransaction {
+                signed_tx(WavesTxData::Exchange(ExchangeTransactionData {
+                    amount: 100,
+                    price: 250,
+                    buy_matcher_fee: 300_000,
+                    sell_matcher_fee: 300_000,
+                    orders,
+                }))
+            }
+
+            #[test]
+            fn exchange_orders() {
+                let tx = exchange(vec![
+                    order(WavesOrderSide::Buy, 150, 260),
+                    order(WavesOrderSide::Sell, 100, 250),
+                ]);
+                let json = convert(&tx, &tx_meta(None));
+                assert_eq!(json["type"], "exchange");
+                assert_eq!(json["origin_transaction_type"], 7);
+                assert_eq!(
+                    (&json["amount"], &json["price"]),
+                    (&serde_json::json!(100), &serde_json::json!(250))
+                );
+                let asset_pair = serde_json::json!({ "amount_asset": base58(&[5; 32]), "price_asset": "WAVES" });
+                assert_eq!(
+                    json["order1"],
+                    serde_json::json!({ "side": "buy", "asset_pair": asset_pair, "amount": 150, "price": 260 })
+                );
+                assert_eq!(
+                    json["order2"],
+                    serde_json::json!({ "side": "sell", "asset_pair": asset_pair, "amount": 100, "price": 250 })
+                );
+
+                // Sell order first, the orders are kept in the transaction order
+                let tx = exchange(vec![
+                    order(WavesOrderSide::Sell, 100, 250),
+                    order(WavesOrderSide::Buy, 150, 260),
+                ]);
+                let json = convert(&tx, &tx_meta(None));
+                assert_eq!(
+                    (&json["order1"]["side"], &json["order2"]["side"]),
+                    (&"sell".into(), &"buy".into())
+                );
+            }
+
+            #[test]
+            fn invalid_exchange() {
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let reason = |tx| {
+                    convert_tx("tx1", &tx, &tx_meta(None), &block_info, &LIMITS)
+                        .unwrap_err()
+                        .reason()
+                };
+
+                let one_order = exchange(vec![order(WavesOrderSide::Buy, 150, 260)]);
+                assert_eq!(reason(one_order), "exchange must have exactly two orders");
+                let mut unknown_side = order(WavesOrderSide::Sell, 100, 250);
+                unknown_side.order_side = 7;
+                let tx = exchange(vec![order(WavesOrderSide::Buy, 150, 260), unknown_side]);
+                assert_eq!(reason(tx), "unknown order side");
+                let mut no_pair = order(WavesOrderSide::Sell, 100, 250);
+                no_pair.asset_pair = None;
+                let tx = exchange(vec![order(WavesOrderSide::Buy, 150, 260), no_pair]);
+                assert_eq!(reason(tx), "missing order asset pair");
+            }
+
+            #[test]
+            fn lease_without_metadata() {
+                let tx = signed_tx(WavesTxData::Lease(LeaseTransactionData {
+                    recipient: None,
+                    amount: 12_345,
+                }));
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let result = convert_tx("tx1", &tx, &tx_meta(None), &block_info, &LIMITS);
+                assert_eq!(result.unwrap_err().reason(), "unexpected Lease metadata contents");
+            }
+
+            #[test]
+            fn data_transaction() {
+                let entry = |key: &str, value| DataEntry {
+                    key: key.to_owned(),
+                    value,
+                };
+                let tx = signed_tx(WavesTxData::DataTransaction(DataTransactionData {
+                    data: vec![
+                        entry("int", Some(DataEntryValue::IntValue(-7))),
+                        entry("bool", Some(DataEntryValue::BoolValue(true))),
+                        entry("bin", Some(DataEntryValue::BinaryValue(vec![1, 2, 3]))),
+                        entry("str", Some(DataEntryValue::StringValue("text".to_owned()))),
+                        entry("deleted", None),
+                    ],
+                }));
+
+                let json = convert(&tx, &tx_meta(None));
+                assert_eq!(json["type"], "data_transaction");
+                assert_eq!(json["origin_transaction_type"], 12);
+                assert_eq!(
+                    json["data"],
+                    serde_json::json!([
+                        { "key": "int", "type": "integer", "value": -7 },
+                        { "key": "bool", "type": "boolean", "value": true },
+                        { "key": "bin", "type": "binary", "value": "base64:AQID" },
+                        { "key": "str", "type": "string", "value": "text" },
+                        { "key": "deleted", "type": "delete" },
+                    ])
+                );
+            }
+
+            #[test]
+            fn binary_encodings() {
+                let arg = |value| Argument { value: Some(value) };
+                let tx = signed_tx(WavesTxData::InvokeScript(InvokeScriptTransactionData {
+                    d_app: None,
+                    function_call: vec![],
+                    payments: vec![],
+                }));
+                let meta = tx_meta(Some(Metadata::InvokeScript(InvokeScriptMetadata {
+                    d_app_address: vec![4; 26],
+                    function_name: "call".to_owned(),
+                    arguments: vec![
+                        arg(Value::BinaryValue(vec![1, 2, 255])),
+                        arg(Value::CaseObj(vec![10, 11])),
+                        arg(Value::List(ArgumentList {
+                            items: vec![arg(Value::BinaryValue(vec![]))],
+                        })),
+                    ],
+                    ..Default::default()
+                })));
+                let data_tx = signed_tx(WavesTxData::DataTransaction(DataTransactionData {
+                    data: vec![DataEntry {
+                        key: "bin".to_owned(),
+                        value: Some(DataEntryValue::BinaryValue(vec![1, 2, 255])),
+                    }],
+                }));
+                let convert_with = |tx: &SignedTransaction, meta: &TransactionMetadata, binary_encoding| {
+                    let block_info = BlockInfo {
+                        height: 42,
+                        timestamp: None,
+                    };
+                    let limits = ConvertLimits {
+                        binary_encoding,
+                        ..LIMITS
+                    };
+                    let tx = convert_tx("tx1", tx, meta, &block_info, &limits).unwrap().unwrap();
+                    serde_json::to_value(tx).unwrap()
+                };
+
+                let json = convert_with(&tx, &meta, BinaryEncoding::Base64);
+                let expected = serde_json::json!([
+                    { "type": "binary", "value": "base64:AQL/" },
+                    { "type": "case_obj", "value": "base64:Cgs=" },
+                    { "type": "list", "value": [{ "type": "binary", "value": "base64:" }] },
+                ]);
+                assert_eq!(json["call"]["args"], expected);
+                let json = convert_with(&data_tx, &tx_meta(None), BinaryEncoding::Base64);
+                assert_eq!(json["data"][0]["value"], "base64:AQL/");
+
+                let json = convert_with(&tx, &meta, BinaryEncoding::Hex);
+                let expected = serde_json::json!([
+                    { "type": "binary", "value": "0102ff" },
+                    { "type": "case_obj", "value": "0a0b" },
+                    { "type": "list", "value": [{ "type": "binary", "value": "" }] },
+                ]);
+                assert_eq!(json["call"]["args"], expected);
+                let json = convert_with(&data_tx, &tx_meta(None), BinaryEncoding::Hex);
+                assert_eq!(json["data"][0]["value"], "0102ff");
+            }
+
+            fn transfer() -> (SignedTransaction, TransactionMetadata) {
+                let data = WavesTxData::Transfer(TransferTransactionData {
+                    recipient: None,
+                    amount: None,
+                    attachment: vec![],
+                });
+                (signed_tx(data), tx_meta(None))
+            }
+
+            fn lease_cancel_tx(lease_id: u8) -> (SignedTransaction, TransactionMetadata) {
+                let data = WavesTxData::LeaseCancel(LeaseCancelTransactionData {
+                    lease_id: vec![lease_id; 32],
+                });
+                (signed_tx(data), tx_meta(None))
+            }
+
+            /// Key block at the height with the given transactions, their ids are 1, 2, 3...
+            fn block_update(height: i32, txs: Vec<(SignedTransaction, TransactionMetadata)>) -> BlockchainUpdated {
+                let (transactions, transactions_metadata): (Vec<_>, Vec<_>) = txs.into_iter().unzip();
+                let transaction_ids = (1..=transactions.len()).map(|i| vec![i as u8; 32]).collect();
+                BlockchainUpdated {
+                    id: vec![9; 64],
+                    height,
+                    update: Some(Update::Append(Append {
+                        body: Some(Body::Block(BlockAppend {
+                            block: Some(Block {
+                                header: Some(Header {
+                                    timestamp: 1_600_000_000_000,
+                                    ..Default::default()
+                                }),
+                                transactions,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        })),
+                        transaction_ids,
+                        transactions_metadata,
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }
+            }
+
+            fn convert_append(update: BlockchainUpdated) -> AppendBlock {
+                match convert_update(update, &LIMITS, false) {
+                    Ok(Some(BlockchainUpdate::Append(append))) => append,
+                    other => panic!("not an append: {:?}", other),
+                }
+            }
+
+            #[test]
+            fn block_without_operations() {
+                let append = convert_append(block_update(42, vec![transfer(), transfer()]));
+                assert_eq!(append.block_id, base58(&[9; 64]));
+                assert_eq!(append.height, 42);
+                assert_eq!(append.timestamp, Some(1_600_000_000_000));
+                assert!(!append.is_microblock);
+                assert!(append.transactions.is_empty());
+                assert!(append.quarantined.is_empty());
+            }
+
+            #[test]
+            fn block_with_some_operations() {
+                let txs = vec![
+                    transfer(),
+                    lease_cancel_tx(7),
+                    transfer(),
+                    transfer(),
+                    lease_cancel_tx(8),
+                ];
+                let append = convert_append(block_update(42, txs));
+                let ids = append.transactions.iter().map(|tx| tx.id.clone()).collect_vec();
+                assert_eq!(ids, [base58(&[2; 32]), base58(&[5; 32])]);
+                let lease_ids = append.transactions.iter().map(|tx| match &tx.data {
+                    OperationData::LeaseCancel(data) => data.lease_id.clone(),
+                    _ => panic!("not a lease cancel"),
+                });
+                assert_eq!(lease_ids.collect_vec(), [base58(&[7; 32]), base58(&[8; 32])]);
+                assert!(append.transactions.iter().all(|tx| tx.height == 42));
+            }
+
+            #[test]
+            fn error_names_transaction() {
+                let lease_without_metadata = signed_tx(WavesTxData::Lease(LeaseTransactionData {
+                    recipient: None,
+                    amount: 12_345,
+                }));
+                let txs = vec![lease_cancel_tx(7), (lease_without_metadata, tx_meta(None))];
+                let err = convert_update(block_update(42, txs), &LIMITS, false).unwrap_err();
+                assert_eq!(err.reason(), "unexpected Lease metadata contents");
+                let message = err.to_string();
+                assert!(message.contains(&base58(&[2; 32])), "{}", message);
+                assert!(message.contains("height 42"), "{}", message);
+            }
+
+            fn with_timestamp(mut tx: SignedTransaction, timestamp: i64) -> SignedTransaction {
+                if let Some(TransactionEnum::WavesTransaction(waves_tx)) = &mut tx.transaction {
+                    waves_tx.timestamp = timestamp;
+                }
+                tx
+            }
+
+            fn tx_error(tx: SignedTransaction) -> &'static str {
+                let block_info = BlockInfo {
+                    height: 42,
+                    timestamp: None,
+                };
+                let result = convert_tx("tx1", &tx, &tx_meta(None), &block_info, &LIMITS);
+                result.unwrap_err().reason()
+            }
+
+            #[test]
+            fn soft_errors() {
+                let without_body = BlockchainUpdated {
+                    update: Some(Update::Append(Append::default())),
+                    ..block_update(1, vec![])
+                };
+                let err = convert_update(without_body, &LIMITS, false).unwrap_err();
+                assert_eq!(err.reason(), "append body is None");
+                assert!(err.is_soft());
+                let without_update = BlockchainUpdated {
+                    update: None,
+                    ..block_update(1, vec![])
+                };
+                let err = convert_update(without_update, &LIMITS, false).unwrap_err();
+                assert_eq!(err.reason(), "failed to parse blockchain update");
+                assert!(err.is_soft());
+
+                // A transaction that fails to convert is not
+                let (tx, meta) = lease_cancel_tx(7);
+                let mut update = block_update(1, vec![(with_timestamp(tx, 0), meta)]);
+                let err = convert_update(update.clone(), &LIMITS, false).unwrap_err();
+                assert_eq!(err.reason(), "transaction timestamp is zero");
+                assert!(!err.is_soft());
+                if let Some(Update::Append(Append {
+                    body: Some(Body::Block(BlockAppend { block: Some(block), .. })),
+                    ..
+                })) = &mut update.update
+                {
+                    block.header.as_mut().unwrap().timestamp = 0;
+                }
+                assert!(!convert_update(update, &LIMITS, false).unwrap_err().is_soft());
+            }
+
+            #[test]
+            fn genesis_and_zero_timestamps() {
+                // A freshly started node may send an append before the genesis block
+                assert!(
+                    convert_update(block_update(0, vec![lease_cancel_tx(7)]), &LIMITS, false)
+                        .unwrap()
+                        .is_none()
+                );
+
+                let mut update = block_update(1, vec![]);
+                if let Some(Update::Append(Append {
+                    body: Some(Body::Block(BlockAppend { block: Some(block), .. })),
+                    ..
+                })) = &mut update.update
+                {
+                    block.header.as_mut().unwrap().timestamp = 0;
+                }
+                let err = convert_update(update, &LIMITS, false).unwrap_err();
+                assert_eq!(err.reason(), "block timestamp is zero");
+
+                let (tx, _) = lease_cancel_tx(7);
+                assert_eq!(tx_error(with_timestamp(tx, 0)), "transaction timestamp is zero");
+            }
+
+            #[test]
+            fn out_of_range_timestamp() {
+                let (tx, _) = lease_cancel_tx(7);
+                assert_eq!(tx_error(with_timestamp(tx.clone(), i64::MAX)), "invalid timestamp");
+                // Negative, which is out of the `u64` the timestamps are converted from
+                assert_eq!(tx_error(with_timestamp(tx.clone(), -1)), "invalid timestamp");
+
+                let json = convert(&with_timestamp(tx, 253_402_300_799_999), &tx_meta(None));
+                assert_eq!(json["timestamp"], "9999-12-31T23:59:59.999Z");
+            }
+
+            #[test]
+            fn bad_transaction_is_quarantined() {
+                let (bad, meta) = lease_cancel_tx(2);
+                let txs = vec![lease_cancel_tx(1), (with_timestamp(bad, 0), meta), lease_cancel_tx(3)];
+                assert!(convert_update(block_update(42, txs.clone()), &LIMITS, false).is_err());
+
+                let append = match convert_update(block_update(42, txs), &LIMITS, true) {
+                    Ok(Some(BlockchainUpdate::Append(append))) => append,
+                    other => panic!("not an append: {:?}", other),
+                };
+                let ids = append.transactions.iter().map(|tx| tx.id.clone()).collect::<Vec<_>>();
+                assert_eq!(ids, [base58(&[1; 32]), base58(&[3; 32])]);
+                assert_eq!(append.quarantined.len(), 1);
+                let quarantined = &append.quarantined[0];
+                assert_eq!(quarantined.id, base58(&[2; 32]));
+                assert!(quarantined.reason.contains("transaction timestamp is zero"));
+                let raw = SignedTransaction::decode(quarantined.raw.as_slice()).unwrap();
+                assert_eq!(raw, with_timestamp(lease_cancel_tx(2).0, 0));
+            }
+
+            #[test]
+            fn quarantined_errors_are_counted() {
+                let errors = |reason| CONVERSION_ERRORS.with_label_values(&[reason]).get();
+                let (invalid_timestamp, too_deep) = (errors("invalid timestamp"), errors("argument nesting too deep"));
+                let (bad, meta) = lease_cancel_tx(2);
+                let txs = vec![
+                    lease_cancel_tx(1),
+                    (with_timestamp(bad, i64::MAX), meta),
+                    invoke_nested(LIMITS.max_arg_depth + 1),
+                    invoke_nested(LIMITS.max_arg_depth + 2),
+                ];
+
+                // Without the quarantine the block fails, the error is counted where it is caught
+                assert!(convert_update(block_update(42, txs.clone()), &LIMITS, false).is_err());
+                assert_eq!(errors("invalid timestamp"), invalid_timestamp);
+
+                let append = match convert_update(block_update(42, txs), &LIMITS, true) {
+                    Ok(Some(BlockchainUpdate::Append(append))) => append,
+                    other => panic!("not an append: {:?}", other),
+                };
+                assert_eq!(append.quarantined.len(), 3);
+                assert_eq!(errors("invalid timestamp"), invalid_timestamp + 1);
+                assert_eq!(errors("argument nesting too deep"), too_deep + 2);
+            }
+
+            #[test]
+            fn anomalous_proofs_are_kept() {
+                let (mut tx, meta) = lease_cancel_tx(7);
+                let before = ANOMALOUS_PROOFS.get();
+                let json = convert(&tx, &meta);
+                assert_eq!(json["proofs"], serde_json::json!([base58(&[2; 64])]));
+                assert_eq!(ANOMALOUS_PROOFS.get(), before);
+
+                tx.proofs = vec![vec![2; 64], vec![4; 32]];
+                let json = convert(&tx, &meta);
+                assert_eq!(json["proofs"], serde_json::json!([base58(&[2; 64]), base58(&[4; 32])]));
+                assert_eq!(ANOMALOUS_PROOFS.get(), before + 1);
+
+                // Duplicates are not removed, scripts refer to the proofs by index
+                tx.proofs = vec![vec![2; 64], vec![2; 64]];
+                let json = convert(&tx, &meta);
+                assert_eq!(json["proofs"].as_array().unwrap().len(), 2);
+                assert_eq!(ANOMALOUS_PROOFS.get(), before + 2);
+            }
+
+            #[test]
+            fn lease_cancel() {
+                let tx = signed_tx(WavesTxData::LeaseCancel(LeaseCancelTransactionData {
+                    lease_id: vec![5; 32],
+                }));
+
+                let json = convert(&tx, &tx_meta(None));
+                assert_eq!(json["schema_version"], SCHEMA_VERSION);
+                assert_eq!(json["type"], "lease_cancel");
+                assert_eq!(json["origin_transaction_type"], 9);
+                assert_eq!(json["lease_id"], base58(&[5; 32]));
+                assert!(json.get("recipient").is_none());
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub mod test_server {
+        //! Blockchain updates server on a unix socket for the tests, answers every subscription with the same updates
+
+        use std::convert::Infallible;
+        use std::path::PathBuf;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use futures::{stream, StreamExt};
+        use tokio::net::UnixListener;
+        use waves_protobuf_schemas::tonic::codec::ProstCodec;
+        use waves_protobuf_schemas::tonic::codegen::{
+            http, Arc, Body, BoxFuture, BoxStream, CompressionEncoding, Context, Poll, Service, StdError,
+        };
+        use waves_protobuf_schemas::tonic::server::{Grpc, NamedService, ServerStreamingService};
+        use waves_protobuf_schemas::tonic::{self, body::BoxBody, transport::Server};
+        use waves_protobuf_schemas::waves::{
+            block::Header,
+            events::{
+                blockchain_updated::{
+                    append::{BlockAppend, Body as AppendBody},
+                    Append, Update,
+                },
+                BlockchainUpdated, TransactionMetadata,
+            },
+            signed_transaction::Transaction as TransactionEnum,
+            transaction::Data as WavesTxData,
+            Amount, Block, LeaseCancelTransactionData, SignedTransaction, Transaction as WavesTransaction,
+        };
+
+        use super::{SubscribeEvent, SubscribeRequest};
+
+        #[derive(Clone)]
+        struct Updates {
+            updates: Arc<Vec<BlockchainUpdated>>,
+            compression: Option<CompressionEncoding>,
+        }
+
+        impl NamedService for Updates {
+            const NAME: &'static str = "waves.events.grpc.BlockchainUpdatesApi";
+        }
+
+        impl<B> Service<http::Request<B>> for Updates
+        where
+            B: Body + Send + 'static,
+            B::Error: Into<StdError> + Send + 'static,
+        {
+            type Response = http::Response<BoxBody>;
+            type Error = Infallible;
+            type Future = BoxFuture<Self::Response, Self::Error>;
+
+            fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: http::Request<B>) -> Self::Future {
+                let updates = self.clone();
+                Box::pin(async move {
+                    let mut grpc = Grpc::new(ProstCodec::<SubscribeEvent, SubscribeRequest>::default());
+                    if let Some(compression) = updates.compression {
+                        grpc = grpc.send_compressed(compression);
+                    }
+                    Ok(grpc.server_streaming(updates, req).await)
+                })
+            }
+        }
+
+        impl ServerStreamingService<SubscribeRequest> for Updates {
+            type Response = SubscribeEvent;
+            type ResponseStream = BoxStream<SubscribeEvent>;
+            type Future = BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+
+            fn call(&mut self, _request: tonic::Request<SubscribeRequest>) -> Self::Future {
+                let events = self.updates.iter().map(|update| SubscribeEvent {
+                    update: Some(update.clone()),
+                });
+                let events = stream::iter(events.map(Ok).collect::<Vec<_>>()).boxed();
+                Box::pin(async move { Ok(tonic::Response::new(events)) })
+            }
+        }
+
+        /// Starts the server, returns the `unix://` URL to connect to
+        pub fn start(updates: Vec<BlockchainUpdated>, compression: Option<CompressionEncoding>) -> String {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "blockchain-updates-{}-{}.sock",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path: PathBuf = std::env::temp_dir().join(name);
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).expect("failed to bind the socket");
+            let incoming = stream::unfold(listener, |listener| async move {
+                let stream = listener.accept().await.map(|(stream, _)| stream);
+                Some((stream, listener))
+            });
+            let service = Updates {
+                updates: Arc::new(updates),
+                compression,
+            };
+            tokio::spawn(Server::builder().add_service(service).serve_with_incoming(incoming));
+            format!("unix://{}", path.display())
+        }
+
+        /// Lease cancel of the lease `[5; 32]`
+        pub fn lease_cancel() -> SignedTransaction {
+            SignedTransaction {
+                transaction: Some(TransactionEnum::WavesTransaction(WavesTransaction {
+                    chain_id: 'W' as i32,
+                    sender_public_key: vec![1; 32],
+                    fee: Some(Amount {
+                        asset_id: vec![],
+                        amount: 100_000,
+                    }),
+                    timestamp: 1_600_000_000_000,
+                    version: 3,
+                    data: Some(WavesTxData::LeaseCancel(LeaseCancelTransactionData {
+                        lease_id: vec![5; 32],
+                    })),
+                })),
+                proofs: vec![vec![2; 64]],
+            }
+        }
+
+        /// Key block at the height with the given transactions
+        pub fn block_update(height: i32, transactions: Vec<SignedTransaction>) -> BlockchainUpdated {
+            let transaction_ids = (0..transactions.len()).map(|i| vec![i as u8; 32]).collect();
+            let transactions_metadata = transactions.iter().map(|_| TransactionMetadata::default()).collect();
+            BlockchainUpdated {
+                id: vec![height as u8; 64],
+                height,
+                update: Some(Update::Append(Append {
+                    body: Some(AppendBody::Block(BlockAppend {
+                        block: Some(Block {
+                            header: Some(Header {
+                                timestamp: 1_600_000_000_000,
+                                ..Default::default()
+                            }),
+                            transactions,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })),
+                    transaction_ids,
+                    transactions_metadata,
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use waves_protobuf_schemas::waves::{
+            events::blockchain_updated::{
+                append::{BlockAppend, Body as AppendBody},
+                Append, Update,
+            },
+            signed_transaction::Transaction as TransactionEnum,
+            transaction::Data as WavesTxData,
+            SignedTransaction, Transaction as WavesTransaction, TransferTransactionData,
+        };
+
+        use super::super::{AppendBlock, BinaryEncoding, Rollback};
+        use super::test_server::{self, block_update, lease_cancel};
+        use super::*;
+
+        fn append(height: u32, is_microblock: bool) -> BlockchainUpdate {
+            BlockchainUpdate::Append(AppendBlock {
+                block_id: format!("block{}", height),
+                height,
+                timestamp: None,
+                is_microblock,
+                transactions: Vec::new(),
+                quarantined: Vec::new(),
+            })
+        }
+
+        #[test]
+        fn height_gaps() {
+            let rollback = BlockchainUpdate::Rollback(Rollback {
+                block_id: "block1".to_owned(),
+            });
+            let gaps_after = |updates: &[BlockchainUpdate]| {
+                let before = HEIGHT_GAPS.get();
+                let mut last_height = None;
+                for update in updates {
+                    track_height(&mut last_height, update);
+                }
+                HEIGHT_GAPS.get() - before
+            };
+
+            assert_eq!(gaps_after(&[append(10, false), append(10, true), append(11, false)]), 0);
+            assert_eq!(
+                gaps_after(&[append(10, false), append(12, false), append(13, false)]),
+                1
+            );
+            assert_eq!(gaps_after(&[append(10, false), append(12, true), append(15, false)]), 2);
+            // Starting anywhere is fine, and so is any height after a rollback
+            assert_eq!(gaps_after(&[append(100, false)]), 0);
+            assert_eq!(gaps_after(&[append(10, false), rollback, append(5, false)]), 0);
+            assert_eq!(CHAIN_HEIGHT.get(), 5);
+        }
+
+        const LIMITS: ConvertLimits = ConvertLimits {
+            max_arg_depth: 10,
+            max_args: None,
+            max_payments: None,
+            reject_over_limit: false,
+            binary_encoding: BinaryEncoding::Base64,
+        };
+
+        const MB: usize = 1024 * 1024;
+
+        /// Transfer, not an operation, but it is decoded as any other transaction
+        fn transfer(attachment_size: usize) -> SignedTransaction {
+            SignedTransaction {
+                transaction: Some(TransactionEnum::WavesTransaction(WavesTransaction {
+                    data: Some(WavesTxData::Transfer(TransferTransactionData {
+                        recipient: None,
+                        amount: None,
+                        attachment: vec![1; attachment_size],
+                    })),
+                    ..Default::default()
+                })),
+                proofs: vec![],
+            }
+        }
+
+        /// Heights of all the updates streamed from the server until it closes the stream (or fails)
+        async fn received_heights(url: &str, max_message_size: usize, compression: GrpcCompression) -> Vec<u32> {
+            let updates =
+                BlockchainUpdates::connect(url.to_owned(), max_message_size, compression, LIMITS, false, false)
+                    .await
+                    .expect("failed to connect");
+            let mut rx = updates.stream(1).await.expect("failed to subscribe");
+            let mut heights = Vec::new();
+            while let Some(update) = rx.recv().await {
+                match update {
+                    BlockchainUpdate::Append(append) => heights.push(append.height),
+                    BlockchainUpdate::Rollback(_) => panic!("unexpected rollback"),
+                }
+            }
+            heights
+        }
+
+        #[tokio::test]
+        async fn unix_socket() {
+            let updates = vec![block_update(1, vec![transfer(1)]), block_update(2, vec![])];
+            let url = test_server::start(updates, None);
+            assert!(url.starts_with("unix:///"), "{}", url);
+            assert_eq!(received_heights(&url, MB, GrpcCompression::None).await, [1, 2]);
+            // The authority part is optional
+            let short_url = url.replacen("unix://", "unix:", 1);
+            assert_eq!(received_heights(&short_url, MB, GrpcCompression::None).await, [1, 2]);
+
+            let missing = format!("{}.missing", url);
+            let res = BlockchainUpdates::connect(missing, MB, GrpcCompression::None, LIMITS, false, false).await;
+            assert!(res.is_err());
+        }
+
+        #[tokio::test]
+        async fn gzip_compression() {
+            let updates = || {
+                vec![
+                    block_update(1, vec![transfer(MB)]),
+                    block_update(2, vec![transfer(1), lease_cancel()]),
+                ]
+            };
+            let gzip_url = test_server::start(updates(), Some(CompressionEncoding::Gzip));
+            let plain_url = test_server::start(updates(), None);
+
+            assert_eq!(received_heights(&gzip_url, 2 * MB, GrpcCompression::Gzip).await, [1, 2]);
+            // Either side without gzip falls back to the uncompressed messages
+            assert_eq!(received_heights(&gzip_url, 2 * MB, GrpcCompression::None).await, [1, 2]);
+            assert_eq!(
+                received_heights(&plain_url, 2 * MB, GrpcCompression::Gzip).await,
+                [1, 2]
+            );
+
+            // The decoded contents are the same
+            let decoded = |url: String| async move {
+                let updates = BlockchainUpdates::connect(url, 2 * MB, GrpcCompression::Gzip, LIMITS, false, false)
+                    .await
+                    .expect("failed to connect");
+                let mut rx = updates.stream(2).await.expect("failed to subscribe");
+                let mut operations = Vec::new();
+                while let Some(update) = rx.recv().await {
+                    if let BlockchainUpdate::Append(append) = update {
+                        operations.extend(append.transactions.iter().map(|tx| serde_json::to_value(tx).unwrap()));
+                    }
+                }
+                operations
+            };
+            let operations = decoded(gzip_url).await;
+            assert_eq!(operations.len(), 1);
+            assert_eq!(operations, decoded(plain_url).await);
+        }
+
+        #[tokio::test]
+        async fn failed_update_is_counted() {
+            let mut update = block_update(2, vec![]);
+            if let Some(Update::Append(Append {
+                body: Some(AppendBody::Block(BlockAppend { block: Some(block), .. })),
+                ..
+            })) = &mut update.update
+            {
+                block.header.as_mut().unwrap().timestamp = 0;
+            }
+            let url = test_server::start(vec![block_update(1, vec![]), update], None);
+            let errors = || CONVERSION_ERRORS.with_label_values(&["block timestamp is zero"]).get();
+            let before = errors();
+
+            // The stream stops at the update that fails to convert
+            assert_eq!(received_heights(&url, MB, GrpcCompression::None).await, [1]);
+            assert_eq!(errors(), before + 1);
+        }
+
+        #[tokio::test]
+        async fn unconvertible_update_is_skipped() {
+            let without_body = BlockchainUpdated {
+                update: Some(Update::Append(Append::default())),
+                ..block_update(2, vec![])
+            };
+            let url = test_server::start(
+                vec![block_update(1, vec![]), without_body, block_update(3, vec![])],
+                None,
+            );
+            let heights = |skip_unconvertible_updates| {
+                let url = url.clone();
+                async move {
+                    let updates = BlockchainUpdates::connect(
+                        url,
+                        MB,
+                        GrpcCompression::None,
+                        LIMITS,
+                        false,
+                        skip_unconvertible_updates,
+                    )
+                    .await
+                    .expect("failed to connect");
+                    let mut rx = updates.stream(1).await.expect("failed to subscribe");
+                    let mut heights = Vec::new();
+                    while let Some(BlockchainUpdate::Append(append)) = rx.recv().await {
+                        heights.push(append.height);
+                    }
+                    heights
+                }
+            };
+            assert_eq!(heights(false).await, [1]);
+            assert_eq!(heights(true).await, [1, 3]);
+        }
+
+        #[tokio::test]
+        async fn large_message() {
+            let updates = vec![
+                block_update(1, vec![transfer(1)]),
+                block_update(2, vec![transfer(5 * MB)]),
+            ];
+            let url = test_server::start(updates, None);
+
+            assert_eq!(received_heights(&url, 16 * MB, GrpcCompression::None).await, [1, 2]);
+            // The default limit of the gRPC client, the stream fails with "message too large"
+            assert_eq!(received_heights(&url, 4 * MB, GrpcCompression::None).await, [1]);
+        }
     }
 }
@@ -0,0 +1,187 @@
+//! Watchdog of the consumer's own progress.
+//!
+//! The readiness channel only checks the age of the last stored block,
+//! which can't tell a quiet chain from a consumer stuck on a database write.
+//! The write loop reports the batches it is writing, and a batch which is not
+//! written within the configured interval makes the consumer unready,
+//! regardless of what the block age check says.
+//!
+//! Failures of the block age check itself (e.g. a short database failover) can be tolerated:
+//! the consumer only becomes unready after the configured number of failed checks in a row.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::{sync::mpsc, task, time};
+use wx_warp::endpoints::Readiness;
+
+/// How often the in-flight write is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Progress of the write loop, shared with the watchdog.
+#[derive(Default)]
+pub struct Heartbeat {
+    /// Unix time (in ms) when the batch being written was received, 0 if not writing
+    write_started_at: AtomicI64,
+}
+
+impl Heartbeat {
+    pub fn write_started(&self) {
+        self.write_started_at.store(now_millis(), Ordering::Relaxed);
+    }
+
+    pub fn write_finished(&self) {
+        self.write_started_at.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether a batch has been in flight for longer than `max_write_time`
+    fn is_stalled(&self, max_write_time: Duration) -> bool {
+        let started_at = self.write_started_at.load(Ordering::Relaxed);
+        started_at != 0 && now_millis() - started_at > max_write_time.as_millis() as i64
+    }
+}
+
+/// Wraps the readiness channel, reporting `Dead` while the write loop is stalled,
+/// or once `failure_threshold` readiness checks in a row have failed.
+pub fn start(
+    mut readiness: mpsc::UnboundedReceiver<Readiness>,
+    heartbeat: Arc<Heartbeat>,
+    max_write_time: Duration,
+    failure_threshold: u32,
+) -> mpsc::UnboundedReceiver<Readiness> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    task::spawn(async move {
+        let mut last_status = None;
+        let mut failures = 0;
+        let mut stalled = false;
+        let mut check = time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                status = readiness.recv() => match status {
+                    Some(Readiness::Dead) if failures + 1 < failure_threshold => {
+                        failures += 1;
+                        log::warn!(
+                            "Readiness check failed ({} of {} in a row), keeping the previous status",
+                            failures,
+                            failure_threshold
+                        );
+                        continue;
+                    }
+                    Some(status) => {
+                        failures = if matches!(status, Readiness::Dead) { failures + 1 } else { 0 };
+                        last_status = Some(status);
+                    }
+                    None => break,
+                },
+                _ = check.tick() => {
+                    let now_stalled = heartbeat.is_stalled(max_write_time);
+                    if now_stalled == stalled {
+                        continue;
+                    }
+                    stalled = now_stalled;
+                    if stalled {
+                        log::error!("Consumer is stuck: no database write succeeded within {:?}", max_write_time);
+                    } else {
+                        log::info!("Consumer is making progress again");
+                    }
+                }
+            }
+            let status = if stalled {
+                Some(Readiness::Dead)
+            } else {
+                last_status.clone()
+            };
+            if let Some(status) = status {
+                if tx.send(status).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_ready(status: Option<Readiness>) -> bool {
+        match status {
+            Some(Readiness::Ready) => true,
+            Some(Readiness::Dead) => false,
+            None => panic!("readiness channel closed"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_writer_is_unready() {
+        let (readiness_tx, readiness) = mpsc::unbounded_channel();
+        let heartbeat = Arc::new(Heartbeat::default());
+        let mut output = start(readiness, heartbeat.clone(), Duration::ZERO, 1);
+
+        // The chain is quiet, but nothing is being written either
+        readiness_tx.send(Readiness::Ready).unwrap();
+        assert!(is_ready(output.recv().await));
+
+        heartbeat.write_started();
+        // The write time is checked by the wall clock, not the paused one
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(!is_ready(output.recv().await));
+
+        heartbeat.write_finished();
+        assert!(is_ready(output.recv().await));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_checks_are_tolerated() {
+        let (readiness_tx, readiness) = mpsc::unbounded_channel();
+        let mut output = start(readiness, Arc::default(), Duration::from_secs(60), 3);
+
+        let statuses = [
+            Readiness::Ready,
+            Readiness::Dead,
+            Readiness::Dead,
+            Readiness::Ready,
+            Readiness::Dead,
+            Readiness::Dead,
+            Readiness::Dead,
+        ];
+        for status in statuses {
+            readiness_tx.send(status).unwrap();
+        }
+        // Only the third failure in a row is passed on
+        assert!(is_ready(output.recv().await));
+        assert!(is_ready(output.recv().await));
+        assert!(!is_ready(output.recv().await));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn single_blip_is_tolerated() {
+        let statuses = [Readiness::Ready, Readiness::Dead, Readiness::Ready];
+
+        // Default threshold, the first failure is passed on right away
+        let (readiness_tx, readiness) = mpsc::unbounded_channel();
+        let mut output = start(readiness, Arc::default(), Duration::from_secs(60), 1);
+        for status in statuses.clone() {
+            readiness_tx.send(status).unwrap();
+        }
+        assert!(is_ready(output.recv().await));
+        assert!(!is_ready(output.recv().await));
+        assert!(is_ready(output.recv().await));
+
+        let (readiness_tx, readiness) = mpsc::unbounded_channel();
+        let mut output = start(readiness, Arc::default(), Duration::from_secs(60), 2);
+        for status in statuses {
+            readiness_tx.send(status).unwrap();
+        }
+        drop(readiness_tx);
+        assert!(is_ready(output.recv().await));
+        assert!(is_ready(output.recv().await));
+        assert!(output.recv().await.is_none());
+    }
+}
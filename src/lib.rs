@@ -7,6 +7,8 @@ extern crate wavesexchange_log as log;
 extern crate wavesexchange_warp as wx_warp;
 
 mod schema;
+#[cfg(feature = "sqlite")]
+mod schema_sqlite;
 
 pub mod common;
 pub mod consumer;
@@ -12,6 +12,17 @@ diesel::table! {
         id -> Varchar,
         height -> Int4,
         time_stamp -> Int8,
+        is_microblock -> Bool,
+    }
+}
+
+diesel::table! {
+    quarantine (id) {
+        uid -> Int8,
+        id -> Varchar,
+        block_uid -> Int8,
+        raw -> Bytea,
+        reason -> Text,
     }
 }
 
@@ -27,10 +38,15 @@ diesel::table! {
         tx_type -> Int2,
         op_type -> OperationType,
         operation -> Jsonb,
+        fee_amount -> Int8,
+        fee_asset -> Varchar,
+        height -> Int4,
+        created_at -> Timestamptz,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
     blocks_microblocks,
+    quarantine,
     transactions,
 );
@@ -0,0 +1,41 @@
+//! Schema of the SQLite database (local development only), see `migrations_sqlite`.
+//!
+//! Same tables as in Postgres, but the operation type is stored as text and the operation body as JSON text.
+
+diesel::table! {
+    blocks_microblocks (uid) {
+        uid -> BigInt,
+        id -> Text,
+        height -> Integer,
+        time_stamp -> BigInt,
+        is_microblock -> Bool,
+    }
+}
+
+diesel::table! {
+    quarantine (uid) {
+        uid -> BigInt,
+        id -> Text,
+        block_uid -> BigInt,
+        raw -> Binary,
+        reason -> Text,
+    }
+}
+
+diesel::table! {
+    transactions (uid) {
+        uid -> BigInt,
+        id -> Text,
+        block_uid -> BigInt,
+        sender -> Text,
+        tx_type -> SmallInt,
+        op_type -> Text,
+        operation -> Text,
+        fee_amount -> BigInt,
+        fee_asset -> Text,
+        height -> Integer,
+        created_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(blocks_microblocks, quarantine, transactions,);
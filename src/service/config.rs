@@ -1,12 +1,18 @@
 //! Operation services' config.
 
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
 use serde::Deserialize;
 use thiserror::Error;
 
-use crate::common::database::config::PostgresConfig;
+use crate::common::database::config::{self as db_config, PostgresConfig};
 
 #[derive(Clone)]
 pub struct ServiceConfig {
+    /// Address to bind the server to, either IPv4 or IPv6
+    pub bind_address: IpAddr,
+
     /// Server port
     pub port: u16,
 
@@ -16,12 +22,73 @@ pub struct ServiceConfig {
     /// Postgres database config
     pub db: PostgresConfig,
 
+    /// Postgres read replica config, if any (used for all the reads instead of the primary database)
+    pub db_replica: Option<PostgresConfig>,
+
     /// Database pool size
     pub db_pool_size: u32,
+
+    /// How long a request may wait for a free database connection, unlimited if not set
+    pub db_pool_wait_timeout: Option<Duration>,
+
+    /// Max value of the `limit` query parameter
+    pub max_query_limit: u32,
+
+    /// Value of the `limit` query parameter when it is not specified
+    pub default_query_limit: u32,
+
+    /// How long to cache query results, zero disables caching
+    pub query_cache_ttl: Duration,
+
+    /// Max number of requests served concurrently, if limited
+    pub max_connections: Option<usize>,
+
+    /// Per-client rate limit of the operations endpoints, if any
+    pub rate_limit: Option<RateLimitParams>,
+
+    /// Reject the `sender` filter which is not an address, if set
+    pub sender_validation: Option<SenderValidation>,
+
+    /// How long to cache the latest operations, zero disables caching
+    pub latest_cache_ttl: Duration,
+
+    /// The service is not ready while the last stored block is older than this, if set
+    pub max_data_age: Option<Duration>,
+
+    /// Check the database connection on start, to fail fast
+    pub validate_db_on_start: bool,
+
+    /// Max size of a request body, in bytes
+    pub max_body_size: u64,
+
+    /// Max number of items in a list query parameter
+    pub max_list_param_len: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct SenderValidation {
+    /// Network the address must belong to, any if not set
+    pub chain_id: Option<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitParams {
+    /// Sustained rate of requests per client
+    pub requests_per_second: f64,
+
+    /// Max number of requests a client can make at once
+    pub burst: u32,
+
+    /// Proxies whose `X-Forwarded-For` header is trusted to contain the client address
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 #[derive(Deserialize)]
 struct RawConfig {
+    /// Address to bind the server to, all the IPv4 interfaces if not set
+    #[serde(rename = "bind_address")]
+    bind_address: Option<String>,
+
     /// Server port
     #[serde(rename = "port", default = "default_port")]
     port: u16,
@@ -32,6 +99,133 @@ struct RawConfig {
     /// Database pool size
     #[serde(rename = "pgpoolsize", default = "default_db_pool_size")]
     pub db_pool_size: u32,
+
+    /// How long a request may wait for a free database connection (in milliseconds), zero means no limit
+    #[serde(rename = "pgpool_wait_timeout_ms", default = "default_db_pool_wait_timeout_ms")]
+    db_pool_wait_timeout_ms: u64,
+
+    /// Max value of the `limit` query parameter
+    #[serde(rename = "max_query_limit", default = "default_max_query_limit")]
+    max_query_limit: u32,
+
+    /// Value of the `limit` query parameter when it is not specified
+    #[serde(rename = "default_query_limit", default = "default_default_query_limit")]
+    default_query_limit: u32,
+
+    /// How long to cache query results (in seconds), zero disables caching
+    #[serde(rename = "query_cache_ttl_secs", default)]
+    query_cache_ttl_secs: u64,
+
+    /// How long to cache the latest operations (in seconds), zero disables caching
+    #[serde(rename = "latest_cache_ttl_secs", default = "default_latest_cache_ttl_secs")]
+    latest_cache_ttl_secs: u64,
+
+    /// Max number of requests served concurrently, unlimited if not set
+    #[serde(rename = "max_connections")]
+    max_connections: Option<usize>,
+
+    /// Reject the `sender` filter which is not a valid address (default false)
+    #[serde(rename = "validate_sender", default)]
+    validate_sender: bool,
+
+    /// Chain id (network byte) of the addresses, e.g. 'W' for mainnet, checked only when validating the sender
+    #[serde(rename = "chain_id")]
+    chain_id: Option<char>,
+
+    /// Max age of the last stored block (in seconds) for the service to be ready, not checked if not set
+    #[serde(rename = "max_data_age_secs")]
+    max_data_age_secs: Option<u64>,
+
+    /// Connect to the database on start and fail if it doesn't work (default true)
+    #[serde(rename = "validate_db_on_start", default = "default_validate_db_on_start")]
+    validate_db_on_start: bool,
+
+    /// Max size of a request body (in bytes), larger ones get 413
+    #[serde(rename = "max_body_size", default = "default_max_body_size")]
+    max_body_size: u64,
+
+    /// Max number of items in a list query parameter, e.g. `type__in` or `fields`
+    #[serde(rename = "max_list_param_len", default = "default_max_list_param_len")]
+    max_list_param_len: usize,
+}
+
+#[derive(Deserialize)]
+struct RateLimitRawConfig {
+    /// Requests per second per client, rate limiting is disabled if not set
+    #[serde(rename = "rate_limit_rps")]
+    rate_limit_rps: Option<f64>,
+
+    /// Burst size, default is the per-second rate
+    #[serde(rename = "rate_limit_burst")]
+    rate_limit_burst: Option<u32>,
+
+    /// Comma-separated IP addresses of the trusted reverse proxies
+    #[serde(rename = "trusted_proxies")]
+    trusted_proxies: Option<String>,
+}
+
+impl RateLimitRawConfig {
+    fn into_params(self) -> Result<Option<RateLimitParams>, ConfigError> {
+        let requests_per_second = match self.rate_limit_rps {
+            Some(rps) if rps > 0.0 => rps,
+            Some(_) => return Err(ConfigError::ValidationError("RATE_LIMIT_RPS", "must be positive")),
+            None => return Ok(None),
+        };
+        let burst = self
+            .rate_limit_burst
+            .unwrap_or_else(|| requests_per_second.ceil() as u32)
+            .max(1);
+        let trusted_proxies = self
+            .trusted_proxies
+            .iter()
+            .flat_map(|proxies| proxies.split(','))
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| proxy.parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|_| ConfigError::ValidationError("TRUSTED_PROXIES", "invalid IP address"))?;
+        Ok(Some(RateLimitParams {
+            requests_per_second,
+            burst,
+            trusted_proxies,
+        }))
+    }
+}
+
+/// Read replica settings, only host is required, the rest defaults to the primary database settings
+#[derive(Deserialize)]
+struct ReplicaRawConfig {
+    #[serde(rename = "pg_replica_host")]
+    host: Option<String>,
+
+    #[serde(rename = "pg_replica_port")]
+    port: Option<u16>,
+
+    #[serde(rename = "pg_replica_database")]
+    database: Option<String>,
+
+    #[serde(rename = "pg_replica_user")]
+    user: Option<String>,
+
+    #[serde(rename = "pg_replica_password")]
+    password: Option<String>,
+}
+
+impl ReplicaRawConfig {
+    fn into_config(self, primary: &PostgresConfig) -> Option<PostgresConfig> {
+        let host = self.host?;
+        Some(PostgresConfig {
+            host,
+            port: self.port.unwrap_or(primary.port),
+            database: self.database.unwrap_or_else(|| primary.database.clone()),
+            user: self.user.unwrap_or_else(|| primary.user.clone()),
+            password: self.password.unwrap_or_else(|| primary.password.clone()),
+            connect_timeout_secs: primary.connect_timeout_secs,
+            statement_timeout_ms: primary.statement_timeout_ms,
+            ssl_mode: primary.ssl_mode,
+            ssl_root_cert: primary.ssl_root_cert.clone(),
+        })
+    }
 }
 
 fn default_port() -> u16 {
@@ -46,20 +240,208 @@ fn default_db_pool_size() -> u32 {
     8
 }
 
+fn default_db_pool_wait_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_latest_cache_ttl_secs() -> u64 {
+    5
+}
+
+fn default_max_query_limit() -> u32 {
+    100
+}
+
+fn default_default_query_limit() -> u32 {
+    20
+}
+
+fn default_validate_db_on_start() -> bool {
+    true
+}
+
+fn default_max_body_size() -> u64 {
+    64 * 1024
+}
+
+fn default_max_list_param_len() -> usize {
+    32
+}
+
 #[derive(Error, Debug)]
-#[error("configuration error: {0}")]
-pub struct ConfigError(#[from] envy::Error);
+pub enum ConfigError {
+    #[error("configuration error: {0}")]
+    EnvyError(#[from] envy::Error),
+
+    #[error("configuration error: invalid {0} parameter: {1}")]
+    ValidationError(&'static str, &'static str),
+}
 
 pub fn load() -> Result<ServiceConfig, ConfigError> {
     let raw_config = envy::from_env::<RawConfig>()?;
-    let pg_config = envy::from_env::<PostgresConfig>()?;
+    let pg_config = db_config::load().map_err(|db_config::DbConfigError(e)| e)?;
+    let replica_config = envy::from_env::<ReplicaRawConfig>()?.into_config(&pg_config);
+    let rate_limit = envy::from_env::<RateLimitRawConfig>()?.into_params()?;
+
+    check_query_limits(raw_config.max_query_limit, raw_config.default_query_limit)?;
+
+    if raw_config.max_connections == Some(0) {
+        return Err(ConfigError::ValidationError("MAX_CONNECTIONS", "must be positive"));
+    }
+
+    if raw_config.max_data_age_secs == Some(0) {
+        return Err(ConfigError::ValidationError("MAX_DATA_AGE_SECS", "must be positive"));
+    }
+
+    if raw_config.max_body_size == 0 {
+        return Err(ConfigError::ValidationError("MAX_BODY_SIZE", "must be positive"));
+    }
+
+    if raw_config.max_list_param_len == 0 {
+        return Err(ConfigError::ValidationError("MAX_LIST_PARAM_LEN", "must be positive"));
+    }
+
+    let bind_address = match raw_config.bind_address.as_deref() {
+        Some(address) => {
+            parse_bind_address(address).ok_or(ConfigError::ValidationError("BIND_ADDRESS", "invalid IP address"))?
+        }
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    let chain_id = raw_config
+        .chain_id
+        .map(|chain_id| u8::try_from(chain_id).map_err(|_| ConfigError::ValidationError("CHAIN_ID", "must be ASCII")))
+        .transpose()?;
+    let sender_validation = raw_config.validate_sender.then_some(SenderValidation { chain_id });
 
     let config = ServiceConfig {
+        bind_address,
         port: raw_config.port,
         metrics_port: raw_config.metrics_port,
         db: pg_config,
+        db_replica: replica_config,
         db_pool_size: raw_config.db_pool_size,
+        db_pool_wait_timeout: (raw_config.db_pool_wait_timeout_ms > 0)
+            .then(|| Duration::from_millis(raw_config.db_pool_wait_timeout_ms)),
+        max_query_limit: raw_config.max_query_limit,
+        default_query_limit: raw_config.default_query_limit,
+        query_cache_ttl: Duration::from_secs(raw_config.query_cache_ttl_secs),
+        max_connections: raw_config.max_connections,
+        rate_limit,
+        sender_validation,
+        latest_cache_ttl: Duration::from_secs(raw_config.latest_cache_ttl_secs),
+        max_data_age: raw_config.max_data_age_secs.map(Duration::from_secs),
+        validate_db_on_start: raw_config.validate_db_on_start,
+        max_body_size: raw_config.max_body_size,
+        max_list_param_len: raw_config.max_list_param_len,
     };
 
     Ok(config)
 }
+
+fn check_query_limits(max_query_limit: u32, default_query_limit: u32) -> Result<(), ConfigError> {
+    if max_query_limit == 0 {
+        return Err(ConfigError::ValidationError("MAX_QUERY_LIMIT", "must be positive"));
+    }
+
+    if default_query_limit == 0 || default_query_limit > max_query_limit {
+        return Err(ConfigError::ValidationError(
+            "DEFAULT_QUERY_LIMIT",
+            "must be positive and not greater than MAX_QUERY_LIMIT",
+        ));
+    }
+
+    Ok(())
+}
+
+/// IPv6 addresses may come in brackets, as in URLs: `[::1]`
+fn parse_bind_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+    let address = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    address.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_config(vars: &[(&str, &str)]) -> RawConfig {
+        let vars = vars.iter().map(|&(name, value)| (name.to_owned(), value.to_owned()));
+        envy::from_iter(vars).unwrap()
+    }
+
+    #[test]
+    fn bind_addresses() {
+        let v6 = |address: &str| Some(IpAddr::V6(address.parse().unwrap()));
+        assert_eq!(parse_bind_address("::"), v6("::"));
+        assert_eq!(parse_bind_address("::1"), v6("::1"));
+        assert_eq!(parse_bind_address("[::1]"), v6("::1"));
+        // IPv4-mapped, stays IPv6 so that the socket is bound as such
+        assert_eq!(parse_bind_address("::ffff:127.0.0.1"), v6("::ffff:7f00:1"));
+        assert_eq!(parse_bind_address(" 0.0.0.0 "), Some(IpAddr::V4([0, 0, 0, 0].into())));
+        assert_eq!(parse_bind_address("[::1"), None);
+        assert_eq!(parse_bind_address("[127.0.0.1]:8080"), None);
+        assert_eq!(parse_bind_address("localhost"), None);
+    }
+
+    #[test]
+    fn max_query_limit() {
+        assert_eq!(raw_config(&[]).max_query_limit, 100);
+        assert_eq!(raw_config(&[("MAX_QUERY_LIMIT", "1000")]).max_query_limit, 1000);
+
+        assert!(check_query_limits(1000, 20).is_ok());
+        assert!(check_query_limits(0, 0).is_err());
+    }
+
+    #[test]
+    fn default_query_limit() {
+        assert_eq!(raw_config(&[]).default_query_limit, 20);
+        let config = raw_config(&[("MAX_QUERY_LIMIT", "1000"), ("DEFAULT_QUERY_LIMIT", "1000")]);
+        assert_eq!((config.max_query_limit, config.default_query_limit), (1000, 1000));
+
+        assert!(check_query_limits(100, 20).is_ok());
+        assert!(check_query_limits(1000, 1000).is_ok());
+        assert!(check_query_limits(100, 0).is_err());
+        // Over the max
+        assert!(check_query_limits(100, 101).is_err());
+    }
+
+    #[test]
+    fn validate_db_on_start() {
+        assert!(raw_config(&[]).validate_db_on_start);
+        assert!(!raw_config(&[("VALIDATE_DB_ON_START", "false")]).validate_db_on_start);
+    }
+
+    #[test]
+    fn replica_defaults_to_primary() {
+        let primary = PostgresConfig {
+            host: "primary".to_owned(),
+            port: 5433,
+            database: "operations".to_owned(),
+            user: "user".to_owned(),
+            password: "secret".to_owned(),
+            ..PostgresConfig::default()
+        };
+        let replica = |vars: &[(&str, &str)]| {
+            let vars = vars.iter().map(|&(name, value)| (name.to_owned(), value.to_owned()));
+            envy::from_iter::<_, ReplicaRawConfig>(vars)
+                .unwrap()
+                .into_config(&primary)
+        };
+
+        assert!(replica(&[("PG_REPLICA_PORT", "5434")]).is_none());
+
+        let config = replica(&[("PG_REPLICA_HOST", "replica")]).unwrap();
+        assert_eq!(config.host, "replica");
+        assert_eq!(config.port, 5433);
+        assert_eq!(config.database, "operations");
+        assert_eq!(config.user, "user");
+        assert_eq!(config.password, "secret");
+
+        let config = replica(&[("PG_REPLICA_HOST", "replica"), ("PG_REPLICA_USER", "reader")]).unwrap();
+        assert_eq!(config.user, "reader");
+    }
+}
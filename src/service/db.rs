@@ -3,16 +3,103 @@
 pub mod pool {
     //! Pooled connections to the database
 
-    use deadpool_diesel::postgres::{Manager, Pool, Runtime};
+    use std::time::Duration;
+
+    use deadpool::managed::TimeoutType;
+    use deadpool_diesel::postgres::{Manager, Pool, PoolError, Runtime};
+    use diesel::RunQueryDsl;
 
     use crate::common::database::config::PostgresConfig;
 
     pub type PgPool = Pool;
 
-    pub fn new(config: &PostgresConfig, pool_size: u32) -> Result<PgPool, anyhow::Error> {
+    /// Waiting for a free connection longer than `wait_timeout` fails, if set
+    pub fn new(
+        config: &PostgresConfig,
+        pool_size: u32,
+        wait_timeout: Option<Duration>,
+    ) -> Result<PgPool, anyhow::Error> {
         let db_url = config.database_url();
         let manager = Manager::new(db_url, Runtime::Tokio1);
-        let pool = Pool::builder(manager).max_size(pool_size as usize).build()?;
+        let pool = Pool::builder(manager)
+            .max_size(pool_size as usize)
+            .wait_timeout(wait_timeout)
+            .runtime(Runtime::Tokio1)
+            .build()?;
+        Ok(pool)
+    }
+
+    /// Acquire a connection and run a trivial query on it, the pool itself connects lazily,
+    /// so bad credentials or an unreachable database would otherwise show up only on the first request
+    pub async fn validate(pool: &PgPool) -> Result<(), anyhow::Error> {
+        let conn = pool
+            .get()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to connect to the database: {}", e))?;
+        conn.interact(|conn| diesel::sql_query("SELECT 1").execute(conn))
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("failed to query the database: {}", e))?;
+        Ok(())
+    }
+
+    /// Whether the error is a timeout waiting for a free connection, i.e. all of them are busy.
+    /// The error type is the same for the Postgres and SQLite pools.
+    pub fn is_exhausted(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<PoolError>(),
+            Some(PoolError::Timeout(TimeoutType::Wait))
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::time::Instant;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn validate_fails_fast_without_database() {
+            // Nothing listens on the port
+            let config = PostgresConfig {
+                host: "127.0.0.1".to_owned(),
+                port: 1,
+                database: "operations".to_owned(),
+                user: "user".to_owned(),
+                password: "secret".to_owned(),
+                connect_timeout_secs: Some(1),
+                ..PostgresConfig::default()
+            };
+            let start = Instant::now();
+            let pool = new(&config, 2, Some(Duration::from_secs(5))).expect("pool is created lazily");
+            let err = validate(&pool).await.unwrap_err();
+            assert!(
+                err.to_string().starts_with("failed to connect to the database"),
+                "{}",
+                err
+            );
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite_pool {
+    //! Pooled connections to the SQLite database, for local development only
+
+    use std::time::Duration;
+
+    use deadpool_diesel::sqlite::{Manager, Pool, Runtime};
+
+    pub type SqlitePool = Pool;
+
+    pub fn new(path: &str, pool_size: u32, wait_timeout: Option<Duration>) -> Result<SqlitePool, anyhow::Error> {
+        let manager = Manager::new(path, Runtime::Tokio1);
+        let pool = Pool::builder(manager)
+            .max_size(pool_size as usize)
+            .wait_timeout(wait_timeout)
+            .runtime(Runtime::Tokio1)
+            .build()?;
         Ok(pool)
     }
 }
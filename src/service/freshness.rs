@@ -0,0 +1,100 @@
+//! Freshness guard of the served data.
+//!
+//! The service only reads what the consumer stores, so a stopped consumer goes unnoticed
+//! by the clients, which keep getting the stale data. The timestamp of the last stored block
+//! is checked periodically, and the service is not ready while it is older than the configured age.
+//! An empty database or a failed check also makes the service unready.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::{sync::mpsc, task, time};
+use wx_warp::endpoints::Readiness;
+
+use crate::service::repo::Repo;
+
+/// How often the last block timestamp is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Readiness channel reporting `Dead` while the last stored block is older than `max_age`.
+pub fn start<R>(repo: Arc<R>, max_age: Duration) -> mpsc::UnboundedReceiver<Readiness>
+where
+    R: Repo + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    task::spawn(async move {
+        let mut fresh = None;
+        let mut check = time::interval(CHECK_INTERVAL);
+        loop {
+            check.tick().await;
+            let now_fresh = match repo.last_block_timestamp().await {
+                Ok(Some(timestamp)) => {
+                    let age = now_millis() - timestamp;
+                    if age > max_age.as_millis() as i64 && fresh != Some(false) {
+                        log::error!("Stored data is stale: the last block is {} ms old", age);
+                    }
+                    age <= max_age.as_millis() as i64
+                }
+                Ok(None) => {
+                    if fresh != Some(false) {
+                        log::warn!("No blocks are stored yet");
+                    }
+                    false
+                }
+                Err(e) => {
+                    log::error!("Failed to check the last block timestamp: {:?}", e);
+                    false
+                }
+            };
+            if now_fresh && fresh == Some(false) {
+                log::info!("Stored data is fresh again");
+            }
+            fresh = Some(now_fresh);
+            let status = if now_fresh { Readiness::Ready } else { Readiness::Dead };
+            if tx.send(status).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::repo::memory::MemoryRepo;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    /// First status reported for the repo
+    async fn is_ready(repo: MemoryRepo) -> bool {
+        let mut readiness = start(Arc::new(repo), MAX_AGE);
+        match readiness.recv().await {
+            Some(Readiness::Ready) => true,
+            Some(Readiness::Dead) => false,
+            None => panic!("readiness channel closed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fresh_data_is_ready() {
+        let repo = MemoryRepo::default().with_last_block_timestamp(now_millis() - 1000);
+        assert!(is_ready(repo).await);
+    }
+
+    #[tokio::test]
+    async fn stale_data_is_unready() {
+        let repo = MemoryRepo::default().with_last_block_timestamp(now_millis() - 120_000);
+        assert!(!is_ready(repo).await);
+    }
+
+    #[tokio::test]
+    async fn empty_or_failing_database_is_unready() {
+        assert!(!is_ready(MemoryRepo::default()).await);
+        assert!(!is_ready(MemoryRepo::failing()).await);
+    }
+}
@@ -1,28 +1,70 @@
 //! Operations service.
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 mod config;
-mod db;
-mod repo;
+pub mod db;
+mod freshness;
+pub mod repo;
 mod server;
 
 pub async fn main() -> Result<(), anyhow::Error> {
     // Load configs
     let config = config::load()?;
-    let port = config.port;
-    let metrics_port = config.metrics_port;
+
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = crate::common::database::config::sqlite_database() {
+        log::warn!("Using SQLite database {} (for local development only)", path);
+        let pool = db::sqlite_pool::new(&path, config.db_pool_size, config.db_pool_wait_timeout)?;
+        return serve(repo::sqlite::SqliteRepo::new(pool), &config).await;
+    }
 
     // Create repo
     log::info!("Connecting to database: {:?}", config.db);
-    let pgpool = db::pool::new(&config.db, config.db_pool_size)?;
-    let repo = repo::postgres::PgRepo::new(pgpool);
+    let pgpool = db::pool::new(&config.db, config.db_pool_size, config.db_pool_wait_timeout)?;
+    if config.validate_db_on_start {
+        db::pool::validate(&pgpool).await?;
+    }
+    let mut repo = repo::postgres::PgRepo::new(pgpool);
+    if let Some(db_replica) = &config.db_replica {
+        log::info!("Using read replica: {:?}", db_replica);
+        let replica_pool = db::pool::new(db_replica, config.db_pool_size, config.db_pool_wait_timeout)?;
+        if config.validate_db_on_start {
+            db::pool::validate(&replica_pool).await?;
+        }
+        repo = repo.with_read_replica(replica_pool);
+    }
+    if !config.query_cache_ttl.is_zero() {
+        log::info!("Caching query results for {:?}", config.query_cache_ttl);
+        repo = repo.with_cache(config.query_cache_ttl);
+    }
+
+    serve(repo, &config).await
+}
 
+async fn serve<R>(repo: R, config: &config::ServiceConfig) -> Result<(), anyhow::Error>
+where
+    R: repo::Repo + Send + Sync + 'static,
+{
     // Create the web server
-    let server = server::ServerBuilder::new().repo(repo).build().new_server();
+    let server = server::ServerBuilder::new()
+        .repo(repo)
+        .max_query_limit(config.max_query_limit)
+        .default_query_limit(config.default_query_limit)
+        .max_connections(config.max_connections)
+        .rate_limit(config.rate_limit.clone())
+        .sender_validation(config.sender_validation.clone())
+        .latest_cache_ttl(config.latest_cache_ttl)
+        .max_data_age(config.max_data_age)
+        .max_body_size(config.max_body_size)
+        .max_list_len(config.max_list_param_len)
+        .build()
+        .new_server();
 
     // Run the web server
-    Arc::new(server).run(port, metrics_port).await;
+    let address = SocketAddr::new(config.bind_address, config.port);
+    Arc::new(server).run(address, config.metrics_port).await;
 
     Ok(())
 }
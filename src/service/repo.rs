@@ -11,109 +11,1505 @@ use crate::common::database::types::OperationType;
 pub trait Repo {
     type TxUID: Copy + Send + FromStr + ToString + Serialize;
 
+    /// Fetch a page of operations, paged (and sorted) by the transaction uid.
+    ///
+    /// In Postgres, the sender-filtered queries are served by the `(sender, uid)` index
+    /// and the type-filtered ones by the `op_type` index.
+    ///
+    /// With `include` every operation carries the metadata of its block and/or the time it was stored.
     async fn fetch_operations(
         &self,
-        op_types: Option<Vec<OperationType>>,
-        sender: Option<String>,
+        filters: Filters,
         page: Page<Self::TxUID>,
         sort: Sort,
+        include: Include,
     ) -> anyhow::Result<(Vec<Operation<Self::TxUID>>, Option<Self::TxUID>)>;
+
+    /// All operations of the given block (or microblock) in blockchain order, `None` if the block is unknown.
+    async fn fetch_operations_by_block(&self, block_id: &str) -> anyhow::Result<Option<Vec<Operation<Self::TxUID>>>>;
+
+    /// Operations of the given transactions in blockchain order, unknown ids are skipped.
+    async fn fetch_operations_by_ids(&self, ids: Vec<String>) -> anyhow::Result<Vec<Operation<Self::TxUID>>>;
+
+    /// The `operation` column of the given transaction as is, `None` if the transaction is unknown.
+    async fn fetch_raw_operation(&self, id: &str) -> anyhow::Result<Option<String>>;
+
+    /// Range of the stored heights and (possibly estimated) number of the stored rows.
+    async fn stats(&self) -> anyhow::Result<Stats>;
+
+    /// Timestamp (in ms) of the last stored block, `None` if nothing is stored.
+    async fn last_block_timestamp(&self) -> anyhow::Result<Option<i64>>;
+
+    /// Distinct senders of the operations of the given types, in alphabetical order, at most `limit` of them.
+    ///
+    /// This scans the `(sender, uid)` index, which is slow on a big table when filtered by a rare type,
+    /// so the limit should be kept small.
+    async fn distinct_senders(&self, op_types: Option<Vec<OperationType>>, limit: u32) -> anyhow::Result<Vec<String>>;
+
+    /// Number of the operations of each operation and transaction type within the given height range (inclusive).
+    ///
+    /// Counts all the matching rows, so an unbounded range scans the whole table.
+    async fn operation_counts(
+        &self,
+        min_height: Option<u32>,
+        max_height: Option<u32>,
+    ) -> anyhow::Result<Vec<OperationCount>>;
+
+    /// Number of the operations matching the filters within each interval (by the block timestamp),
+    /// in chronological order, the intervals without operations are omitted.
+    ///
+    /// Counts all the matching rows, so broad filters scan most of the table.
+    async fn operation_histogram(&self, filters: Filters, interval: Interval) -> anyhow::Result<Vec<HistogramBucket>>;
 }
 
-#[derive(Serialize, Queryable)]
+#[derive(Clone, Serialize)]
 pub struct Operation<TxUID> {
+    /// Position of the operation in the blockchain order
     #[serde(skip)]
     tx_uid: TxUID,
+    /// The `tx_uid`, exposed only to the `since_uid` pollers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<TxUID>,
     #[serde(flatten)]
     body: serde_json::Value,
+    /// Block the operation belongs to, only if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    block: Option<serde_json::Value>,
+    /// Derived `outgoing` relative to the queried address, only if queried by one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direction: Option<&'static str>,
+    /// When the operation was stored by the consumer (in ms since the epoch), only if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<i64>,
+    /// Whether the operation is from a microblock, which is not finalized yet and may still be rolled back,
+    /// only if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_microblock: Option<bool>,
+}
+
+impl<TxUID> Operation<TxUID> {
+    pub fn body(&self) -> &serde_json::Value {
+        &self.body
+    }
+
+    pub fn tx_uid(&self) -> &TxUID {
+        &self.tx_uid
+    }
+
+    pub fn block(&self) -> Option<&serde_json::Value> {
+        self.block.as_ref()
+    }
+
+    pub fn direction(&self) -> Option<&'static str> {
+        self.direction
+    }
+
+    pub fn created_at(&self) -> Option<i64> {
+        self.created_at
+    }
+
+    pub fn is_microblock(&self) -> Option<bool> {
+        self.is_microblock
+    }
+
+    /// Derive the direction of the operation from the point of view of the given address:
+    /// `outgoing` if the address is the sender, none otherwise (only the operations of the queried sender
+    /// are selected, so the recipients, e.g. of leases, never see theirs).
+    /// Must be called before `project`, which may drop the fields it is derived from.
+    pub fn with_direction(self, address: &str) -> Self {
+        let outgoing = self.body.get("sender").and_then(serde_json::Value::as_str) == Some(address);
+        Operation {
+            direction: outgoing.then_some("outgoing"),
+            ..self
+        }
+    }
+
+    pub fn with_block(self, block_id: String, block_height: i32, block_timestamp: i64) -> Self {
+        let block = serde_json::json!({
+            "block_id": block_id,
+            "block_height": block_height,
+            "block_timestamp": block_timestamp,
+        });
+        Operation {
+            block: Some(block),
+            ..self
+        }
+    }
+
+    /// Expose the uid, for the pollers to pass the greatest one seen as `since_uid` next time
+    pub fn with_uid(self) -> Self
+    where
+        TxUID: Copy,
+    {
+        Operation {
+            uid: Some(self.tx_uid),
+            ..self
+        }
+    }
+
+    pub fn with_created_at(self, created_at: Option<i64>) -> Self {
+        Operation { created_at, ..self }
+    }
+
+    pub fn with_is_microblock(self, is_microblock: bool) -> Self {
+        Operation {
+            is_microblock: Some(is_microblock),
+            ..self
+        }
+    }
+
+    /// Keep only the given top-level fields of the operation body
+    pub fn project(mut self, fields: &[&str]) -> Self {
+        if let serde_json::Value::Object(map) = &mut self.body {
+            map.retain(|key, _| fields.contains(&key.as_str()));
+        }
+        self
+    }
+
+    /// Rename all the keys of the operation body (nested ones included) from snake_case to camelCase
+    pub fn into_camel_case(mut self) -> Self {
+        camel_case_keys(&mut self.body);
+        if let Some(block) = &mut self.block {
+            camel_case_keys(block);
+        }
+        self
+    }
+}
+
+fn camel_case_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    camel_case_keys(&mut value);
+                    (snake_to_camel(&key), value)
+                })
+                .collect();
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(camel_case_keys),
+        _ => {}
+    }
+}
+
+fn snake_to_camel(key: &str) -> String {
+    let mut parts = key.split('_');
+    let mut res = parts.next().unwrap_or_default().to_owned();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            res.extend(first.to_uppercase());
+            res.push_str(chars.as_str());
+        }
+    }
+    res
+}
+
+/// Summary of the stored data
+#[derive(Clone, Serialize)]
+pub struct Stats {
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Estimated, exact count would be too slow on a big table
+    pub block_count: i64,
+    /// Estimated, exact count would be too slow on a big table
+    pub transaction_count: i64,
+}
+
+/// Number of the operations of the given operation and transaction type
+#[derive(Clone, Queryable)]
+pub struct OperationCount {
+    pub op_type: OperationType,
+    pub tx_type: i16,
+    pub count: i64,
+}
+
+/// Length of the histogram intervals, aligned to UTC
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Interval {
+    Hour,
+    Day,
+}
+
+impl Interval {
+    pub fn millis(&self) -> i64 {
+        match self {
+            Interval::Hour => 60 * 60 * 1000,
+            Interval::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+/// Number of the operations within the interval
+#[derive(Clone, Queryable)]
+pub struct HistogramBucket {
+    /// Start of the interval, Unix time in ms
+    pub start: i64,
+    pub count: i64,
+}
+
+/// Optional data to fetch along with the operations
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Include {
+    /// Metadata of the block of the operation
+    pub block: bool,
+    /// Time the operation was stored by the consumer
+    pub created_at: bool,
+    /// Whether the operation is from a microblock not finalized yet
+    pub is_microblock: bool,
+}
+
+/// Type of the Ethereum transactions, as stored in the `tx_type` column
+pub const ETHEREUM_TX_TYPE: i16 = 18;
+
+/// Whether the transaction is a native Waves one or an Ethereum one
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Origin {
+    Waves,
+    Ethereum,
+}
+
+/// Operations filters, `None` means no filtering
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Filters {
+    pub op_types: Option<Vec<OperationType>>,
+    pub sender: Option<String>,
+    /// Asset id of the fee, `WAVES` for Waves
+    pub fee_asset: Option<String>,
+    /// Whether the operation has any payments attached, only invoke scripts have payments at all
+    pub has_payment: Option<bool>,
+    pub origin: Option<Origin>,
+    /// Lowest fee amount (inclusive), in the units of the fee asset
+    pub fee_gte: Option<i64>,
+    /// Highest fee amount (inclusive), in the units of the fee asset
+    pub fee_lte: Option<i64>,
 }
 
 pub struct Page<TxUID> {
+    /// Inclusive start of the page (in the sort order)
     pub start: Option<TxUID>,
     pub limit: u32,
+    /// Only operations with uid greater than this one, regardless of the sort order
+    pub since: Option<TxUID>,
 }
 
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
 pub enum Sort {
     Asc,
     #[default]
     Desc,
 }
 
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Asc => "asc",
+            Sort::Desc => "desc",
+        }
+    }
+}
+
 pub mod postgres {
+    use std::time::Duration;
+
     use async_trait::async_trait;
-    use diesel::{prelude::*, QueryDsl};
+    use diesel::{
+        deserialize,
+        dsl::sql,
+        expression::SqlLiteral,
+        pg::Pg,
+        prelude::*,
+        sql_query,
+        sql_types::{BigInt, Bool, Jsonb, Text},
+        QueryDsl,
+    };
+    use moka::future::Cache;
 
     use super::Repo;
-    use super::{Operation, OperationType, Page, Sort};
-    use crate::schema::transactions;
+    use super::{
+        Filters, HistogramBucket, Include, Interval, Operation, OperationCount, OperationType, Origin, Page, Sort,
+        Stats, ETHEREUM_TX_TYPE,
+    };
+    use crate::schema::{blocks_microblocks, transactions};
     use crate::service::db::pool::PgPool;
 
+    /// Operations without the `payment` field match neither `true` nor `false`
+    fn payment_filter(has_payment: bool) -> SqlLiteral<Bool> {
+        let op = if has_payment { ">" } else { "=" };
+        sql(&format!(
+            "jsonb_array_length(transactions.operation -> 'payment') {} 0",
+            op
+        ))
+    }
+
+    /// Time the transaction was stored, in ms since the epoch
+    fn created_at_millis() -> SqlLiteral<BigInt> {
+        sql("(extract(epoch FROM transactions.created_at) * 1000)::BIGINT")
+    }
+
+    /// Whether the block is a microblock of the last (liquid) block, i.e. not finalized yet.
+    /// The `is_microblock` column itself is never cleared: a microblock is finalized by the next key block,
+    /// and the last block is the one with the greatest uid (there is no index on the height).
+    fn unfinalized_microblock() -> SqlLiteral<Bool> {
+        sql("(blocks_microblocks.is_microblock AND blocks_microblocks.height = \
+             (SELECT height FROM blocks_microblocks ORDER BY uid DESC LIMIT 1))")
+    }
+
+    /// Normalized query parameters: filters, page start, since uid, page limit, sort, include
+    type CacheKey = (Filters, Option<i64>, Option<i64>, u32, Sort, Include);
+    type CacheValue = (Vec<Operation<i64>>, Option<i64>);
+
+    const CACHE_MAX_ENTRIES: u64 = 10_000;
+
+    /// Row count estimate of a table, as maintained by Postgres' autovacuum
+    #[derive(QueryableByName)]
+    struct TableEstimate {
+        #[diesel(sql_type = Text)]
+        name: String,
+        #[diesel(sql_type = BigInt)]
+        count: i64,
+    }
+
+    /// Operation without the block, from the `(uid, operation)` columns
+    impl Queryable<(BigInt, Jsonb), Pg> for Operation<i64> {
+        type Row = (i64, serde_json::Value);
+
+        fn build((tx_uid, body): Self::Row) -> deserialize::Result<Self> {
+            Ok(Operation {
+                tx_uid,
+                body,
+                uid: None,
+                block: None,
+                direction: None,
+                created_at: None,
+                is_microblock: None,
+            })
+        }
+    }
+
     pub struct PgRepo {
         pgpool: PgPool,
+        replica_pool: Option<PgPool>,
+        cache: Option<Cache<CacheKey, CacheValue>>,
     }
 
     impl PgRepo {
         pub fn new(pgpool: PgPool) -> Self {
-            PgRepo { pgpool }
+            PgRepo {
+                pgpool,
+                replica_pool: None,
+                cache: None,
+            }
+        }
+
+        /// Cache query results for the given time.
+        /// New blocks don't invalidate the cache, so the TTL should be short (few seconds).
+        pub fn with_cache(self, ttl: Duration) -> Self {
+            let cache = Cache::builder()
+                .max_capacity(CACHE_MAX_ENTRIES)
+                .time_to_live(ttl)
+                .build();
+            PgRepo {
+                cache: Some(cache),
+                ..self
+            }
+        }
+
+        /// Use the given pool for all the reads instead of the primary one
+        pub fn with_read_replica(self, replica_pool: PgPool) -> Self {
+            PgRepo {
+                replica_pool: Some(replica_pool),
+                ..self
+            }
+        }
+
+        fn read_pool(&self) -> &PgPool {
+            self.replica_pool.as_ref().unwrap_or(&self.pgpool)
         }
     }
 
+    /// Start of the interval of the block timestamp, plain integer arithmetic (timestamps are never negative)
+    fn interval_start(interval: Interval) -> SqlLiteral<BigInt> {
+        sql(&format!("blocks_microblocks.time_stamp / {0} * {0}", interval.millis()))
+    }
+
+    /// Filter the boxed query of the transactions (possibly joined with the blocks) by the `Filters`
+    macro_rules! apply_filters {
+        ($query:ident, $filters:ident) => {
+            if let Some(op_types) = $filters.op_types {
+                $query = $query.filter(transactions::op_type.eq_any(op_types));
+            }
+
+            if let Some(sender) = $filters.sender {
+                $query = $query.filter(transactions::sender.eq(sender));
+            }
+
+            if let Some(fee_asset) = $filters.fee_asset {
+                $query = $query.filter(transactions::fee_asset.eq(fee_asset));
+            }
+
+            if let Some(fee_gte) = $filters.fee_gte {
+                $query = $query.filter(transactions::fee_amount.ge(fee_gte));
+            }
+
+            if let Some(fee_lte) = $filters.fee_lte {
+                $query = $query.filter(transactions::fee_amount.le(fee_lte));
+            }
+
+            if let Some(has_payment) = $filters.has_payment {
+                $query = $query.filter(payment_filter(has_payment));
+            }
+
+            match $filters.origin {
+                Some(Origin::Waves) => $query = $query.filter(transactions::tx_type.ne(ETHEREUM_TX_TYPE)),
+                Some(Origin::Ethereum) => $query = $query.filter(transactions::tx_type.eq(ETHEREUM_TX_TYPE)),
+                None => {}
+            }
+        };
+    }
+
+    /// Page the boxed query of the transactions (possibly joined with the blocks) by uid, in the sort order,
+    /// one extra row is fetched to know whether there is a next page
+    macro_rules! apply_page {
+        ($query:ident, $page:ident, $sort:ident) => {
+            if let Some(from_uid) = $page.start {
+                match $sort {
+                    Sort::Asc => $query = $query.filter(transactions::uid.ge(from_uid)),
+                    Sort::Desc => $query = $query.filter(transactions::uid.le(from_uid)),
+                }
+            }
+
+            if let Some(since_uid) = $page.since {
+                $query = $query.filter(transactions::uid.gt(since_uid));
+            }
+
+            $query = $query.limit(($page.limit + 1) as i64);
+
+            match $sort {
+                Sort::Asc => $query = $query.order(transactions::uid.asc()),
+                Sort::Desc => $query = $query.order(transactions::uid.desc()),
+            }
+        };
+    }
+
     #[async_trait]
     impl Repo for PgRepo {
         type TxUID = i64;
 
         async fn fetch_operations(
             &self,
-            op_types: Option<Vec<OperationType>>,
-            sender: Option<String>,
+            mut filters: Filters,
             page: Page<Self::TxUID>,
             sort: Sort,
+            include: Include,
         ) -> anyhow::Result<(Vec<Operation<Self::TxUID>>, Option<Self::TxUID>)> {
             log::timer!("fetch_operations()");
-            let conn = self.pgpool.get().await?;
-            let mut res = conn
-                .interact(move |conn| {
-                    let mut query = transactions::table
-                        .select((transactions::uid, transactions::operation))
-                        .into_boxed();
 
-                    if let Some(op_types) = op_types {
-                        if !op_types.is_empty() {
-                            query = query.filter(transactions::op_type.eq_any(op_types));
-                        }
-                    }
+            filters.op_types = filters
+                .op_types
+                .map(|mut op_types| {
+                    op_types.sort_unstable_by_key(|&t| t as u8);
+                    op_types.dedup();
+                    op_types
+                })
+                .filter(|op_types| !op_types.is_empty());
 
-                    if let Some(sender) = sender {
-                        query = query.filter(transactions::sender.eq(sender));
-                    }
+            let cache_key = (filters.clone(), page.start, page.since, page.limit, sort, include);
+            if let Some(cache) = &self.cache {
+                if let Some(cached) = cache.get(&cache_key).await {
+                    log::trace!("fetch_operations() cache hit");
+                    return Ok(cached);
+                }
+            }
 
-                    if let Some(from_uid) = page.start {
-                        match sort {
-                            Sort::Asc => query = query.filter(transactions::uid.ge(from_uid)),
-                            Sort::Desc => query = query.filter(transactions::uid.le(from_uid)),
-                        }
+            let conn = self.read_pool().get().await?;
+            let mut res = conn
+                .interact(move |conn| -> QueryResult<Vec<Operation<i64>>> {
+                    // The join is only made if the block is needed, it doesn't filter anything out though:
+                    // every transaction has its block
+                    if include.block || include.is_microblock {
+                        let mut query = transactions::table
+                            .inner_join(
+                                blocks_microblocks::table.on(blocks_microblocks::uid.eq(transactions::block_uid)),
+                            )
+                            .into_boxed();
+                        apply_filters!(query, filters);
+                        apply_page!(query, page, sort);
+                        let rows = query
+                            .select((
+                                (transactions::uid, transactions::operation),
+                                (
+                                    blocks_microblocks::id,
+                                    blocks_microblocks::height,
+                                    blocks_microblocks::time_stamp,
+                                ),
+                                unfinalized_microblock(),
+                                created_at_millis(),
+                            ))
+                            .load::<(Operation<i64>, (String, i32, i64), bool, i64)>(conn)?;
+                        let operations = rows
+                            .into_iter()
+                            .map(|(operation, (id, height, timestamp), is_microblock, created_at)| {
+                                let operation = if include.block {
+                                    operation.with_block(id, height, timestamp)
+                                } else {
+                                    operation
+                                };
+                                let operation = if include.is_microblock {
+                                    operation.with_is_microblock(is_microblock)
+                                } else {
+                                    operation
+                                };
+                                operation.with_created_at(include.created_at.then_some(created_at))
+                            })
+                            .collect();
+                        Ok(operations)
+                    } else {
+                        let mut query = transactions::table.into_boxed();
+                        apply_filters!(query, filters);
+                        apply_page!(query, page, sort);
+                        let rows = query
+                            .select(((transactions::uid, transactions::operation), created_at_millis()))
+                            .load::<(Operation<i64>, i64)>(conn)?;
+                        let operations = rows
+                            .into_iter()
+                            .map(|(operation, created_at)| {
+                                operation.with_created_at(include.created_at.then_some(created_at))
+                            })
+                            .collect();
+                        Ok(operations)
                     }
+                })
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            let page = if res.len() > page.limit as usize {
+                let last = res.pop().expect("extra item");
+                Some(last.tx_uid)
+            } else {
+                None
+            };
+            let result = (res, page);
+            if let Some(cache) = &self.cache {
+                cache.insert(cache_key, result.clone()).await;
+            }
+            Ok(result)
+        }
 
-                    query = query.limit((page.limit + 1) as i64);
+        async fn fetch_operations_by_block(
+            &self,
+            block_id: &str,
+        ) -> anyhow::Result<Option<Vec<Operation<Self::TxUID>>>> {
+            log::timer!("fetch_operations_by_block()");
 
-                    match sort {
-                        Sort::Asc => query = query.order(transactions::uid.asc()),
-                        Sort::Desc => query = query.order(transactions::uid.desc()),
-                    }
+            let block_id = block_id.to_owned();
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                let block_uid = blocks_microblocks::table
+                    .select(blocks_microblocks::uid)
+                    .filter(blocks_microblocks::id.eq(block_id))
+                    .first::<i64>(conn)
+                    .optional()?;
+                let block_uid = match block_uid {
+                    Some(block_uid) => block_uid,
+                    None => return Ok(None),
+                };
+                transactions::table
+                    .select((transactions::uid, transactions::operation))
+                    .filter(transactions::block_uid.eq(block_uid))
+                    .order(transactions::uid.asc())
+                    .load::<Operation<i64>>(conn)
+                    .map(Some)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn fetch_operations_by_ids(&self, ids: Vec<String>) -> anyhow::Result<Vec<Operation<Self::TxUID>>> {
+            log::timer!("fetch_operations_by_ids()");
+
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                transactions::table
+                    .select((transactions::uid, transactions::operation))
+                    .filter(transactions::id.eq_any(ids))
+                    .order(transactions::uid.asc())
+                    .load::<Operation<i64>>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        /// Text representation of the stored JSONB, i.e. normalized by Postgres
+        async fn fetch_raw_operation(&self, id: &str) -> anyhow::Result<Option<String>> {
+            log::timer!("fetch_raw_operation()");
 
-                    query.load::<Operation<i64>>(conn)
+            let id = id.to_owned();
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                transactions::table
+                    .select(sql::<Text>("transactions.operation::text"))
+                    .filter(transactions::id.eq(id))
+                    .order(transactions::uid.desc())
+                    .first::<String>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn stats(&self) -> anyhow::Result<Stats> {
+            log::timer!("stats()");
+
+            let conn = self.read_pool().get().await?;
+            conn.interact(|conn| {
+                // Blocks are ordered by uid, so both ends are found by the uid index, unlike min/max of height
+                let min_height = blocks_microblocks::table
+                    .select(blocks_microblocks::height)
+                    .order(blocks_microblocks::uid.asc())
+                    .first::<i32>(conn)
+                    .optional()?;
+                let max_height = blocks_microblocks::table
+                    .select(blocks_microblocks::height)
+                    .order(blocks_microblocks::uid.desc())
+                    .first::<i32>(conn)
+                    .optional()?;
+                // `reltuples` is -1 for a table that was never analyzed,
+                // and a partitioned table has no rows of its own, they are in its partitions
+                let estimates = sql_query(
+                    "SELECT parent.relname::TEXT AS name, \
+                            SUM(GREATEST(COALESCE(child.reltuples, parent.reltuples), 0))::BIGINT AS count \
+                     FROM pg_class parent \
+                          LEFT JOIN pg_inherits ON pg_inherits.inhparent = parent.oid \
+                          LEFT JOIN pg_class child ON child.oid = pg_inherits.inhrelid \
+                     WHERE parent.relname IN ('blocks_microblocks', 'transactions') AND parent.relkind IN ('r', 'p') \
+                     GROUP BY parent.relname",
+                )
+                .load::<TableEstimate>(conn)?;
+                let count_of = |table: &str| {
+                    estimates
+                        .iter()
+                        .find(|e| e.name == table)
+                        .map(|e| e.count)
+                        .unwrap_or_default()
+                };
+                Ok::<_, diesel::result::Error>(Stats {
+                    min_height: min_height.map(|h| h as u32),
+                    max_height: max_height.map(|h| h as u32),
+                    block_count: count_of("blocks_microblocks"),
+                    transaction_count: count_of("transactions"),
+                })
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn last_block_timestamp(&self) -> anyhow::Result<Option<i64>> {
+            log::timer!("last_block_timestamp()");
+
+            let conn = self.read_pool().get().await?;
+            conn.interact(|conn| {
+                blocks_microblocks::table
+                    .select(blocks_microblocks::time_stamp)
+                    .order(blocks_microblocks::uid.desc())
+                    .first::<i64>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn distinct_senders(
+            &self,
+            op_types: Option<Vec<OperationType>>,
+            limit: u32,
+        ) -> anyhow::Result<Vec<String>> {
+            log::timer!("distinct_senders()");
+
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                let mut query = transactions::table.select(transactions::sender).distinct().into_boxed();
+
+                if let Some(op_types) = op_types.filter(|op_types| !op_types.is_empty()) {
+                    query = query.filter(transactions::op_type.eq_any(op_types));
+                }
+
+                query
+                    .order(transactions::sender.asc())
+                    .limit(limit as i64)
+                    .load::<String>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn operation_counts(
+            &self,
+            min_height: Option<u32>,
+            max_height: Option<u32>,
+        ) -> anyhow::Result<Vec<OperationCount>> {
+            log::timer!("operation_counts()");
+
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                // Filtered by the height of the transactions, not of the blocks,
+                // so that only the matching partitions are scanned if the table is partitioned
+                let mut query = transactions::table
+                    .group_by((transactions::op_type, transactions::tx_type))
+                    .select((transactions::op_type, transactions::tx_type, diesel::dsl::count_star()))
+                    .into_boxed();
+
+                if let Some(min_height) = min_height {
+                    query = query.filter(transactions::height.ge(min_height as i32));
+                }
+
+                if let Some(max_height) = max_height {
+                    query = query.filter(transactions::height.le(max_height as i32));
+                }
+
+                query.load::<OperationCount>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+        async fn operation_histogram(
+            &self,
+            mut filters: Filters,
+            interval: Interval,
+        ) -> anyhow::Result<Vec<HistogramBucket>> {
+            log::timer!("operation_histogram()");
+
+            filters.op_types = filters.op_types.filter(|op_types| !op_types.is_empty());
+            let conn = self.read_pool().get().await?;
+            conn.interact(move |conn| {
+                let start = interval_start(interval);
+                let mut query = transactions::table
+                    .inner_join(blocks_microblocks::table.on(blocks_microblocks::uid.eq(transactions::block_uid)))
+                    .group_by(start.clone())
+                    .select((start.clone(), diesel::dsl::count_star()))
+                    .order(start)
+                    .into_boxed();
+
+                apply_filters!(query, filters);
+
+                query.load::<HistogramBucket>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+    }
+}
+
+/// Same as the Postgres repo, but on SQLite, for local development only (no caching, no replicas)
+#[cfg(feature = "sqlite")]
+pub mod sqlite {
+    use async_trait::async_trait;
+    use diesel::{
+        dsl::{count_star, sql},
+        expression::SqlLiteral,
+        prelude::*,
+        sql_types::{BigInt, Bool, Nullable},
+        QueryDsl,
+    };
+
+    use super::Repo;
+    use super::{
+        Filters, HistogramBucket, Include, Interval, Operation, OperationCount, OperationType, Origin, Page, Sort,
+        Stats, ETHEREUM_TX_TYPE,
+    };
+    use crate::schema_sqlite::{blocks_microblocks, transactions};
+    use crate::service::db::sqlite_pool::SqlitePool;
+
+    pub struct SqliteRepo {
+        pool: SqlitePool,
+    }
+
+    impl SqliteRepo {
+        pub fn new(pool: SqlitePool) -> Self {
+            SqliteRepo { pool }
+        }
+    }
+
+    /// Operation body is stored as JSON text
+    fn operation(tx_uid: i64, body: &str) -> QueryResult<Operation<i64>> {
+        let body = serde_json::from_str(body).map_err(|e| diesel::result::Error::DeserializationError(e.into()))?;
+        Ok(Operation {
+            tx_uid,
+            body,
+            uid: None,
+            block: None,
+            direction: None,
+            created_at: None,
+            is_microblock: None,
+        })
+    }
+
+    /// Time the transaction was stored, in ms since the epoch, none for the ones stored before it was recorded
+    fn created_at_millis() -> SqlLiteral<Nullable<BigInt>> {
+        sql("CAST(strftime('%s', transactions.created_at) AS INTEGER) * 1000")
+    }
+
+    /// Same as in Postgres: a microblock of the last block, not finalized yet
+    fn unfinalized_microblock() -> SqlLiteral<Bool> {
+        sql("(blocks_microblocks.is_microblock AND blocks_microblocks.height = \
+             (SELECT height FROM blocks_microblocks ORDER BY uid DESC LIMIT 1))")
+    }
+
+    fn operations(rows: Vec<(i64, String)>) -> QueryResult<Vec<Operation<i64>>> {
+        rows.into_iter().map(|(uid, body)| operation(uid, &body)).collect()
+    }
+
+    fn op_types_str(op_types: &[OperationType]) -> Vec<&'static str> {
+        op_types.iter().map(OperationType::as_str).collect()
+    }
+
+    fn payment_filter(has_payment: bool) -> SqlLiteral<Bool> {
+        let op = if has_payment { ">" } else { "=" };
+        sql(&format!(
+            "json_array_length(transactions.operation, '$.payment') {} 0",
+            op
+        ))
+    }
+
+    /// Start of the interval of the block timestamp, plain integer arithmetic (timestamps are never negative)
+    fn interval_start(interval: Interval) -> SqlLiteral<BigInt> {
+        sql(&format!("blocks_microblocks.time_stamp / {0} * {0}", interval.millis()))
+    }
+
+    /// Filter the boxed query of the transactions (possibly joined with the blocks) by the `Filters`
+    macro_rules! apply_filters {
+        ($query:ident, $filters:ident) => {
+            if let Some(op_types) = $filters.op_types.filter(|op_types| !op_types.is_empty()) {
+                $query = $query.filter(transactions::op_type.eq_any(op_types_str(&op_types)));
+            }
+
+            if let Some(sender) = $filters.sender {
+                $query = $query.filter(transactions::sender.eq(sender));
+            }
+
+            if let Some(fee_asset) = $filters.fee_asset {
+                $query = $query.filter(transactions::fee_asset.eq(fee_asset));
+            }
+
+            if let Some(fee_gte) = $filters.fee_gte {
+                $query = $query.filter(transactions::fee_amount.ge(fee_gte));
+            }
+
+            if let Some(fee_lte) = $filters.fee_lte {
+                $query = $query.filter(transactions::fee_amount.le(fee_lte));
+            }
+
+            if let Some(has_payment) = $filters.has_payment {
+                $query = $query.filter(payment_filter(has_payment));
+            }
+
+            match $filters.origin {
+                Some(Origin::Waves) => $query = $query.filter(transactions::tx_type.ne(ETHEREUM_TX_TYPE)),
+                Some(Origin::Ethereum) => $query = $query.filter(transactions::tx_type.eq(ETHEREUM_TX_TYPE)),
+                None => {}
+            }
+        };
+    }
+
+    /// Page the boxed query of the transactions (possibly joined with the blocks) by uid, in the sort order,
+    /// one extra row is fetched to know whether there is a next page
+    macro_rules! apply_page {
+        ($query:ident, $page:ident, $sort:ident) => {
+            if let Some(from_uid) = $page.start {
+                match $sort {
+                    Sort::Asc => $query = $query.filter(transactions::uid.ge(from_uid)),
+                    Sort::Desc => $query = $query.filter(transactions::uid.le(from_uid)),
+                }
+            }
+
+            if let Some(since_uid) = $page.since {
+                $query = $query.filter(transactions::uid.gt(since_uid));
+            }
+
+            $query = $query.limit(($page.limit + 1) as i64);
+
+            match $sort {
+                Sort::Asc => $query = $query.order(transactions::uid.asc()),
+                Sort::Desc => $query = $query.order(transactions::uid.desc()),
+            }
+        };
+    }
+
+    #[async_trait]
+    impl Repo for SqliteRepo {
+        type TxUID = i64;
+
+        async fn fetch_operations(
+            &self,
+            filters: Filters,
+            page: Page<Self::TxUID>,
+            sort: Sort,
+            include: Include,
+        ) -> anyhow::Result<(Vec<Operation<Self::TxUID>>, Option<Self::TxUID>)> {
+            let conn = self.pool.get().await?;
+            let mut res = conn
+                .interact(move |conn| {
+                    // Same as in Postgres, the join is only made if the block is needed
+                    if include.block || include.is_microblock {
+                        let mut query = transactions::table
+                            .inner_join(
+                                blocks_microblocks::table.on(blocks_microblocks::uid.eq(transactions::block_uid)),
+                            )
+                            .into_boxed();
+                        apply_filters!(query, filters);
+                        apply_page!(query, page, sort);
+                        query
+                            .select((
+                                transactions::uid,
+                                transactions::operation,
+                                blocks_microblocks::id,
+                                blocks_microblocks::height,
+                                blocks_microblocks::time_stamp,
+                                unfinalized_microblock(),
+                                created_at_millis(),
+                            ))
+                            .load::<(i64, String, String, i32, i64, bool, Option<i64>)>(conn)?
+                            .into_iter()
+                            .map(|(uid, body, id, height, timestamp, is_microblock, created_at)| {
+                                let op = operation(uid, &body)?;
+                                let op = if include.block {
+                                    op.with_block(id, height, timestamp)
+                                } else {
+                                    op
+                                };
+                                let op = if include.is_microblock {
+                                    op.with_is_microblock(is_microblock)
+                                } else {
+                                    op
+                                };
+                                Ok(op.with_created_at(created_at.filter(|_| include.created_at)))
+                            })
+                            .collect::<QueryResult<Vec<_>>>()
+                    } else {
+                        let mut query = transactions::table.into_boxed();
+                        apply_filters!(query, filters);
+                        apply_page!(query, page, sort);
+                        query
+                            .select((transactions::uid, transactions::operation, created_at_millis()))
+                            .load::<(i64, String, Option<i64>)>(conn)?
+                            .into_iter()
+                            .map(|(uid, body, created_at)| {
+                                Ok(operation(uid, &body)?.with_created_at(created_at.filter(|_| include.created_at)))
+                            })
+                            .collect::<QueryResult<Vec<_>>>()
+                    }
                 })
                 .await
                 .map_err(|e| anyhow::anyhow!("{}", e))?
                 .map_err(|e| anyhow::anyhow!("{}", e))?;
-            let page = if res.len() > page.limit as usize {
+            let next = if res.len() > page.limit as usize {
                 let last = res.pop().expect("extra item");
                 Some(last.tx_uid)
             } else {
                 None
             };
-            Ok((res, page))
+            Ok((res, next))
+        }
+
+        async fn fetch_operations_by_block(
+            &self,
+            block_id: &str,
+        ) -> anyhow::Result<Option<Vec<Operation<Self::TxUID>>>> {
+            let block_id = block_id.to_owned();
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                let block_uid = blocks_microblocks::table
+                    .select(blocks_microblocks::uid)
+                    .filter(blocks_microblocks::id.eq(block_id))
+                    .first::<i64>(conn)
+                    .optional()?;
+                let block_uid = match block_uid {
+                    Some(block_uid) => block_uid,
+                    None => return Ok(None),
+                };
+                let rows = transactions::table
+                    .select((transactions::uid, transactions::operation))
+                    .filter(transactions::block_uid.eq(block_uid))
+                    .order(transactions::uid.asc())
+                    .load::<(i64, String)>(conn)?;
+                operations(rows).map(Some)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn fetch_operations_by_ids(&self, ids: Vec<String>) -> anyhow::Result<Vec<Operation<Self::TxUID>>> {
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                let rows = transactions::table
+                    .select((transactions::uid, transactions::operation))
+                    .filter(transactions::id.eq_any(ids))
+                    .order(transactions::uid.asc())
+                    .load::<(i64, String)>(conn)?;
+                operations(rows)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn fetch_raw_operation(&self, id: &str) -> anyhow::Result<Option<String>> {
+            let id = id.to_owned();
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                transactions::table
+                    .select(transactions::operation)
+                    .filter(transactions::id.eq(id))
+                    .order(transactions::uid.desc())
+                    .first::<String>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        /// Exact counts, SQLite databases are small
+        async fn stats(&self) -> anyhow::Result<Stats> {
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                let min_height = blocks_microblocks::table
+                    .select(blocks_microblocks::height)
+                    .order(blocks_microblocks::uid.asc())
+                    .first::<i32>(conn)
+                    .optional()?;
+                let max_height = blocks_microblocks::table
+                    .select(blocks_microblocks::height)
+                    .order(blocks_microblocks::uid.desc())
+                    .first::<i32>(conn)
+                    .optional()?;
+                let block_count = blocks_microblocks::table.select(count_star()).first::<i64>(conn)?;
+                let transaction_count = transactions::table.select(count_star()).first::<i64>(conn)?;
+                Ok::<_, diesel::result::Error>(Stats {
+                    min_height: min_height.map(|h| h as u32),
+                    max_height: max_height.map(|h| h as u32),
+                    block_count,
+                    transaction_count,
+                })
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn last_block_timestamp(&self) -> anyhow::Result<Option<i64>> {
+            let conn = self.pool.get().await?;
+            conn.interact(|conn| {
+                blocks_microblocks::table
+                    .select(blocks_microblocks::time_stamp)
+                    .order(blocks_microblocks::uid.desc())
+                    .first::<i64>(conn)
+                    .optional()
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+
+        async fn distinct_senders(
+            &self,
+            op_types: Option<Vec<OperationType>>,
+            limit: u32,
+        ) -> anyhow::Result<Vec<String>> {
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                let mut query = transactions::table.select(transactions::sender).distinct().into_boxed();
+
+                if let Some(op_types) = op_types.filter(|op_types| !op_types.is_empty()) {
+                    query = query.filter(transactions::op_type.eq_any(op_types_str(&op_types)));
+                }
+
+                query
+                    .order(transactions::sender.asc())
+                    .limit(limit as i64)
+                    .load::<String>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
         }
+
+        async fn operation_counts(
+            &self,
+            min_height: Option<u32>,
+            max_height: Option<u32>,
+        ) -> anyhow::Result<Vec<OperationCount>> {
+            let conn = self.pool.get().await?;
+            let rows = conn
+                .interact(move |conn| {
+                    let mut query = transactions::table
+                        .group_by((transactions::op_type, transactions::tx_type))
+                        .select((transactions::op_type, transactions::tx_type, count_star()))
+                        .into_boxed();
+
+                    if let Some(min_height) = min_height {
+                        query = query.filter(transactions::height.ge(min_height as i32));
+                    }
+
+                    if let Some(max_height) = max_height {
+                        query = query.filter(transactions::height.le(max_height as i32));
+                    }
+
+                    query.load::<(String, i16, i64)>(conn)
+                })
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            rows.into_iter()
+                .map(|(op_type, tx_type, count)| {
+                    let op_type = OperationType::parse(&op_type)
+                        .ok_or_else(|| anyhow::anyhow!("unknown operation type {}", op_type))?;
+                    Ok(OperationCount {
+                        op_type,
+                        tx_type,
+                        count,
+                    })
+                })
+                .collect()
+        }
+
+        async fn operation_histogram(
+            &self,
+            filters: Filters,
+            interval: Interval,
+        ) -> anyhow::Result<Vec<HistogramBucket>> {
+            let conn = self.pool.get().await?;
+            conn.interact(move |conn| {
+                let start = interval_start(interval);
+                let mut query = transactions::table
+                    .inner_join(blocks_microblocks::table.on(blocks_microblocks::uid.eq(transactions::block_uid)))
+                    .group_by(start.clone())
+                    .select((start.clone(), count_star()))
+                    .order(start)
+                    .into_boxed();
+
+                apply_filters!(query, filters);
+
+                query.load::<HistogramBucket>(conn)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+        }
+    }
+}
+
+/// In-memory repo for the tests of the web server.
+/// The operations are given in blockchain order, the uid of an operation is its position (starting from 1).
+#[cfg(test)]
+pub mod memory {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::RwLock;
+
+    use async_trait::async_trait;
+
+    use super::Repo;
+    use super::{
+        Filters, HistogramBucket, Include, Interval, Operation, OperationCount, OperationType, Origin, Page, Sort,
+        Stats, ETHEREUM_TX_TYPE,
+    };
+
+    #[derive(Default)]
+    pub struct MemoryRepo {
+        /// Only appended to, as by the consumer
+        operations: RwLock<Vec<serde_json::Value>>,
+        /// Every query fails, as if the database was down
+        failing: bool,
+        /// Number of the `fetch_operations` calls so far
+        fetch_count: AtomicUsize,
+        /// Timestamp of the last stored block, the operations don't know their blocks
+        last_block_timestamp: Option<i64>,
+    }
+
+    impl MemoryRepo {
+        pub fn new(operations: Vec<serde_json::Value>) -> Self {
+            MemoryRepo {
+                operations: RwLock::new(operations),
+                ..MemoryRepo::default()
+            }
+        }
+
+        /// Store another operation, after all the others
+        pub fn push(&self, operation: serde_json::Value) {
+            self.operations.write().unwrap().push(operation);
+        }
+
+        pub fn failing() -> Self {
+            MemoryRepo {
+                failing: true,
+                ..MemoryRepo::default()
+            }
+        }
+
+        pub fn with_last_block_timestamp(self, timestamp: i64) -> Self {
+            MemoryRepo {
+                last_block_timestamp: Some(timestamp),
+                ..self
+            }
+        }
+
+        pub fn fetch_count(&self) -> usize {
+            self.fetch_count.load(Ordering::Relaxed)
+        }
+
+        fn check(&self) -> anyhow::Result<()> {
+            if self.failing {
+                return Err(anyhow::anyhow!("connection refused").context("database is down"));
+            }
+            Ok(())
+        }
+
+        fn bodies(&self) -> Vec<serde_json::Value> {
+            self.operations.read().unwrap().clone()
+        }
+
+        fn operations(&self) -> impl DoubleEndedIterator<Item = Operation<i64>> {
+            self.bodies().into_iter().enumerate().map(|(i, body)| Operation {
+                tx_uid: i as i64 + 1,
+                body,
+                uid: None,
+                block: None,
+                direction: None,
+                created_at: None,
+                is_microblock: None,
+            })
+        }
+    }
+
+    /// Same as the `apply_filters` of the Postgres repo
+    fn matches(filters: &Filters, body: &serde_json::Value) -> bool {
+        let str_field = |path| body.pointer(path).and_then(serde_json::Value::as_str);
+        let fee = body.pointer("/fee/amount").and_then(serde_json::Value::as_i64);
+        if let Some(op_types) = &filters.op_types {
+            let op_type = str_field("/type").and_then(OperationType::parse);
+            if !op_type.is_some_and(|t| op_types.contains(&t)) {
+                return false;
+            }
+        }
+        if let Some(sender) = &filters.sender {
+            if str_field("/sender") != Some(sender) {
+                return false;
+            }
+        }
+        if let Some(fee_asset) = &filters.fee_asset {
+            if str_field("/fee/id").unwrap_or("WAVES") != fee_asset {
+                return false;
+            }
+        }
+        if let Some(fee_gte) = filters.fee_gte {
+            if !matches!(fee, Some(fee) if fee >= fee_gte) {
+                return false;
+            }
+        }
+        if let Some(fee_lte) = filters.fee_lte {
+            if !matches!(fee, Some(fee) if fee <= fee_lte) {
+                return false;
+            }
+        }
+        if let Some(has_payment) = filters.has_payment {
+            let payment = body.get("payment").and_then(serde_json::Value::as_array);
+            if payment.map(|payment| !payment.is_empty()) != Some(has_payment) {
+                return false;
+            }
+        }
+        if let Some(origin) = filters.origin {
+            let tx_type = body.get("origin_transaction_type").and_then(serde_json::Value::as_i64);
+            if (tx_type == Some(ETHEREUM_TX_TYPE as i64)) != (origin == Origin::Ethereum) {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[async_trait]
+    impl Repo for MemoryRepo {
+        type TxUID = i64;
+
+        async fn fetch_operations(
+            &self,
+            filters: Filters,
+            page: Page<Self::TxUID>,
+            sort: Sort,
+            _include: Include,
+        ) -> anyhow::Result<(Vec<Operation<Self::TxUID>>, Option<Self::TxUID>)> {
+            self.fetch_count.fetch_add(1, Ordering::Relaxed);
+            self.check()?;
+            let operations: Box<dyn Iterator<Item = Operation<i64>>> = match sort {
+                Sort::Asc => Box::new(self.operations()),
+                Sort::Desc => Box::new(self.operations().rev()),
+            };
+            let mut res = operations
+                .filter(|operation| matches(&filters, &operation.body))
+                .filter(|operation| match (page.start, sort) {
+                    (None, _) => true,
+                    (Some(start), Sort::Asc) => operation.tx_uid >= start,
+                    (Some(start), Sort::Desc) => operation.tx_uid <= start,
+                })
+                .filter(|operation| match page.since {
+                    Some(since) => operation.tx_uid > since,
+                    None => true,
+                })
+                .take(page.limit as usize + 1)
+                .collect::<Vec<_>>();
+            let next = if res.len() > page.limit as usize {
+                res.pop().map(|operation| operation.tx_uid)
+            } else {
+                None
+            };
+            Ok((res, next))
+        }
+
+        async fn fetch_operations_by_block(&self, _block_id: &str) -> anyhow::Result<Option<Vec<Operation<i64>>>> {
+            self.check()?;
+            Ok(None)
+        }
+
+        async fn fetch_operations_by_ids(&self, ids: Vec<String>) -> anyhow::Result<Vec<Operation<i64>>> {
+            self.check()?;
+            let has_id = |operation: &Operation<i64>| {
+                let id = operation.body.get("id").and_then(serde_json::Value::as_str);
+                id.is_some_and(|id| ids.iter().any(|i| i == id))
+            };
+            Ok(self.operations().filter(has_id).collect())
+        }
+
+        async fn fetch_raw_operation(&self, id: &str) -> anyhow::Result<Option<String>> {
+            self.check()?;
+            let operation = self
+                .bodies()
+                .into_iter()
+                .find(|body| body.get("id").and_then(|v| v.as_str()) == Some(id));
+            Ok(operation.map(|body| body.to_string()))
+        }
+
+        async fn stats(&self) -> anyhow::Result<Stats> {
+            self.check()?;
+            Ok(Stats {
+                min_height: None,
+                max_height: None,
+                block_count: 0,
+                transaction_count: self.operations.read().unwrap().len() as i64,
+            })
+        }
+
+        async fn last_block_timestamp(&self) -> anyhow::Result<Option<i64>> {
+            self.check()?;
+            Ok(self.last_block_timestamp)
+        }
+
+        async fn distinct_senders(
+            &self,
+            _op_types: Option<Vec<OperationType>>,
+            _limit: u32,
+        ) -> anyhow::Result<Vec<String>> {
+            self.check()?;
+            Ok(Vec::new())
+        }
+
+        async fn operation_counts(
+            &self,
+            min_height: Option<u32>,
+            max_height: Option<u32>,
+        ) -> anyhow::Result<Vec<OperationCount>> {
+            self.check()?;
+            let mut counts = Vec::<OperationCount>::new();
+            for body in &self.bodies() {
+                let height = body["height"].as_u64().unwrap_or(0) as u32;
+                if min_height.is_some_and(|min| height < min) || max_height.is_some_and(|max| height > max) {
+                    continue;
+                }
+                let Some(op_type) = body["type"].as_str().and_then(OperationType::parse) else {
+                    continue;
+                };
+                let tx_type = body["origin_transaction_type"].as_i64().unwrap_or(0) as i16;
+                match counts.iter_mut().find(|c| c.op_type == op_type && c.tx_type == tx_type) {
+                    Some(count) => count.count += 1,
+                    None => counts.push(OperationCount {
+                        op_type,
+                        tx_type,
+                        count: 1,
+                    }),
+                }
+            }
+            Ok(counts)
+        }
+
+        /// By the `timestamp` of the operation, the operations don't know their blocks
+        async fn operation_histogram(
+            &self,
+            filters: Filters,
+            interval: Interval,
+        ) -> anyhow::Result<Vec<HistogramBucket>> {
+            self.check()?;
+            let mut buckets = Vec::<HistogramBucket>::new();
+            for body in self.bodies().iter().filter(|body| matches(&filters, body)) {
+                let timestamp = body["timestamp"].as_str().unwrap_or_default();
+                let millis = chrono::DateTime::parse_from_rfc3339(timestamp)?.timestamp_millis();
+                let start = millis - millis.rem_euclid(interval.millis());
+                match buckets.iter_mut().find(|bucket| bucket.start == start) {
+                    Some(bucket) => bucket.count += 1,
+                    None => buckets.push(HistogramBucket { start, count: 1 }),
+                }
+            }
+            buckets.sort_by_key(|bucket| bucket.start);
+            Ok(buckets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Operation;
+
+    fn operation(body: serde_json::Value) -> Operation<i64> {
+        Operation {
+            tx_uid: 1,
+            uid: None,
+            body,
+            block: None,
+            direction: None,
+            created_at: None,
+            is_microblock: None,
+        }
+    }
+
+    #[test]
+    fn direction() {
+        let invoke = json!({ "type": "invoke_script", "sender": "3PSender", "dapp": "3PDapp" });
+        assert_eq!(
+            operation(invoke.clone()).with_direction("3PSender").direction(),
+            Some("outgoing")
+        );
+        assert_eq!(operation(invoke).with_direction("3PDapp").direction(), None);
+
+        let lease = json!({ "type": "lease", "sender": "3PSender", "recipient": "3PRecipient" });
+        assert_eq!(
+            operation(lease.clone()).with_direction("3PSender").direction(),
+            Some("outgoing")
+        );
+        assert_eq!(operation(lease).with_direction("3PRecipient").direction(), None);
+
+        // Leased to itself
+        let lease = json!({ "type": "lease", "sender": "3PSender", "recipient": "3PSender" });
+        assert_eq!(
+            operation(lease).with_direction("3PSender").direction(),
+            Some("outgoing")
+        );
     }
 }
@@ -0,0 +1,424 @@
+//! Consumer's Postgres storage, see `common` for the database the tests run on.
+
+mod common;
+
+use diesel::sql_types::{BigInt, Binary, Bool, Integer, SmallInt, Text};
+use diesel::{sql_query, QueryableByName, RunQueryDsl};
+
+use lib::common::database::types::OperationType;
+use lib::consumer::storage::{
+    is_transient, BlockUID, NewQuarantinedTransaction, NewTransaction, PostgresStorage, Repo, Storage,
+};
+
+use common::TestDb;
+
+fn storage(db: &TestDb) -> PostgresStorage {
+    PostgresStorage::new(db.connect(), &db.url())
+}
+
+fn insert_tx(repo: &mut impl Repo, id: &str, block_uid: BlockUID, height: u32) -> anyhow::Result<()> {
+    repo.insert_tx(&NewTransaction {
+        id,
+        block_uid,
+        sender: "3PSender",
+        tx_type: 16,
+        op_type: OperationType::InvokeScript,
+        fee_amount: 500000,
+        fee_asset: "WAVES",
+        operation: &serde_json::json!({ "id": id }),
+        height: height as i32,
+    })
+}
+
+#[tokio::test]
+async fn rollback_to_block_deletes_blocks_and_transactions_after_it() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    let (kept, ids) = storage
+        .transaction(|repo| {
+            let kept = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", kept, 1)?;
+            let micro = repo.insert_block("micro1", 1, 1001, true)?;
+            insert_tx(repo, "tx2", micro, 1)?;
+            let next = repo.insert_block("block2", 2, 2000, false)?;
+            insert_tx(repo, "tx3", next, 2)?;
+            assert_eq!(repo.key_block_uid(1)?, Some(kept));
+            assert_eq!(repo.key_block_uid(3)?, None);
+            Ok((kept, repo.transaction_ids(1, 2)?))
+        })
+        .await
+        .unwrap();
+    assert_eq!(ids, ["tx1", "tx2", "tx3"]);
+
+    let (rolled_back_to, last_height, ids) = storage
+        .transaction(move |repo| {
+            let rolled_back_to = repo.rollback_to_block(kept)?;
+            Ok((rolled_back_to, repo.last_height()?, repo.transaction_ids(1, 2)?))
+        })
+        .await
+        .unwrap();
+    assert_eq!(rolled_back_to, Some(1));
+    assert_eq!(last_height, Some(1));
+    assert_eq!(ids, ["tx1"]);
+}
+
+#[tokio::test]
+async fn failed_transaction_writes_nothing() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    let res = storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", block_uid, 1)?;
+            // Same id again
+            insert_tx(repo, "tx1", block_uid, 1)
+        })
+        .await;
+    assert!(res.is_err());
+
+    let last_height = storage.transaction(|repo| repo.last_height()).await.unwrap();
+    assert_eq!(last_height, None);
+}
+
+#[derive(QueryableByName, Debug, PartialEq)]
+struct StoredBlock {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Bool)]
+    is_microblock: bool,
+}
+
+#[tokio::test]
+async fn microblock_flag_is_stored() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    storage
+        .transaction(|repo| {
+            repo.insert_block("block1", 1, 1000, false)?;
+            repo.insert_block("micro1", 1, 1001, true)?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+    let blocks = sql_query("SELECT id, is_microblock FROM blocks_microblocks ORDER BY uid")
+        .load::<StoredBlock>(&mut db.connect())
+        .unwrap();
+    let block = |id: &str, is_microblock| StoredBlock {
+        id: id.to_owned(),
+        is_microblock,
+    };
+    assert_eq!(blocks, [block("block1", false), block("micro1", true)]);
+}
+
+#[tokio::test]
+async fn duplicate_block_is_not_transient() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+    storage
+        .transaction(|repo| repo.insert_block("block1", 1, 1000, false))
+        .await
+        .unwrap();
+
+    let err = storage
+        .transaction(|repo| repo.insert_block("block1", 2, 2000, false))
+        .await
+        .unwrap_err();
+    assert!(!is_transient(&err), "{}", err);
+}
+
+#[tokio::test]
+async fn reconnect_replaces_broken_connection() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+    storage
+        .transaction(|repo| repo.insert_block("block1", 1, 1000, false))
+        .await
+        .unwrap();
+
+    // Same as a connection lost in the middle of a write
+    storage
+        .transaction(|conn| Ok(sql_query("SELECT pg_terminate_backend(pg_backend_pid())").execute(conn)?))
+        .await
+        .unwrap_err();
+    let err = storage.transaction(|repo| repo.last_height()).await.unwrap_err();
+    assert!(is_transient(&err), "{}", err);
+
+    storage.reconnect().await.unwrap();
+    let last_height = storage.transaction(|repo| repo.last_height()).await.unwrap();
+    assert_eq!(last_height, Some(1));
+}
+
+#[derive(QueryableByName, Debug, PartialEq)]
+struct StoredTransaction {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = BigInt)]
+    block_uid: i64,
+    #[diesel(sql_type = Text)]
+    sender: String,
+    #[diesel(sql_type = SmallInt)]
+    tx_type: i16,
+    #[diesel(sql_type = Text)]
+    op_type: String,
+    #[diesel(sql_type = BigInt)]
+    fee_amount: i64,
+    #[diesel(sql_type = Text)]
+    fee_asset: String,
+    #[diesel(sql_type = Text)]
+    operation: String,
+    #[diesel(sql_type = Integer)]
+    height: i32,
+}
+
+#[tokio::test]
+async fn new_transaction_round_trip() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+    let operation = serde_json::json!({ "id": "tx1", "lease_id": "lease1" });
+
+    let block_uid = storage
+        .transaction(move |repo| {
+            let block_uid = repo.insert_block("block1", 7, 1000, false)?;
+            repo.insert_tx(&NewTransaction {
+                id: "tx1",
+                block_uid,
+                sender: "3PSender",
+                tx_type: 9,
+                op_type: OperationType::LeaseCancel,
+                fee_amount: 100000,
+                fee_asset: "Asset",
+                operation: &operation,
+                height: 7,
+            })?;
+            Ok(block_uid)
+        })
+        .await
+        .unwrap();
+
+    let stored = sql_query(
+        "SELECT id, block_uid, sender, tx_type, op_type::text AS op_type, fee_amount, fee_asset, \
+         operation::text AS operation, height FROM transactions",
+    )
+    .load::<StoredTransaction>(&mut db.connect())
+    .unwrap();
+    assert_eq!(
+        stored,
+        [StoredTransaction {
+            id: "tx1".to_owned(),
+            block_uid,
+            sender: "3PSender".to_owned(),
+            tx_type: 9,
+            op_type: "lease_cancel".to_owned(),
+            fee_amount: 100000,
+            fee_asset: "Asset".to_owned(),
+            operation: r#"{"id": "tx1", "lease_id": "lease1"}"#.to_owned(),
+            height: 7,
+        }]
+    );
+}
+
+#[derive(QueryableByName, Debug, PartialEq)]
+struct StoredQuarantined {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Binary)]
+    raw: Vec<u8>,
+    #[diesel(sql_type = Text)]
+    reason: String,
+}
+
+#[tokio::test]
+async fn quarantined_transaction_is_stored_and_rolled_back() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    let kept = storage
+        .transaction(|repo| {
+            let kept = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", kept, 1)?;
+            repo.insert_quarantined(&NewQuarantinedTransaction {
+                id: "tx2",
+                block_uid: kept,
+                raw: &[1, 2, 3],
+                reason: "transaction timestamp is zero",
+            })?;
+            let next = repo.insert_block("block2", 2, 2000, false)?;
+            repo.insert_quarantined(&NewQuarantinedTransaction {
+                id: "tx3",
+                block_uid: next,
+                raw: &[4],
+                reason: "invalid timestamp",
+            })?;
+            Ok(kept)
+        })
+        .await
+        .unwrap();
+    let ids = storage.transaction(|repo| repo.transaction_ids(1, 2)).await.unwrap();
+    assert_eq!(ids, ["tx1"]);
+
+    storage
+        .transaction(move |repo| repo.rollback_to_block(kept))
+        .await
+        .unwrap();
+    let stored = sql_query("SELECT id, raw, reason FROM quarantine ORDER BY uid")
+        .load::<StoredQuarantined>(&mut db.connect())
+        .unwrap();
+    assert_eq!(
+        stored,
+        [StoredQuarantined {
+            id: "tx2".to_owned(),
+            raw: vec![1, 2, 3],
+            reason: "transaction timestamp is zero".to_owned(),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn operation_is_rewritten_in_place() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    let (ids, updated, unknown) = storage
+        .transaction(|repo| {
+            let block1 = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", block1, 1)?;
+            let block2 = repo.insert_block("block2", 2, 2000, false)?;
+            insert_tx(repo, "tx2", block2, 2)?;
+            insert_tx(repo, "tx3", block2, 2)?;
+            let ids = repo.transaction_ids(2, 2)?;
+            let updated = repo.update_operation("tx2", &serde_json::json!({ "id": "tx2", "version": 2 }))?;
+            let unknown = repo.update_operation("tx4", &serde_json::json!({ "id": "tx4" }))?;
+            Ok((ids, updated, unknown))
+        })
+        .await
+        .unwrap();
+    assert_eq!(ids, ["tx2", "tx3"]);
+    assert!(updated);
+    assert!(!unknown);
+
+    let stored = sql_query(
+        "SELECT id, block_uid, sender, tx_type, op_type::text AS op_type, fee_amount, fee_asset, \
+         operation::text AS operation, height FROM transactions WHERE id = 'tx2'",
+    )
+    .load::<StoredTransaction>(&mut db.connect())
+    .unwrap();
+    assert_eq!(stored.len(), 1);
+    // Only the operation JSON is replaced
+    assert_eq!(stored[0].operation, r#"{"id": "tx2", "version": 2}"#);
+    assert_eq!((stored[0].sender.as_str(), stored[0].height), ("3PSender", 2));
+}
+
+#[tokio::test]
+async fn rollback_to_height_deletes_blocks_above_it() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    let (last_height, ids) = storage
+        .transaction(|repo| {
+            for height in 1..=3 {
+                let block_uid = repo.insert_block(&format!("block{}", height), height, height as u64 * 1000, false)?;
+                insert_tx(repo, &format!("tx{}", height), block_uid, height)?;
+                let micro_uid = repo.insert_block(&format!("micro{}", height), height, height as u64 * 1000, true)?;
+                insert_tx(repo, &format!("tx{}m", height), micro_uid, height)?;
+            }
+            repo.rollback_to_height(1)?;
+            Ok((repo.last_height()?, repo.transaction_ids(1, 3)?))
+        })
+        .await
+        .unwrap();
+    assert_eq!(last_height, Some(1));
+    assert_eq!(ids, ["tx1", "tx1m"]);
+
+    // The replacement block of a rolled back height can be stored
+    storage
+        .transaction(|repo| repo.insert_block("block2", 2, 2000, false))
+        .await
+        .unwrap();
+}
+
+#[derive(QueryableByName)]
+struct CreatedAt {
+    #[diesel(sql_type = Bool)]
+    is_set: bool,
+    #[diesel(sql_type = Bool)]
+    is_recent: bool,
+}
+
+#[tokio::test]
+async fn created_at_is_set_on_insert() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+
+    storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", block_uid, 1)
+        })
+        .await
+        .unwrap();
+
+    let created_at = sql_query(
+        "SELECT created_at IS NOT NULL AS is_set, created_at > now() - INTERVAL '1 minute' AS is_recent \
+         FROM transactions WHERE id = 'tx1'",
+    )
+    .get_result::<CreatedAt>(&mut db.connect())
+    .unwrap();
+    assert!(created_at.is_set);
+    assert!(created_at.is_recent);
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[tokio::test]
+async fn truncate_all_deletes_everything() {
+    let Some(db) = TestDb::create() else { return };
+    let storage = storage(&db);
+    storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", block_uid, 1)?;
+            repo.insert_quarantined(&NewQuarantinedTransaction {
+                id: "tx2",
+                block_uid,
+                raw: &[1],
+                reason: "invalid timestamp",
+            })
+        })
+        .await
+        .unwrap();
+
+    let last_height = storage
+        .transaction(|repo| {
+            repo.truncate_all()?;
+            repo.last_height()
+        })
+        .await
+        .unwrap();
+    assert_eq!(last_height, None);
+    let mut conn = db.connect();
+    for table in ["blocks_microblocks", "transactions", "quarantine"] {
+        let count = sql_query(format!("SELECT count(*) AS count FROM {}", table))
+            .get_result::<Count>(&mut conn)
+            .unwrap();
+        assert_eq!(count.count, 0, "{}", table);
+    }
+
+    // Synced again from scratch, with the uids restarted
+    let block_uid = storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block1", 1, 1000, false)?;
+            insert_tx(repo, "tx1", block_uid, 1)?;
+            Ok(block_uid)
+        })
+        .await
+        .unwrap();
+    assert_eq!(block_uid, 1);
+}
@@ -0,0 +1,231 @@
+//! Blocks and transactions partitioned by height with `migration -- partition`,
+//! see `common` for the database the tests run on.
+
+mod common;
+
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::{BigInt, Text};
+use diesel::{sql_query, Connection, PgConnection, QueryableByName, RunQueryDsl};
+
+use lib::common::database::types::OperationType;
+use lib::consumer::storage::{BlockUID, NewTransaction, PostgresStorage, Repo, Storage};
+use lib::service::db;
+use lib::service::repo::{postgres::PgRepo, Repo as _};
+
+use common::TestDb;
+
+const PARTITION_BY_HEIGHT_SQL: &str = include_str!("../scripts/partition_by_height.sql");
+
+/// Same as `migration -- partition <partition_size>`
+fn partition(conn: &mut PgConnection, partition_size: u32) {
+    let sql = PARTITION_BY_HEIGHT_SQL.replace("{partition_size}", &partition_size.to_string());
+    conn.transaction(|conn| conn.batch_execute(&sql))
+        .expect("failed to partition");
+}
+
+fn storage(db: &TestDb) -> PostgresStorage {
+    PostgresStorage::new(db.connect(), &db.url())
+}
+
+fn insert_tx(repo: &mut impl Repo, id: &str, block_uid: BlockUID, height: u32) -> anyhow::Result<()> {
+    repo.insert_tx(&NewTransaction {
+        id,
+        block_uid,
+        sender: "3PSender",
+        tx_type: 16,
+        op_type: OperationType::InvokeScript,
+        fee_amount: 500000,
+        fee_asset: "WAVES",
+        operation: &serde_json::json!({ "id": id }),
+        height: height as i32,
+    })
+}
+
+/// Block `block<height>` with the transaction `tx<height>` at each of the heights
+async fn insert_blocks(storage: &PostgresStorage, heights: &'static [u32]) {
+    storage
+        .transaction(move |repo| {
+            for &height in heights {
+                let block_uid = repo.insert_block(&format!("block{}", height), height, height as u64 * 1000, false)?;
+                insert_tx(repo, &format!("tx{}", height), block_uid, height)?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+}
+
+#[derive(QueryableByName, Debug, PartialEq)]
+struct Row {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Text)]
+    partition: String,
+}
+
+/// Ids of the rows of the table with the partitions they are stored in
+fn rows(conn: &mut PgConnection, table: &str) -> Vec<(String, String)> {
+    let sql = format!(
+        "SELECT id, tableoid::regclass::text AS partition FROM {} ORDER BY uid",
+        table
+    );
+    let rows = sql_query(sql).load::<Row>(conn).unwrap();
+    rows.into_iter().map(|row| (row.id, row.partition)).collect()
+}
+
+#[derive(QueryableByName)]
+struct Partition {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+fn count(conn: &mut PgConnection, table: &str) -> i64 {
+    let sql = format!("SELECT count(*) AS count FROM {}", table);
+    sql_query(sql).get_result::<Count>(conn).unwrap().count
+}
+
+fn row(id: &str, partition: &str) -> (String, String) {
+    (id.to_owned(), partition.to_owned())
+}
+
+#[tokio::test]
+async fn rows_are_stored_in_partitions_by_height() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    partition(&mut conn, 10);
+    let storage = storage(&db);
+
+    insert_blocks(&storage, &[5, 15, 20]).await;
+
+    let blocks = rows(&mut conn, "blocks_microblocks");
+    let expected = [
+        row("block5", "blocks_microblocks_h0"),
+        row("block15", "blocks_microblocks_h10"),
+        row("block20", "blocks_microblocks_h20"),
+    ];
+    assert_eq!(blocks, expected);
+    let transactions = rows(&mut conn, "transactions");
+    let expected = [
+        row("tx5", "transactions_h0"),
+        row("tx15", "transactions_h10"),
+        row("tx20", "transactions_h20"),
+    ];
+    assert_eq!(transactions, expected);
+}
+
+#[tokio::test]
+async fn rollback_drops_only_partitions_entirely_above_height() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    partition(&mut conn, 10);
+    let storage = storage(&db);
+    insert_blocks(&storage, &[5, 12, 15, 18, 25]).await;
+
+    let ids = storage
+        .transaction(|repo| {
+            repo.rollback_to_height(15)?;
+            repo.transaction_ids(1, 30)
+        })
+        .await
+        .unwrap();
+    assert_eq!(ids, ["tx5", "tx12", "tx15"]);
+
+    let partitions = sql_query(
+        "SELECT relname::text AS name FROM pg_class \
+         WHERE relkind = 'r' AND relname ~ '^(blocks_microblocks|transactions)_h' ORDER BY relname",
+    )
+    .load::<Partition>(&mut conn)
+    .unwrap();
+    let partitions = partitions.into_iter().map(|p| p.name).collect::<Vec<_>>();
+    let expected = [
+        "blocks_microblocks_h0",
+        "blocks_microblocks_h10",
+        "transactions_h0",
+        "transactions_h10",
+    ];
+    assert_eq!(partitions, expected);
+    assert_eq!(count(&mut conn, "block_keys"), 3);
+    assert_eq!(count(&mut conn, "transaction_keys"), 3);
+
+    // Ids of both the dropped and the deleted rows are free again
+    insert_blocks(&storage, &[18, 25]).await;
+    let ids = storage.transaction(|repo| repo.transaction_ids(1, 30)).await.unwrap();
+    assert_eq!(ids, ["tx5", "tx12", "tx15", "tx18", "tx25"]);
+}
+
+#[tokio::test]
+async fn ids_are_unique_across_partitions() {
+    let Some(db) = TestDb::create() else { return };
+    partition(&mut db.connect(), 10);
+    let storage = storage(&db);
+    insert_blocks(&storage, &[5]).await;
+
+    let res = storage
+        .transaction(|repo| repo.insert_block("block5", 15, 15000, false))
+        .await;
+    assert!(res.is_err());
+    let res = storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block15", 15, 15000, false)?;
+            insert_tx(repo, "tx5", block_uid, 15)
+        })
+        .await;
+    assert!(res.is_err());
+
+    // Same uid in another partition
+    let res = db.connect().batch_execute(
+        "INSERT INTO blocks_microblocks (uid, id, height, time_stamp) \
+         SELECT uid, 'block15', 15, 15000 FROM blocks_microblocks WHERE id = 'block5'",
+    );
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn seeded_data_is_converted() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    common::seed(&mut conn, 25, 2, 3);
+
+    partition(&mut conn, 10);
+    conn.batch_execute("ANALYZE blocks_microblocks; ANALYZE transactions;")
+        .unwrap();
+
+    assert_eq!(count(&mut conn, "blocks_microblocks_h0"), 9);
+    assert_eq!(count(&mut conn, "blocks_microblocks_h20"), 6);
+    assert_eq!(count(&mut conn, "transactions_h10"), 20);
+    assert_eq!(count(&mut conn, "block_keys"), 25);
+    assert_eq!(count(&mut conn, "transaction_keys"), 50);
+
+    // Estimates of a partitioned table are the sums of its partitions'
+    let repo = PgRepo::new(db::pool::new(&db.config, 2, None).unwrap());
+    let stats = repo.stats().await.unwrap();
+    assert_eq!((stats.min_height, stats.max_height), (Some(1), Some(25)));
+    assert_eq!((stats.block_count, stats.transaction_count), (25, 50));
+
+    // New uids continue after the converted ones
+    let storage = storage(&db);
+    insert_blocks(&storage, &[60]).await;
+    assert_eq!(count(&mut conn, "transactions WHERE id = 'tx60' AND uid = 51"), 1);
+}
+
+#[tokio::test]
+async fn truncate_all_clears_keys() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    partition(&mut conn, 10);
+    let storage = storage(&db);
+    insert_blocks(&storage, &[5, 15]).await;
+
+    storage.transaction(|repo| repo.truncate_all()).await.unwrap();
+    assert_eq!(count(&mut conn, "block_keys"), 0);
+    assert_eq!(count(&mut conn, "transaction_keys"), 0);
+
+    insert_blocks(&storage, &[5, 15]).await;
+    assert_eq!(count(&mut conn, "transactions"), 2);
+}
@@ -0,0 +1,735 @@
+//! Web service's Postgres repo, see `common` for the database the tests run on.
+
+mod common;
+
+use std::time::Duration;
+
+use diesel::sql_types::{Bool, Text};
+use diesel::{connection::SimpleConnection, sql_query, Connection, PgConnection, QueryableByName, RunQueryDsl};
+
+use lib::common::database::config::SslMode;
+use lib::common::database::types::OperationType;
+use lib::service::db;
+use lib::service::repo::{postgres::PgRepo, Filters, Include, Interval, Operation, Origin, Page, Repo, Sort};
+
+use common::TestDb;
+
+/// Ids of the first page of all the operations, newest first
+async fn first_page(repo: &PgRepo) -> Vec<String> {
+    let page = Page {
+        start: None,
+        limit: 10,
+        since: None,
+    };
+    let (operations, _) = repo
+        .fetch_operations(Filters::default(), page, Sort::Desc, Include::default())
+        .await
+        .unwrap();
+    operations
+        .iter()
+        .map(|operation| operation.body()["id"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+/// Another transaction in the last seeded block
+fn insert_tx(db: &TestDb, id: &str) {
+    let sql = format!(
+        "INSERT INTO transactions (id, block_uid, sender, tx_type, op_type, operation, fee_amount, fee_asset, height) \
+         SELECT '{id}', uid, 'sender0', 16, 'invoke_script', jsonb_build_object('id', '{id}'), 500000, 'WAVES', height \
+         FROM blocks_microblocks ORDER BY uid DESC LIMIT 1",
+        id = id
+    );
+    sql_query(sql).execute(&mut db.connect()).unwrap();
+}
+
+#[derive(QueryableByName)]
+struct PlanLine {
+    #[diesel(sql_type = Text, column_name = "QUERY PLAN")]
+    line: String,
+}
+
+fn repo(db: &TestDb) -> PgRepo {
+    PgRepo::new(db::pool::new(&db.config, 2, None).unwrap())
+}
+
+fn sender(name: &str) -> Filters {
+    Filters {
+        sender: Some(name.to_owned()),
+        ..Filters::default()
+    }
+}
+
+#[tokio::test]
+async fn sender_query_uses_keyset_index() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    common::seed(&mut conn, 500, 100, 1000);
+
+    // Same as the `fetch_operations` query of a page of the sender's operations
+    let plan = sql_query(
+        "EXPLAIN SELECT uid, operation FROM transactions \
+         WHERE sender = 'sender7' AND uid <= 40000 ORDER BY uid DESC LIMIT 21",
+    )
+    .load::<PlanLine>(&mut conn)
+    .unwrap()
+    .into_iter()
+    .map(|plan| plan.line)
+    .collect::<Vec<_>>()
+    .join("\n");
+    assert!(plan.contains("transactions__sender__uid__idx"), "{}", plan);
+    assert!(!plan.contains("Sort"), "{}", plan);
+    assert!(!plan.contains("Seq Scan"), "{}", plan);
+}
+
+#[tokio::test]
+async fn sender_pages_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 10, 10, 3);
+    let repo = repo(&db);
+
+    let mut ids = Vec::new();
+    let mut start = None;
+    loop {
+        let page = Page {
+            start,
+            limit: 4,
+            since: None,
+        };
+        let (operations, next) = repo
+            .fetch_operations(sender("sender1"), page, Sort::Desc, Include::default())
+            .await
+            .unwrap();
+        assert!(operations.len() <= 4);
+        ids.extend(
+            operations
+                .iter()
+                .map(|operation| operation.body()["id"].as_str().unwrap().to_owned()),
+        );
+        match next {
+            Some(next) => start = Some(next),
+            None => break,
+        }
+    }
+    let expected = (0..100).rev().filter(|n| n % 3 == 1).map(|n| format!("tx{}", n));
+    assert_eq!(ids, expected.collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn stats_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    let repo = repo(&db);
+
+    let stats = repo.stats().await.unwrap();
+    assert_eq!((stats.min_height, stats.max_height), (None, None));
+    assert_eq!((stats.block_count, stats.transaction_count), (0, 0));
+
+    // Seeding analyzes the tables, so the estimates are exact
+    common::seed(&mut db.connect(), 5, 4, 2);
+    let stats = repo.stats().await.unwrap();
+    assert_eq!((stats.min_height, stats.max_height), (Some(1), Some(5)));
+    assert_eq!((stats.block_count, stats.transaction_count), (5, 20));
+}
+
+#[tokio::test]
+async fn distinct_senders_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    // 20 operations of 3 senders
+    common::seed(&mut db.connect(), 5, 4, 3);
+    let repo = repo(&db);
+
+    let senders = repo.distinct_senders(None, 10).await.unwrap();
+    assert_eq!(senders, ["sender0", "sender1", "sender2"]);
+    let senders = repo.distinct_senders(None, 2).await.unwrap();
+    assert_eq!(senders, ["sender0", "sender1"]);
+    let senders = repo
+        .distinct_senders(Some(vec![OperationType::InvokeScript, OperationType::Lease]), 10)
+        .await
+        .unwrap();
+    assert_eq!(senders, ["sender0", "sender1", "sender2"]);
+    let senders = repo
+        .distinct_senders(Some(vec![OperationType::Lease]), 10)
+        .await
+        .unwrap();
+    assert!(senders.is_empty());
+}
+
+#[tokio::test]
+async fn operations_by_block() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    common::seed(&mut conn, 3, 4, 1);
+    sql_query(
+        "INSERT INTO blocks_microblocks (id, height, time_stamp, is_microblock) VALUES ('empty', 4, 240000, false)",
+    )
+    .execute(&mut conn)
+    .unwrap();
+    let repo = repo(&db);
+    let ids_of_block = |block_id: &'static str| {
+        let repo = &repo;
+        async move {
+            let operations = repo.fetch_operations_by_block(block_id).await.unwrap()?;
+            let ids = operations
+                .iter()
+                .map(|operation| operation.body()["id"].as_str().unwrap().to_owned());
+            Some(ids.collect::<Vec<_>>())
+        }
+    };
+
+    assert_eq!(ids_of_block("block2").await.unwrap(), ["tx4", "tx5", "tx6", "tx7"]);
+    assert_eq!(ids_of_block("empty").await.unwrap(), Vec::<String>::new());
+    assert_eq!(ids_of_block("unknown").await, None);
+}
+
+#[tokio::test]
+async fn statement_timeout_is_applied() {
+    let Some(mut db) = TestDb::create() else { return };
+    db.config.statement_timeout_ms = Some(100);
+
+    #[derive(QueryableByName)]
+    struct Setting {
+        #[diesel(sql_type = Text)]
+        statement_timeout: String,
+    }
+    let mut conn = db.connect();
+    let setting = sql_query("SHOW statement_timeout")
+        .get_result::<Setting>(&mut conn)
+        .unwrap();
+    assert_eq!(setting.statement_timeout, "100ms");
+    let err = sql_query("SELECT pg_sleep(1)").execute(&mut conn).unwrap_err();
+    assert!(err.to_string().contains("statement timeout"), "{}", err);
+}
+
+#[tokio::test]
+async fn ssl_mode_is_applied() {
+    let Some(mut db) = TestDb::create() else { return };
+
+    #[derive(QueryableByName)]
+    struct Ssl {
+        #[diesel(sql_type = Bool)]
+        ssl: bool,
+    }
+    let ssl_query = || sql_query("SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()");
+    let server_ssl = sql_query("SELECT setting::bool AS ssl FROM pg_settings WHERE name = 'ssl'")
+        .get_result::<Ssl>(&mut db.connect())
+        .unwrap()
+        .ssl;
+
+    db.config.ssl_mode = Some(SslMode::Disable);
+    assert!(!ssl_query().get_result::<Ssl>(&mut db.connect()).unwrap().ssl);
+
+    db.config.ssl_mode = Some(SslMode::Require);
+    if !server_ssl {
+        // Both the consumer's and the service's connections refuse to go without TLS
+        assert!(PgConnection::establish(&db.url()).is_err());
+        let pool = db::pool::new(&db.config, 1, None).unwrap();
+        assert!(db::pool::validate(&pool).await.is_err());
+        return;
+    }
+    assert!(ssl_query().get_result::<Ssl>(&mut db.connect()).unwrap().ssl);
+    let pool = db::pool::new(&db.config, 1, None).unwrap();
+    let conn = pool.get().await.unwrap();
+    let ssl = conn
+        .interact(move |conn| ssl_query().get_result::<Ssl>(conn))
+        .await
+        .unwrap();
+    assert!(ssl.unwrap().ssl);
+}
+
+#[tokio::test]
+async fn validate_fails_on_bad_credentials() {
+    let Some(mut db) = TestDb::create() else { return };
+    let pool = db::pool::new(&db.config, 1, None).unwrap();
+    db::pool::validate(&pool).await.unwrap();
+
+    db.config.password.push_str("-wrong");
+    let pool = db::pool::new(&db.config, 1, None).unwrap();
+    let err = db::pool::validate(&pool).await.unwrap_err();
+    assert!(
+        err.to_string().starts_with("failed to connect to the database"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn block_included_only_when_requested() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 2, 2, 1);
+    let repo = repo(&db);
+    let fetch = |include| {
+        let page = Page {
+            start: None,
+            limit: 1,
+            since: None,
+        };
+        repo.fetch_operations(Filters::default(), page, Sort::Desc, include)
+    };
+
+    let (operations, _) = fetch(Include::default()).await.unwrap();
+    assert!(operations[0].block().is_none());
+    assert!(serde_json::to_value(&operations[0]).unwrap().get("block").is_none());
+
+    let include = Include {
+        block: true,
+        ..Include::default()
+    };
+    let (operations, _) = fetch(include).await.unwrap();
+    let json = serde_json::to_value(&operations[0]).unwrap();
+    assert_eq!(json["id"], "tx3");
+    assert_eq!(
+        json["block"],
+        serde_json::json!({ "block_id": "block2", "block_height": 2, "block_timestamp": 120000 })
+    );
+}
+
+#[tokio::test]
+async fn reads_from_replica_when_configured() {
+    let Some(primary) = TestDb::create() else { return };
+    let replica = TestDb::create().unwrap();
+    common::seed(&mut replica.connect(), 1, 2, 1);
+    let page = || Page {
+        start: None,
+        limit: 10,
+        since: None,
+    };
+
+    let repo = repo(&primary);
+    let (operations, _) = repo
+        .fetch_operations(Filters::default(), page(), Sort::Desc, Include::default())
+        .await
+        .unwrap();
+    assert!(operations.is_empty());
+
+    let repo = repo.with_read_replica(db::pool::new(&replica.config, 2, None).unwrap());
+    let (operations, _) = repo
+        .fetch_operations(Filters::default(), page(), Sort::Desc, Include::default())
+        .await
+        .unwrap();
+    let ids = operations.iter().map(|operation| operation.body()["id"].clone());
+    assert_eq!(ids.collect::<Vec<_>>(), ["tx1", "tx0"]);
+}
+
+#[tokio::test]
+async fn cached_page_until_ttl_expires() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 1, 2, 1);
+    let repo = repo(&db).with_cache(Duration::from_millis(500));
+    assert_eq!(first_page(&repo).await, ["tx1", "tx0"]);
+
+    insert_tx(&db, "tx2");
+    assert_eq!(first_page(&repo).await, ["tx1", "tx0"]);
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(first_page(&repo).await, ["tx2", "tx1", "tx0"]);
+}
+
+#[tokio::test]
+async fn no_cache_by_default() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 1, 2, 1);
+    let repo = repo(&db);
+    assert_eq!(first_page(&repo).await, ["tx1", "tx0"]);
+
+    insert_tx(&db, "tx2");
+    assert_eq!(first_page(&repo).await, ["tx2", "tx1", "tx0"]);
+}
+
+#[tokio::test]
+async fn operation_counts_on_mixed_data() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    // 20 invokes, 4 at each of the heights 1 to 5
+    common::seed(&mut conn, 5, 4, 1);
+    conn.batch_execute(
+        "UPDATE transactions SET tx_type = 18 WHERE id IN ('tx1', 'tx5'); \
+         UPDATE transactions SET tx_type = 8, op_type = 'lease' WHERE id IN ('tx6', 'tx10', 'tx19')",
+    )
+    .unwrap();
+    let repo = repo(&db);
+
+    let counts = |min, max| {
+        let repo = &repo;
+        async move {
+            let mut counts = repo
+                .operation_counts(min, max)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|count| (format!("{:?}", count.op_type), count.tx_type, count.count))
+                .collect::<Vec<_>>();
+            counts.sort();
+            counts
+        }
+    };
+    let invoke = |tx_type, count| ("InvokeScript".to_owned(), tx_type, count);
+    let lease = |count| ("Lease".to_owned(), 8, count);
+    assert_eq!(counts(None, None).await, [invoke(16, 15), invoke(18, 2), lease(3)]);
+    // Heights 2 to 4 are the transactions 4 to 15
+    assert_eq!(counts(Some(2), Some(4)).await, [invoke(16, 9), invoke(18, 1), lease(2)]);
+    assert!(counts(Some(6), None).await.is_empty());
+}
+
+#[tokio::test]
+async fn payment_filter_on_mixed_invokes() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    // 6 invokes of 2 senders
+    common::seed(&mut conn, 2, 3, 2);
+    conn.batch_execute(
+        "UPDATE transactions SET operation = operation || '{\"payment\": [{\"amount\": 1, \"id\": null}]}' \
+         WHERE id IN ('tx0', 'tx1', 'tx4'); \
+         UPDATE transactions SET operation = operation || '{\"payment\": []}' WHERE id IN ('tx2', 'tx3')",
+    )
+    .unwrap();
+    let repo = repo(&db);
+
+    let ids = |filters: Filters| {
+        let repo = &repo;
+        async move {
+            let page = Page {
+                start: None,
+                limit: 10,
+                since: None,
+            };
+            let (operations, _) = repo
+                .fetch_operations(filters, page, Sort::Asc, Include::default())
+                .await
+                .unwrap();
+            operations
+                .iter()
+                .map(|operation| operation.body()["id"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        }
+    };
+    let payment = |has_payment| Filters {
+        has_payment: Some(has_payment),
+        ..Filters::default()
+    };
+    assert_eq!(ids(payment(true)).await, ["tx0", "tx1", "tx4"]);
+    // Operations without the `payment` field at all (tx5) match neither
+    assert_eq!(ids(payment(false)).await, ["tx2", "tx3"]);
+    let paid_by_sender = Filters {
+        sender: Some("sender0".to_owned()),
+        ..payment(true)
+    };
+    assert_eq!(ids(paid_by_sender).await, ["tx0", "tx4"]);
+}
+
+#[tokio::test]
+async fn since_uid_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    // tx n has uid n + 1
+    common::seed(&mut db.connect(), 5, 4, 2);
+    let repo = repo(&db);
+
+    let page = |since, limit| Page {
+        start: None,
+        limit,
+        since: Some(since),
+    };
+    let ids = |operations: &[Operation<i64>]| {
+        let ids = operations
+            .iter()
+            .map(|operation| operation.body()["id"].as_str().unwrap().to_owned());
+        ids.collect::<Vec<_>>()
+    };
+    let (operations, next) = repo
+        .fetch_operations(Filters::default(), page(15, 3), Sort::Asc, Include::default())
+        .await
+        .unwrap();
+    assert_eq!(ids(&operations), ["tx15", "tx16", "tx17"]);
+    assert!(next.is_some());
+    let (operations, next) = repo
+        .fetch_operations(sender("sender1"), page(15, 10), Sort::Asc, Include::default())
+        .await
+        .unwrap();
+    assert_eq!(ids(&operations), ["tx15", "tx17", "tx19"]);
+    assert!(next.is_none());
+    let (operations, _) = repo
+        .fetch_operations(Filters::default(), page(20, 10), Sort::Asc, Include::default())
+        .await
+        .unwrap();
+    assert!(operations.is_empty());
+}
+
+#[tokio::test]
+async fn operations_by_ids_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 5, 4, 2);
+    let repo = repo(&db);
+
+    let ids = ["tx17", "unknown", "tx3", "tx10"].map(str::to_owned).to_vec();
+    let operations = repo.fetch_operations_by_ids(ids).await.unwrap();
+    let ids = operations
+        .iter()
+        .map(|operation| operation.body()["id"].as_str().unwrap());
+    assert_eq!(ids.collect::<Vec<_>>(), ["tx3", "tx10", "tx17"]);
+    assert!(repo
+        .fetch_operations_by_ids(vec!["unknown".to_owned()])
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn created_at_included_only_when_requested() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 1, 1, 1);
+    let repo = repo(&db);
+    let fetch = |include| {
+        let page = Page {
+            start: None,
+            limit: 1,
+            since: None,
+        };
+        repo.fetch_operations(Filters::default(), page, Sort::Desc, include)
+    };
+
+    let (operations, _) = fetch(Include::default()).await.unwrap();
+    assert_eq!(operations[0].created_at(), None);
+    assert!(serde_json::to_value(&operations[0])
+        .unwrap()
+        .get("created_at")
+        .is_none());
+
+    let include = Include {
+        created_at: true,
+        ..Include::default()
+    };
+    let (operations, _) = fetch(include).await.unwrap();
+    let created_at = operations[0].created_at().unwrap();
+    let age = chrono::Utc::now().timestamp_millis() - created_at;
+    assert!((0..60000).contains(&age), "{}", age);
+    assert_eq!(serde_json::to_value(&operations[0]).unwrap()["created_at"], created_at);
+}
+
+#[tokio::test]
+async fn origin_filter_on_mixed_data() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    // 6 invokes, 2 of them from Ethereum transactions, and a lease
+    common::seed(&mut conn, 2, 3, 1);
+    conn.batch_execute(
+        "UPDATE transactions SET tx_type = 18 WHERE id IN ('tx1', 'tx4'); \
+         UPDATE transactions SET tx_type = 8, op_type = 'lease' WHERE id = 'tx5'",
+    )
+    .unwrap();
+    let repo = repo(&db);
+
+    let ids = |origin| {
+        let repo = &repo;
+        async move {
+            let page = Page {
+                start: None,
+                limit: 10,
+                since: None,
+            };
+            let filters = Filters {
+                origin,
+                ..Filters::default()
+            };
+            let (operations, _) = repo
+                .fetch_operations(filters, page, Sort::Asc, Include::default())
+                .await
+                .unwrap();
+            operations
+                .iter()
+                .map(|operation| operation.body()["id"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(ids(None).await, ["tx0", "tx1", "tx2", "tx3", "tx4", "tx5"]);
+    assert_eq!(ids(Some(Origin::Waves)).await, ["tx0", "tx2", "tx3", "tx5"]);
+    assert_eq!(ids(Some(Origin::Ethereum)).await, ["tx1", "tx4"]);
+}
+
+#[tokio::test]
+async fn last_block_timestamp_on_seeded_data() {
+    let Some(db) = TestDb::create() else { return };
+    let repo = repo(&db);
+    assert_eq!(repo.last_block_timestamp().await.unwrap(), None);
+
+    // Block `h` has the timestamp `h` minutes
+    common::seed(&mut db.connect(), 5, 1, 1);
+    assert_eq!(repo.last_block_timestamp().await.unwrap(), Some(5 * 60000));
+}
+
+#[tokio::test]
+async fn fee_filter_on_seeded_fees() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    // 6 invokes with the fees of 100000 * (n + 1) WAVES, the last one in another asset
+    common::seed(&mut conn, 2, 3, 2);
+    conn.batch_execute(
+        "UPDATE transactions SET fee_amount = 100000 * uid, \
+                                 operation = jsonb_set(operation, '{fee}', jsonb_build_object('amount', 100000 * uid)); \
+         UPDATE transactions SET fee_asset = 'Asset' WHERE id = 'tx5'",
+    )
+    .unwrap();
+    let repo = repo(&db);
+
+    let ids = |fee_gte, fee_lte, fee_asset: Option<&str>| {
+        let repo = &repo;
+        let filters = Filters {
+            fee_gte,
+            fee_lte,
+            fee_asset: fee_asset.map(str::to_owned),
+            ..Filters::default()
+        };
+        async move {
+            let page = Page {
+                start: None,
+                limit: 10,
+                since: None,
+            };
+            let (operations, _) = repo
+                .fetch_operations(filters, page, Sort::Asc, Include::default())
+                .await
+                .unwrap();
+            operations
+                .iter()
+                .map(|operation| operation.body()["id"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(ids(Some(400000), None, None).await, ["tx3", "tx4", "tx5"]);
+    assert_eq!(ids(None, Some(200000), None).await, ["tx0", "tx1"]);
+    assert_eq!(ids(Some(200000), Some(300000), None).await, ["tx1", "tx2"]);
+    assert_eq!(ids(Some(400000), None, Some("WAVES")).await, ["tx3", "tx4"]);
+    assert_eq!(ids(None, Some(200000), Some("Asset")).await, Vec::<String>::new());
+    // Composes with the other filters
+    let by_sender = Filters {
+        sender: Some("sender1".to_owned()),
+        fee_gte: Some(300000),
+        ..Filters::default()
+    };
+    let page = Page {
+        start: None,
+        limit: 10,
+        since: None,
+    };
+    let (operations, _) = repo
+        .fetch_operations(by_sender, page, Sort::Asc, Include::default())
+        .await
+        .unwrap();
+    let by_sender = operations
+        .iter()
+        .map(|operation| operation.body()["id"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(by_sender, ["tx3", "tx5"]);
+}
+
+#[tokio::test]
+async fn histogram_on_seeded_days() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    // 12 invokes of 2 senders in 6 blocks, 12 hours apart from the epoch, so 2 blocks a day
+    common::seed(&mut conn, 6, 2, 2);
+    conn.batch_execute("UPDATE blocks_microblocks SET time_stamp = (height - 1) * 43200000")
+        .unwrap();
+    let repo = repo(&db);
+
+    let buckets = |filters, interval| {
+        let repo = &repo;
+        async move {
+            let buckets = repo.operation_histogram(filters, interval).await.unwrap();
+            buckets
+                .into_iter()
+                .map(|bucket| (bucket.start, bucket.count))
+                .collect::<Vec<_>>()
+        }
+    };
+    const DAY: i64 = 86400000;
+    assert_eq!(
+        buckets(Filters::default(), Interval::Day).await,
+        [(0, 4), (DAY, 4), (2 * DAY, 4)]
+    );
+    let hours = (0..6).map(|i| (i * DAY / 2, 2)).collect::<Vec<_>>();
+    assert_eq!(buckets(Filters::default(), Interval::Hour).await, hours);
+    // Each block has one invoke of each sender
+    let by_sender = Filters {
+        sender: Some("sender1".to_owned()),
+        ..Filters::default()
+    };
+    assert_eq!(
+        buckets(by_sender, Interval::Day).await,
+        [(0, 2), (DAY, 2), (2 * DAY, 2)]
+    );
+    let leases = Filters {
+        op_types: Some(vec![OperationType::Lease]),
+        ..Filters::default()
+    };
+    assert!(buckets(leases, Interval::Day).await.is_empty());
+}
+
+#[tokio::test]
+async fn is_microblock_of_key_blocks_and_microblocks() {
+    let Some(db) = TestDb::create() else { return };
+    let mut conn = db.connect();
+    common::seed(&mut conn, 2, 1, 1);
+    // A microblock of the first, already finalized, block and one of the last block
+    conn.batch_execute(
+        "UPDATE blocks_microblocks SET is_microblock = true WHERE id = 'block1'; \
+         INSERT INTO blocks_microblocks (id, height, time_stamp, is_microblock) VALUES ('micro2', 2, 120000, true)",
+    )
+    .unwrap();
+    insert_tx(&db, "tx_micro");
+    let repo = repo(&db);
+    let fetch = |include| {
+        let repo = &repo;
+        async move {
+            let page = Page {
+                start: None,
+                limit: 10,
+                since: None,
+            };
+            let (operations, _) = repo
+                .fetch_operations(Filters::default(), page, Sort::Asc, include)
+                .await
+                .unwrap();
+            let operations = operations.iter().map(|operation| {
+                let id = operation.body()["id"].as_str().unwrap().to_owned();
+                (id, operation.is_microblock())
+            });
+            operations.collect::<Vec<_>>()
+        }
+    };
+    let include = Include {
+        is_microblock: true,
+        ..Include::default()
+    };
+    let flag = |id: &str, is_microblock| (id.to_owned(), Some(is_microblock));
+
+    let expected = [flag("tx0", false), flag("tx1", false), flag("tx_micro", true)];
+    assert_eq!(fetch(include).await, expected);
+    let operations = fetch(Include::default()).await;
+    assert!(operations.iter().all(|(_, is_microblock)| is_microblock.is_none()));
+
+    // The next key block finalizes the microblock
+    conn.batch_execute(
+        "INSERT INTO blocks_microblocks (id, height, time_stamp, is_microblock) VALUES ('block3', 3, 180000, false)",
+    )
+    .unwrap();
+    let expected = [flag("tx0", false), flag("tx1", false), flag("tx_micro", false)];
+    assert_eq!(fetch(include).await, expected);
+}
+
+#[tokio::test]
+async fn raw_operation_as_stored() {
+    let Some(db) = TestDb::create() else { return };
+    common::seed(&mut db.connect(), 1, 1, 1);
+    // Too big for a double and not ASCII, would change if re-serialized through `f64` or escaped
+    let operation =
+        r#"{"id": "tx_raw", "call": {"args": [{"type": "integer", "value": 9007199254740993}], "function": "вызов"}}"#;
+    let sql = format!(
+        "INSERT INTO transactions (id, block_uid, sender, tx_type, op_type, operation, fee_amount, fee_asset, height) \
+         SELECT 'tx_raw', uid, 'sender0', 16, 'invoke_script', '{}', 500000, 'WAVES', height FROM blocks_microblocks",
+        operation
+    );
+    sql_query(sql).execute(&mut db.connect()).unwrap();
+    let repo = repo(&db);
+
+    let raw = repo.fetch_raw_operation("tx_raw").await.unwrap().unwrap();
+    assert_eq!(raw, operation);
+    assert!(repo.fetch_raw_operation("unknown").await.unwrap().is_none());
+}
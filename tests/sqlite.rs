@@ -0,0 +1,158 @@
+//! SQLite storage and repo (local development only), on a temporary database file.
+
+#![cfg(feature = "sqlite")]
+
+use std::path::PathBuf;
+
+use diesel::{sqlite::SqliteConnection, Connection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+use lib::common::database::types::OperationType;
+use lib::consumer::storage::{BlockUID, NewTransaction, Repo as _, SqliteStorage, Storage};
+use lib::service::db::sqlite_pool;
+use lib::service::repo::{sqlite::SqliteRepo, Filters, Include, Page, Repo, Sort};
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+/// Database file with all the migrations applied, deleted when dropped
+struct TestFile(PathBuf);
+
+impl TestFile {
+    fn create(name: &str) -> TestFile {
+        let path = std::env::temp_dir().join(format!("operations_test_{}_{}.db", std::process::id(), name));
+        let file = TestFile(path);
+        let mut conn = SqliteConnection::establish(file.path()).unwrap();
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        file
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TestFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn insert_tx(repo: &mut SqliteConnection, id: &str, block_uid: BlockUID, height: u32) -> anyhow::Result<()> {
+    repo.insert_tx(&NewTransaction {
+        id,
+        block_uid,
+        sender: "3PSender",
+        tx_type: 16,
+        op_type: OperationType::InvokeScript,
+        fee_amount: 500000,
+        fee_asset: "WAVES",
+        operation: &serde_json::json!({ "id": id, "type": "invoke_script", "sender": "3PSender" }),
+        height: height as i32,
+    })
+}
+
+/// Ids of all the stored operations, oldest first
+async fn operation_ids(repo: &SqliteRepo) -> Vec<String> {
+    let page = Page {
+        start: None,
+        limit: 10,
+        since: None,
+    };
+    let (operations, _) = repo
+        .fetch_operations(Filters::default(), page, Sort::Asc, Include::default())
+        .await
+        .unwrap();
+    operations
+        .iter()
+        .map(|operation| operation.body()["id"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn insert_fetch_and_rollback() {
+    let file = TestFile::create("insert_fetch_and_rollback");
+    let storage = SqliteStorage::connect(file.path()).unwrap();
+    let repo = SqliteRepo::new(sqlite_pool::new(file.path(), 1, None).unwrap());
+
+    let kept = storage
+        .transaction(|repo| {
+            let kept = repo.insert_block("block1", 1, 60000, false)?;
+            insert_tx(repo, "tx1", kept, 1)?;
+            let micro = repo.insert_block("micro1", 1, 60000, true)?;
+            insert_tx(repo, "tx2", micro, 1)?;
+            let next = repo.insert_block("block2", 2, 120000, false)?;
+            insert_tx(repo, "tx3", next, 2)?;
+            assert_eq!(repo.key_block_uid(1)?, Some(kept));
+            assert_eq!(repo.key_block_uid(3)?, None);
+            Ok(kept)
+        })
+        .await
+        .unwrap();
+    assert_eq!(operation_ids(&repo).await, ["tx1", "tx2", "tx3"]);
+    let operations = repo.fetch_operations_by_ids(vec!["tx3".to_owned()]).await.unwrap();
+    assert_eq!(operations[0].body()["sender"], "3PSender");
+
+    let (rolled_back_to, last_height) = storage
+        .transaction(move |repo| Ok((repo.rollback_to_block(kept)?, repo.last_height()?)))
+        .await
+        .unwrap();
+    assert_eq!(rolled_back_to, Some(1));
+    assert_eq!(last_height, Some(1));
+    assert_eq!(operation_ids(&repo).await, ["tx1"]);
+    let operations = repo.fetch_operations_by_ids(vec!["tx3".to_owned()]).await.unwrap();
+    assert!(operations.is_empty());
+}
+
+#[tokio::test]
+async fn failed_transaction_writes_nothing() {
+    let file = TestFile::create("failed_transaction_writes_nothing");
+    let storage = SqliteStorage::connect(file.path()).unwrap();
+
+    let res = storage
+        .transaction(|repo| {
+            let block_uid = repo.insert_block("block1", 1, 60000, false)?;
+            insert_tx(repo, "tx1", block_uid, 1)?;
+            // Same id again
+            insert_tx(repo, "tx1", block_uid, 1)
+        })
+        .await;
+    assert!(res.is_err());
+
+    let last_height = storage.transaction(|repo| repo.last_height()).await.unwrap();
+    assert_eq!(last_height, None);
+}
+
+#[tokio::test]
+async fn operation_counts_by_height() {
+    let file = TestFile::create("operation_counts_by_height");
+    let storage = SqliteStorage::connect(file.path()).unwrap();
+    let repo = SqliteRepo::new(sqlite_pool::new(file.path(), 1, None).unwrap());
+
+    storage
+        .transaction(|repo| {
+            for height in 1..=3 {
+                let block_uid = repo.insert_block(&format!("block{}", height), height, height as u64 * 60000, false)?;
+                for i in 0..height {
+                    insert_tx(repo, &format!("tx{}_{}", height, i), block_uid, height)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+    let count = |min, max| {
+        let repo = &repo;
+        async move {
+            let counts = repo.operation_counts(min, max).await.unwrap();
+            counts
+                .iter()
+                .map(|count| (count.tx_type, count.count))
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(count(None, None).await, [(16, 6)]);
+    assert_eq!(count(Some(2), None).await, [(16, 5)]);
+    assert_eq!(count(Some(1), Some(2)).await, [(16, 3)]);
+    assert!(count(Some(4), None).await.is_empty());
+}